@@ -0,0 +1,114 @@
+// Oxygen cost and calorie burn estimated from speed and incline with the ACSM metabolic equations,
+// for machines that don't report expended energy or report it without knowing the user's weight.
+//
+// Oxygen cost in ml/kg/min, with speed S in m/min and grade G as a fraction:
+//   walking: 0.1 * S + 1.8 * S * G + 3.5
+//   running: 0.2 * S + 0.9 * S * G + 3.5
+// and kcal/min = VO2 * weight in kg / 200. The equations are meant for steady state on a
+// treadmill, assume level ground when the incline isn't known and count declines as level, since
+// they were only validated uphill. They describe an average person, a particular runner's economy
+// can be a fair bit better or worse, and they get less accurate in the gap between walking and
+// running speeds and above about 20 km/h.
+
+use crate::units;
+use serde::Serialize;
+
+// Speeds above this use the running equation, about 8 km/h in m/min
+const RUNNING_SPEED: f64 = 134.0;
+// Ml/kg/min at rest, also one MET
+const RESTING_VO2: f64 = 3.5;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Equation {
+    Walking,
+    Running,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct RunningMetrics {
+    // Estimated oxygen cost, ml/kg/min
+    pub vo2: f64,
+    // The same as METs, VO2 over the resting 3.5
+    pub mets: f64,
+    pub equation: Equation,
+    // What the machine reports itself, when it does
+    pub machine_mets: Option<f32>,
+}
+
+// Estimated VO2 in ml/kg/min, from speed in km/h at 0.01 precision and incline in percent grade at
+// 0.1 precision.
+pub fn vo2(speed: u16, inclination: Option<i16>) -> (f64, Equation) {
+    let meters_per_minute = units::meters_per_second(speed) * 60.0;
+    let grade = inclination.map_or(0.0, |inclination| (units::percent(inclination) / 100.0).max(0.0));
+    let (equation, horizontal, vertical) = if meters_per_minute > RUNNING_SPEED {
+        (Equation::Running, 0.2, 0.9)
+    } else {
+        (Equation::Walking, 0.1, 1.8)
+    };
+    (horizontal * meters_per_minute + vertical * meters_per_minute * grade + RESTING_VO2, equation)
+}
+
+pub fn running_metrics(speed: u16, inclination: Option<i16>, machine_mets: Option<f32>) -> RunningMetrics {
+    let (vo2, equation) = vo2(speed, inclination);
+    RunningMetrics { vo2, mets: vo2 / RESTING_VO2, equation, machine_mets }
+}
+
+// Kcal per hour, in the same units as `vo2`.
+pub fn calories_per_hour(speed: u16, inclination: Option<i16>, weight_kg: f64) -> f64 {
+    vo2(speed, inclination).0 * weight_kg / 200.0 * 60.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uphill_burns_more_than_flat_at_the_same_speed() {
+        // 5 km/h walking is 83.3 m/min, 1.8 * 83.3 * 0.1 more VO2 at a 10% grade
+        let flat = calories_per_hour(500, Some(0), 70.0);
+        let uphill = calories_per_hour(500, Some(100), 70.0);
+        assert!((flat - 248.5).abs() < 0.1, "{}", flat);
+        assert!((uphill - 563.5).abs() < 0.1, "{}", uphill);
+    }
+
+    #[test]
+    fn unknown_incline_and_declines_count_as_flat() {
+        let flat = calories_per_hour(1000, Some(0), 70.0);
+        assert_eq!(calories_per_hour(1000, None, 70.0), flat);
+        assert_eq!(calories_per_hour(1000, Some(-50), 70.0), flat);
+    }
+
+    fn assert_vo2(speed: u16, inclination: Option<i16>, expected: f64, equation: Equation) {
+        let (actual, used) = vo2(speed, inclination);
+        assert!((actual - expected).abs() < 0.01, "{} at {} and {:?}: {}", expected, speed, inclination, actual);
+        assert_eq!(used, equation);
+    }
+
+    #[test]
+    fn vo2_at_walking_and_running_speeds_and_grades() {
+        // 5 km/h is 83.3 m/min, 0.1 * 83.3 + 3.5 flat and 1.8 * 83.3 * 0.1 more at 10%
+        assert_vo2(500, Some(0), 11.83, Equation::Walking);
+        assert_vo2(500, Some(100), 26.83, Equation::Walking);
+        // 10 km/h is 166.7 m/min, 0.2 * 166.7 + 3.5 flat and 0.9 * 166.7 * 0.05 more at 5%
+        assert_vo2(1000, Some(0), 36.83, Equation::Running);
+        assert_vo2(1000, Some(50), 44.33, Equation::Running);
+        assert_vo2(1200, None, 43.5, Equation::Running);
+        // Standing still is resting
+        assert_vo2(0, Some(0), RESTING_VO2, Equation::Walking);
+    }
+
+    #[test]
+    fn running_equation_takes_over_just_above_8_kmh() {
+        assert_vo2(800, Some(0), 16.83, Equation::Walking);
+        assert_vo2(810, Some(0), 30.5, Equation::Running);
+    }
+
+    #[test]
+    fn mets_are_vo2_over_resting() {
+        let metrics = running_metrics(1000, Some(0), Some(10.2));
+        assert!((metrics.mets - 36.83 / 3.5).abs() < 0.01, "{}", metrics.mets);
+        assert_eq!(metrics.machine_mets, Some(10.2));
+        assert_eq!(running_metrics(0, None, None).mets, 1.0);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::DecodeError;
+use serde::Serialize;
+
+// Decoding based on https://github.com/oesmith/gatt-xml/blob/master/org.bluetooth.characteristic.fitness_machine_feature.xml
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct FitnessMachineFeatures {
+    pub average_speed: bool,
+    pub cadence: bool,
+    pub total_distance: bool,
+    pub inclination: bool,
+    pub elevation_gain: bool,
+    pub pace: bool,
+    pub step_count: bool,
+    pub resistance_level: bool,
+    pub stride_count: bool,
+    pub expended_energy: bool,
+    pub heart_rate_measurement: bool,
+    pub metabolic_equivalent: bool,
+    pub elapsed_time: bool,
+    pub remaining_time: bool,
+    pub power_measurement: bool,
+    pub force_on_belt_and_power_output: bool,
+    pub user_data_retention: bool,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct TargetSettingFeatures {
+    pub speed: bool,
+    pub inclination: bool,
+    pub resistance: bool,
+    pub power: bool,
+    pub heart_rate: bool,
+    pub expended_energy: bool,
+    pub step_number: bool,
+    pub stride_number: bool,
+    pub distance: bool,
+    pub training_time: bool,
+    pub time_in_two_heart_rate_zones: bool,
+    pub time_in_three_heart_rate_zones: bool,
+    pub time_in_five_heart_rate_zones: bool,
+    pub indoor_bike_simulation: bool,
+    pub wheel_circumference: bool,
+    pub spin_down_control: bool,
+    pub cadence: bool,
+}
+
+// Km/h at 0.01 precision
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct SpeedRange {
+    pub minimum: u16,
+    pub maximum: u16,
+    pub minimum_increment: u16,
+}
+
+// Percent grade at 0.1 precision
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct InclinationRange {
+    pub minimum: i16,
+    pub maximum: i16,
+    pub minimum_increment: u16,
+}
+
+// Watts
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct PowerRange {
+    pub minimum: i16,
+    pub maximum: i16,
+    pub minimum_increment: u16,
+}
+
+// Everything the connected machine told us it supports. Ranges are `None` when the machine doesn't
+// expose the matching characteristic.
+#[derive(Debug, Serialize, Clone)]
+pub struct MachineCapabilities {
+    pub features: Option<FitnessMachineFeatures>,
+    pub target_settings: Option<TargetSettingFeatures>,
+    pub speed_range: Option<SpeedRange>,
+    pub inclination_range: Option<InclinationRange>,
+    pub power_range: Option<PowerRange>,
+    pub control_opcodes: Vec<u8>,
+}
+
+fn flag(flags: u32, bit: u32) -> bool {
+    flags & (1 << bit) != 0
+}
+
+pub fn decode_fitness_machine_feature(
+    data: &[u8],
+) -> Result<(FitnessMachineFeatures, TargetSettingFeatures), DecodeError> {
+    if data.len() < 8 {
+        return Err(DecodeError::NotEnoughData);
+    }
+
+    let machine = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+    let target = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+
+    let features = FitnessMachineFeatures {
+        average_speed: flag(machine, 0),
+        cadence: flag(machine, 1),
+        total_distance: flag(machine, 2),
+        inclination: flag(machine, 3),
+        elevation_gain: flag(machine, 4),
+        pace: flag(machine, 5),
+        step_count: flag(machine, 6),
+        resistance_level: flag(machine, 7),
+        stride_count: flag(machine, 8),
+        expended_energy: flag(machine, 9),
+        heart_rate_measurement: flag(machine, 10),
+        metabolic_equivalent: flag(machine, 11),
+        elapsed_time: flag(machine, 12),
+        remaining_time: flag(machine, 13),
+        power_measurement: flag(machine, 14),
+        force_on_belt_and_power_output: flag(machine, 15),
+        user_data_retention: flag(machine, 16),
+    };
+
+    let target_settings = TargetSettingFeatures {
+        speed: flag(target, 0),
+        inclination: flag(target, 1),
+        resistance: flag(target, 2),
+        power: flag(target, 3),
+        heart_rate: flag(target, 4),
+        expended_energy: flag(target, 5),
+        step_number: flag(target, 6),
+        stride_number: flag(target, 7),
+        distance: flag(target, 8),
+        training_time: flag(target, 9),
+        time_in_two_heart_rate_zones: flag(target, 10),
+        time_in_three_heart_rate_zones: flag(target, 11),
+        time_in_five_heart_rate_zones: flag(target, 12),
+        indoor_bike_simulation: flag(target, 13),
+        wheel_circumference: flag(target, 14),
+        spin_down_control: flag(target, 15),
+        cadence: flag(target, 16),
+    };
+
+    Ok((features, target_settings))
+}
+
+pub fn decode_supported_speed_range(data: &[u8]) -> Result<SpeedRange, DecodeError> {
+    if data.len() < 6 {
+        return Err(DecodeError::NotEnoughData);
+    }
+
+    Ok(SpeedRange {
+        minimum: u16::from_le_bytes([data[0], data[1]]),
+        maximum: u16::from_le_bytes([data[2], data[3]]),
+        minimum_increment: u16::from_le_bytes([data[4], data[5]]),
+    })
+}
+
+pub fn decode_supported_inclination_range(data: &[u8]) -> Result<InclinationRange, DecodeError> {
+    if data.len() < 6 {
+        return Err(DecodeError::NotEnoughData);
+    }
+
+    Ok(InclinationRange {
+        minimum: i16::from_le_bytes([data[0], data[1]]),
+        maximum: i16::from_le_bytes([data[2], data[3]]),
+        minimum_increment: u16::from_le_bytes([data[4], data[5]]),
+    })
+}
+
+pub fn decode_supported_power_range(data: &[u8]) -> Result<PowerRange, DecodeError> {
+    if data.len() < 6 {
+        return Err(DecodeError::NotEnoughData);
+    }
+
+    Ok(PowerRange {
+        minimum: i16::from_le_bytes([data[0], data[1]]),
+        maximum: i16::from_le_bytes([data[2], data[3]]),
+        minimum_increment: u16::from_le_bytes([data[4], data[5]]),
+    })
+}
+
+// Control point opcodes the machine accepts. Request control, reset, start and stop are always
+// available, the rest depend on the target setting features. Without the feature characteristic
+// we can't tell, so only the always available ones are listed.
+pub fn control_opcodes(target_settings: Option<&TargetSettingFeatures>) -> Vec<u8> {
+    let mut opcodes = vec![0x00, 0x01, 0x07, 0x08];
+    let Some(t) = target_settings else {
+        return opcodes;
+    };
+
+    let optional = [
+        (t.speed, 0x02),
+        (t.inclination, 0x03),
+        (t.resistance, 0x04),
+        (t.power, 0x05),
+        (t.heart_rate, 0x06),
+        (t.expended_energy, 0x09),
+        (t.step_number, 0x0A),
+        (t.stride_number, 0x0B),
+        (t.distance, 0x0C),
+        (t.training_time, 0x0D),
+        (t.time_in_two_heart_rate_zones, 0x0E),
+        (t.time_in_three_heart_rate_zones, 0x0F),
+        (t.time_in_five_heart_rate_zones, 0x10),
+        (t.indoor_bike_simulation, 0x11),
+        (t.wheel_circumference, 0x12),
+        (t.spin_down_control, 0x13),
+        (t.cadence, 0x14),
+    ];
+    opcodes.extend(optional.iter().filter(|(supported, _)| *supported).map(|(_, opcode)| *opcode));
+    opcodes.sort();
+    opcodes
+}
+
+// Treadmill Data fields a machine with these features is expected to fill in.
+pub fn data_fields(features: &FitnessMachineFeatures) -> Vec<&'static str> {
+    let optional: [(bool, &[&'static str]); 11] = [
+        (features.average_speed, &["average_speed"]),
+        (features.total_distance, &["total_distance"]),
+        (features.inclination, &["inclination", "inclination_percent", "ramp_angle", "ramp_angle_degrees"]),
+        (features.elevation_gain, &["positive_elevation", "negative_elevation"]),
+        (features.pace, &["instantaneous_pace", "average_pace"]),
+        (features.expended_energy, &["total_energy", "energy_per_hour", "energy_per_minute"]),
+        (features.heart_rate_measurement, &["heart_rate"]),
+        (features.metabolic_equivalent, &["metabolic_equivalent", "metabolic_equivalent_met"]),
+        (features.elapsed_time, &["elapsed_time"]),
+        (features.remaining_time, &["remaining_time"]),
+        (features.force_on_belt_and_power_output, &["force_on_belt", "power_output"]),
+    ];
+
+    let mut fields = vec!["speed"];
+    for (supported, names) in optional {
+        if supported {
+            fields.extend_from_slice(names);
+        }
+    }
+    fields
+}
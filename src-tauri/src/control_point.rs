@@ -0,0 +1,214 @@
+use crate::{error::CommandError, DecodeError};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::{sync::broadcast, time};
+
+// Every control point indication starts with this opcode, followed by the opcode of the request it
+// answers, the result code and any response parameters.
+const RESPONSE_CODE: u8 = 0x80;
+const SPIN_DOWN_CONTROL_OPCODE: u8 = 0x13;
+
+// The spec gives the server 30 seconds to complete a control point procedure, used unless the
+// settings say otherwise.
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub enum ResultCode {
+    Success,
+    OpCodeNotSupported,
+    InvalidParameter,
+    OperationFailed,
+    ControlNotPermitted,
+    Unknown(u8),
+}
+
+impl From<u8> for ResultCode {
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => ResultCode::Success,
+            0x02 => ResultCode::OpCodeNotSupported,
+            0x03 => ResultCode::InvalidParameter,
+            0x04 => ResultCode::OperationFailed,
+            0x05 => ResultCode::ControlNotPermitted,
+            code => ResultCode::Unknown(code),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ControlPointResponse {
+    pub request_opcode: u8,
+    pub result: ResultCode,
+    pub parameters: Vec<u8>,
+}
+
+// Speeds the user has to bring the belt to during a spin down calibration, km/h at 0.01 precision.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct SpinDownTargets {
+    pub speed_low: u16,
+    pub speed_high: u16,
+}
+
+impl ControlPointResponse {
+    // Only a successful spin down control response carries targets.
+    pub fn spin_down_targets(&self) -> Option<SpinDownTargets> {
+        if self.request_opcode != SPIN_DOWN_CONTROL_OPCODE || self.result != ResultCode::Success {
+            return None;
+        }
+        match self.parameters[..] {
+            [low_0, low_1, high_0, high_1, ..] => Some(SpinDownTargets {
+                speed_low: u16::from_le_bytes([low_0, low_1]),
+                speed_high: u16::from_le_bytes([high_0, high_1]),
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn decode_control_point_response(data: &[u8]) -> Result<ControlPointResponse, DecodeError> {
+    if data.len() < 3 || data[0] != RESPONSE_CODE {
+        return Err(DecodeError::NotEnoughData);
+    }
+
+    Ok(ControlPointResponse {
+        request_opcode: data[1],
+        result: ResultCode::from(data[2]),
+        parameters: data[3..].to_vec(),
+    })
+}
+
+// Response parameters are only defined for a successful spin down control request, which returns
+// the target speed low and high as two uint16s. Everything else answers with no parameters.
+fn parameter_len(request_opcode: u8, result: u8) -> usize {
+    match (request_opcode, ResultCode::from(result)) {
+        (SPIN_DOWN_CONTROL_OPCODE, ResultCode::Success) => 4,
+        _ => 0,
+    }
+}
+
+// Indications can arrive split across notifications on some platforms, so bytes are buffered and
+// only complete responses are decoded out of them.
+#[derive(Debug, Default)]
+pub struct ResponseBuffer {
+    pending: Vec<u8>,
+}
+
+impl ResponseBuffer {
+    pub fn push(&mut self, data: &[u8]) -> Vec<ControlPointResponse> {
+        self.pending.extend_from_slice(data);
+
+        let mut responses = Vec::new();
+        loop {
+            // Skip anything that can't be the start of a response so one bad chunk doesn't wedge
+            // the buffer
+            let start = self.pending.iter().position(|b| *b == RESPONSE_CODE).unwrap_or(self.pending.len());
+            self.pending.drain(..start);
+            if self.pending.len() < 3 {
+                break;
+            }
+
+            let len = 3 + parameter_len(self.pending[1], self.pending[2]);
+            if self.pending.len() < len {
+                break;
+            }
+            let frame: Vec<u8> = self.pending.drain(..len).collect();
+            if let Ok(response) = decode_control_point_response(&frame) {
+                responses.push(response);
+            }
+        }
+        responses
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+// Waits until a response has arrived for each of `opcodes`, returning them in the same order, or
+// fails the procedure once `timeout` is up. Subscribe `responses` before writing so indications
+// that arrive quickly aren't missed.
+pub async fn wait_for_responses(
+    responses: &mut broadcast::Receiver<ControlPointResponse>,
+    opcodes: &[u8],
+    timeout: Duration,
+) -> Result<Vec<ControlPointResponse>, CommandError> {
+    let mut received: Vec<Option<ControlPointResponse>> = vec![None; opcodes.len()];
+
+    let wait = async {
+        while received.iter().any(|r| r.is_none()) {
+            let response = match responses.recv().await {
+                Ok(r) => r,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Err(CommandError::NotConnected),
+            };
+            let slot = opcodes
+                .iter()
+                .zip(received.iter_mut())
+                .find(|(opcode, slot)| **opcode == response.request_opcode && slot.is_none());
+            if let Some((_, slot)) = slot {
+                *slot = Some(response);
+            }
+        }
+        Ok(())
+    };
+
+    match time::timeout(timeout, wait).await {
+        Ok(result) => result?,
+        Err(_) => {
+            let unanswered = opcodes.iter().zip(&received).find(|(_, r)| r.is_none()).map(|(opcode, _)| *opcode);
+            return Err(CommandError::ProcedureTimeout(unanswered.unwrap_or_default()));
+        }
+    }
+
+    Ok(received.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_split_across_two_chunks_is_decoded_once_complete() {
+        let mut buffer = ResponseBuffer::default();
+        assert!(buffer.push(&[0x80, 0x02]).is_empty());
+        let responses = buffer.push(&[0x01]);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].request_opcode, 0x02);
+        assert_eq!(responses[0].result, ResultCode::Success);
+    }
+
+    #[test]
+    fn spin_down_parameters_can_arrive_in_a_later_chunk() {
+        let mut buffer = ResponseBuffer::default();
+        assert!(buffer.push(&[0x80, 0x13, 0x01, 0xE8]).is_empty());
+        let responses = buffer.push(&[0x03, 0xD0, 0x07, 0x80, 0x07, 0x01]);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0].spin_down_targets(), Some(SpinDownTargets { speed_low: 1000, speed_high: 2000 }));
+        assert_eq!(responses[1].request_opcode, 0x07);
+    }
+
+    #[test]
+    fn bytes_before_a_response_are_skipped() {
+        let mut buffer = ResponseBuffer::default();
+        let responses = buffer.push(&[0x00, 0x42, 0x80, 0x00, 0x05]);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].result, ResultCode::ControlNotPermitted);
+    }
+
+    #[test]
+    fn spin_down_response_decodes_its_target_speeds() {
+        // Success answering spin down control, 8 km/h low and 16 km/h high
+        let response = decode_control_point_response(&[0x80, 0x13, 0x01, 0x20, 0x03, 0x40, 0x06]).unwrap();
+        assert_eq!(response.spin_down_targets(), Some(SpinDownTargets { speed_low: 800, speed_high: 1600 }));
+    }
+
+    #[test]
+    fn only_successful_spin_downs_carry_targets() {
+        let failed = decode_control_point_response(&[0x80, 0x13, 0x04]).unwrap();
+        assert_eq!(failed.spin_down_targets(), None);
+        let other = decode_control_point_response(&[0x80, 0x02, 0x01, 0x20, 0x03, 0x40, 0x06]).unwrap();
+        assert_eq!(other.spin_down_targets(), None);
+        let short = decode_control_point_response(&[0x80, 0x13, 0x01, 0x20]).unwrap();
+        assert_eq!(short.spin_down_targets(), None);
+    }
+}
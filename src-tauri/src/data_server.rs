@@ -0,0 +1,103 @@
+// Local WebSocket server for tools like OBS overlays, only built with the `data-server` feature.
+// Every decoded frame goes to every client as one text message holding the same JSON as the
+// `treadmill-data` event, e.g. `{"speed":850,"total_distance":1200,"heart_rate":142,...}`, with
+// fields the machine doesn't report as `null`. Unlike the event it isn't throttled, clients see
+// each frame as it arrives. Anything clients send is ignored, and the server only listens on
+// localhost.
+
+use crate::{error::CommandError, TreadmillData};
+use futures::{SinkExt as _, StreamExt as _};
+use tauri::async_runtime::JoinHandle;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+// Frames a slow client can fall behind by before it skips ahead
+const CLIENT_BUFFER: usize = 64;
+
+pub struct DataServer {
+    pub port: u16,
+    frames: broadcast::Sender<String>,
+    listener: JoinHandle<()>,
+}
+
+impl DataServer {
+    // Port 0 picks a free one, `port` says which.
+    pub async fn start(port: u16) -> Result<DataServer, CommandError> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.map_err(|e| CommandError::Io(e.to_string()))?;
+        let port = listener.local_addr().map_err(|e| CommandError::Io(e.to_string()))?.port();
+        let (frames, _) = broadcast::channel(CLIENT_BUFFER);
+        let clients = frames.clone();
+        let listener = tauri::async_runtime::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tauri::async_runtime::spawn(serve(stream, clients.subscribe()));
+                    }
+                    Err(e) => eprintln!("Error accepting data server client: {:?}", e),
+                }
+            }
+        });
+        println!("Data server listening on port {}.", port);
+        Ok(DataServer { port, frames, listener })
+    }
+
+    pub fn send(&self, data: &TreadmillData) {
+        if self.frames.receiver_count() == 0 {
+            return;
+        }
+        match serde_json::to_string(data) {
+            Ok(json) => {
+                // Only fails when the last client left in the meantime
+                let _ = self.frames.send(json);
+            }
+            Err(e) => eprintln!("Error serializing treadmill data for the data server: {:?}", e),
+        }
+    }
+
+    // Stops accepting clients and closes the connected ones, which see the channel close once the
+    // listener has dropped its sender.
+    pub fn stop(self) {
+        self.listener.abort();
+        println!("Data server on port {} stopped.", self.port);
+    }
+}
+
+async fn serve(stream: TcpStream, mut frames: broadcast::Receiver<String>) {
+    let peer = stream.peer_addr().ok();
+    let socket = match tokio_tungstenite::accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Error opening WebSocket with {:?}: {:?}", peer, e);
+            return;
+        }
+    };
+    println!("Data server client {:?} connected.", peer);
+    let (mut outgoing, mut incoming) = socket.split();
+    loop {
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Ok(json) => {
+                    if let Err(e) = outgoing.send(Message::Text(json)).await {
+                        eprintln!("Error sending to data server client {:?}: {:?}", peer, e);
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => {
+                    if let Err(e) = outgoing.send(Message::Close(None)).await {
+                        eprintln!("Error closing data server client {:?}: {:?}", peer, e);
+                    }
+                    break;
+                }
+            },
+            message = incoming.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    println!("Data server client {:?} disconnected.", peer);
+}
@@ -0,0 +1,102 @@
+// Per model workarounds for treadmills whose Treadmill Data doesn't quite follow the spec.
+//
+// To support one, write a decoder with the same signature as `decode_treadmill_data` (it can
+// patch the frame up and hand it to the standard decoder) and add a `DeviceProfile` to
+// `PROFILES` with the model number or name the treadmill reports. Devices that only get the speed
+// units wrong can keep the standard decoder and set a `speed_scale`. Every other device keeps
+// using the standard profile, and users can force any profile for hardware that isn't matched by
+// name.
+
+use crate::{decode_treadmill_data, DecodeError, TreadmillData};
+use serde::Serialize;
+
+pub type TreadmillDecoder = fn(&[u8]) -> Result<TreadmillData, DecodeError>;
+
+#[derive(Debug, Serialize)]
+pub struct DeviceProfile {
+    pub name: &'static str,
+    // Matched against the start of the model number and the advertised local name, `None` is never
+    // picked by name
+    pub name_prefix: Option<&'static str>,
+    #[serde(skip)]
+    pub decode: TreadmillDecoder,
+    // Multiplies decoded speeds into 0.01 km/h, e.g. 10 for a device reporting 0.1 km/h or
+    // 1.60934 for 0.01 mph
+    pub speed_scale: f64,
+}
+
+// The first profile is the fallback for devices nothing else matches.
+pub const PROFILES: &[DeviceProfile] = &[DeviceProfile {
+    name: "Standard",
+    name_prefix: None,
+    decode: decode_treadmill_data,
+    speed_scale: 1.0,
+}];
+
+pub fn find(name: &str) -> Option<&'static DeviceProfile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
+
+// The forced profile when there is one, otherwise the first whose prefix matches one of `names`,
+// tried in order.
+pub fn select(names: &[Option<&str>], forced: Option<&str>) -> &'static DeviceProfile {
+    select_from(PROFILES, names, forced)
+}
+
+fn select_from<'a>(profiles: &'a [DeviceProfile], names: &[Option<&str>], forced: Option<&str>) -> &'a DeviceProfile {
+    if let Some(profile) = forced.and_then(|forced| profiles.iter().find(|profile| profile.name == forced)) {
+        return profile;
+    }
+
+    let by_name = names.iter().flatten().find_map(|name| {
+        profiles
+            .iter()
+            .find(|profile| profile.name_prefix.is_some_and(|prefix| name.starts_with(prefix)))
+    });
+    by_name.unwrap_or(&profiles[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A model that flags total distance without ever sending it
+    fn decode_without_distance(data: &[u8]) -> Result<TreadmillData, DecodeError> {
+        let mut data = data.to_vec();
+        if let Some(flags) = data.first_mut() {
+            *flags &= !0b100;
+        }
+        decode_treadmill_data(&data)
+    }
+
+    const TEST_PROFILES: &[DeviceProfile] = &[
+        DeviceProfile { name: "Standard", name_prefix: None, decode: decode_treadmill_data, speed_scale: 1.0 },
+        DeviceProfile {
+            name: "No distance",
+            name_prefix: Some("TM-NODIST"),
+            decode: decode_without_distance,
+            speed_scale: 1.0,
+        },
+    ];
+
+    #[test]
+    fn custom_decoder_is_selected_by_name() {
+        let frame = [0b100, 0x00, 0xE8, 0x03];
+        let profile = select_from(TEST_PROFILES, &[None, Some("TM-NODIST 2")], None);
+        assert_eq!(profile.name, "No distance");
+        let data = (profile.decode)(&frame).unwrap();
+        assert_eq!(data.speed, 1000);
+        assert_eq!(data.total_distance, None);
+
+        let standard = select_from(TEST_PROFILES, &[Some("HORIZON_7.0AT")], None);
+        assert_eq!(standard.name, "Standard");
+        assert!(matches!((standard.decode)(&frame), Err(DecodeError::NotEnoughData)));
+    }
+
+    #[test]
+    fn forced_profile_wins_over_the_name() {
+        let profile = select_from(TEST_PROFILES, &[Some("HORIZON_7.0AT")], Some("No distance"));
+        assert_eq!(profile.name, "No distance");
+        assert_eq!(select_from(TEST_PROFILES, &[Some("TM-NODIST")], Some("Unknown")).name, "No distance");
+    }
+}
@@ -0,0 +1,119 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::fmt;
+
+// Errors returned to the frontend from Tauri commands. Serialized as `{ kind, message }` so the UI
+// can branch on `kind` and still show `message` to the user.
+#[derive(Debug)]
+pub enum CommandError {
+    DeviceNotFound,
+    WorkoutNotFound(String),
+    WorkoutNotRunning,
+    ReplayNotRunning,
+    SessionNotFound(String),
+    BleError(String),
+    WorkoutParse(String),
+    Io(String),
+    NotConnected,
+    NotSupported(String),
+    OutOfRange(String),
+    ControlRejected(String),
+    ControlNotPermitted,
+    // Opcode of the control point request the machine never answered
+    ProcedureTimeout(u8),
+    PermissionDenied,
+    ConnectFailed(String),
+    Cancelled,
+    NotArmed,
+    AdapterPoweredOff,
+}
+
+impl CommandError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CommandError::DeviceNotFound => "DeviceNotFound",
+            CommandError::WorkoutNotFound(_) => "WorkoutNotFound",
+            CommandError::WorkoutNotRunning => "WorkoutNotRunning",
+            CommandError::ReplayNotRunning => "ReplayNotRunning",
+            CommandError::SessionNotFound(_) => "SessionNotFound",
+            CommandError::BleError(_) => "BleError",
+            CommandError::WorkoutParse(_) => "WorkoutParse",
+            CommandError::Io(_) => "Io",
+            CommandError::NotConnected => "NotConnected",
+            CommandError::NotSupported(_) => "NotSupported",
+            CommandError::OutOfRange(_) => "OutOfRange",
+            CommandError::ControlRejected(_) => "ControlRejected",
+            CommandError::ControlNotPermitted => "ControlNotPermitted",
+            CommandError::ProcedureTimeout(_) => "ProcedureTimeout",
+            CommandError::PermissionDenied => "PermissionDenied",
+            CommandError::ConnectFailed(_) => "ConnectFailed",
+            CommandError::Cancelled => "Cancelled",
+            CommandError::NotArmed => "NotArmed",
+            CommandError::AdapterPoweredOff => "AdapterPoweredOff",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::DeviceNotFound => write!(f, "Treadmill not found."),
+            CommandError::WorkoutNotFound(id) => write!(f, "Workout {} not found.", id),
+            CommandError::WorkoutNotRunning => write!(f, "No workout is running."),
+            CommandError::ReplayNotRunning => write!(f, "No session is being replayed."),
+            CommandError::SessionNotFound(id) => write!(f, "Session {} not found.", id),
+            CommandError::BleError(e) => write!(f, "Bluetooth error: {}", e),
+            CommandError::WorkoutParse(e) => write!(f, "Error parsing workout: {}", e),
+            CommandError::Io(e) => write!(f, "Error reading file: {}", e),
+            CommandError::NotConnected => write!(f, "Treadmill not connected."),
+            CommandError::NotSupported(what) => write!(f, "Not supported by this treadmill: {}", what),
+            CommandError::OutOfRange(e) => write!(f, "Out of range: {}", e),
+            CommandError::ControlRejected(e) => write!(f, "Treadmill rejected the command: {}", e),
+            CommandError::ControlNotPermitted => write!(
+                f,
+                "The treadmill is being controlled by something else. Disconnect the other app or finish on the console, then try again."
+            ),
+            CommandError::ProcedureTimeout(opcode) => {
+                write!(f, "Treadmill didn't respond to control point request {:#04x} in time.", opcode)
+            }
+            CommandError::PermissionDenied => write!(
+                f,
+                "Bluetooth permission denied. Allow this app under System Settings > Privacy & Security > Bluetooth, then try again."
+            ),
+            CommandError::ConnectFailed(e) => write!(f, "Could not connect to the treadmill after {}", e),
+            CommandError::Cancelled => write!(f, "Cancelled."),
+            CommandError::NotArmed => write!(f, "Arm the treadmill before starting the belt."),
+            CommandError::AdapterPoweredOff => write!(f, "Bluetooth is turned off."),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl Serialize for CommandError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("CommandError", 2)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+// btleplug has no dedicated error for a powered off adapter, each platform words it differently
+fn powered_off(e: &btleplug::Error) -> bool {
+    let btleplug::Error::Other(e) = e else {
+        return false;
+    };
+    let message = e.to_string().to_lowercase();
+    ["powered off", "poweredoff", "not powered", "notready", "not ready"].iter().any(|m| message.contains(m))
+}
+
+impl From<btleplug::Error> for CommandError {
+    fn from(e: btleplug::Error) -> Self {
+        match e {
+            // macOS reports a missing Bluetooth permission this way, usually on first run
+            btleplug::Error::PermissionDenied => CommandError::PermissionDenied,
+            e if powered_off(&e) => CommandError::AdapterPoweredOff,
+            e => CommandError::BleError(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// Opt-in safety convenience: pause the belt when heart rate goes above a ceiling, and optionally
+// resume once it drops below a floor. This is not a medical device and should not be relied on as
+// one, it only reacts to whatever heart rate the treadmill happens to report.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct HeartRateLimit {
+    // Beats per minute
+    pub ceiling: u8,
+    // Resume automatically below this heart rate, otherwise the user has to resume manually
+    pub resume_below: Option<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeartRateAction {
+    Pause,
+    Resume,
+}
+
+// `paused` is whether the limit itself paused the belt, so we only resume what we stopped.
+pub fn check_heart_rate(limit: &HeartRateLimit, paused: bool, heart_rate: u8) -> Option<HeartRateAction> {
+    if !paused && heart_rate > limit.ceiling {
+        return Some(HeartRateAction::Pause);
+    }
+
+    match limit.resume_below {
+        Some(floor) if paused && heart_rate < floor => Some(HeartRateAction::Resume),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HeartRateLimitEvent {
+    pub heart_rate: u8,
+    pub limit: HeartRateLimit,
+}
@@ -0,0 +1,146 @@
+// A heart rate strap connected alongside the treadmill. While its readings are fresh they replace
+// the machine's heart rate in the data we emit, chest straps being more accurate than hand grips.
+
+use crate::{consume_before_subscribing, discover_characteristics, error::CommandError, subscribe, AppState, DecodeError};
+use btleplug::api::{bleuuid::uuid_from_u16, Central as _, Peripheral as _, ScanFilter};
+use btleplug::platform::{Adapter, Peripheral};
+use futures::StreamExt;
+use std::time::{Duration, Instant};
+use tauri::{async_runtime::JoinHandle, AppHandle, Manager as _};
+use tokio::time;
+use uuid::Uuid;
+
+const HEART_RATE_SERVICE_UUID: Uuid = uuid_from_u16(0x180D);
+const HEART_RATE_MEASUREMENT_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2A37);
+const SCAN_TIME: Duration = Duration::from_secs(2);
+// How long a strap reading is used for, after that the machine's own heart rate shows again
+const FRESHNESS: Duration = Duration::from_secs(5);
+
+pub struct HeartRateStrap {
+    pub peripheral: Peripheral,
+    notifications: JoinHandle<()>,
+}
+
+impl HeartRateStrap {
+    pub async fn disconnect(self) {
+        self.notifications.abort();
+        if let Err(e) = self.peripheral.disconnect().await {
+            eprintln!("Error disconnecting heart rate strap: {:?}", e);
+        }
+    }
+}
+
+// Beats per minute, straps send a uint16 instead of a uint8 when the first flag bit is set.
+pub fn decode_heart_rate_measurement(data: &[u8]) -> Result<u8, DecodeError> {
+    let flags = *data.first().ok_or(DecodeError::NotEnoughData)?;
+    if flags & 0b1 == 0 {
+        return data.get(1).copied().ok_or(DecodeError::NotEnoughData);
+    }
+    let bytes = data.get(1..3).ok_or(DecodeError::NotEnoughData)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]).min(u8::MAX as u16) as u8)
+}
+
+// The strap's latest heart rate, unless it has gone quiet.
+pub fn fresh_heart_rate(state: &AppState) -> Option<u8> {
+    let reading = *state.strap_heart_rate.lock().unwrap();
+    reading.filter(|(_, at)| at.elapsed() <= FRESHNESS).map(|(heart_rate, _)| heart_rate)
+}
+
+// The heart rate to emit, the strap's when it has a fresh one and the machine's otherwise.
+pub fn merge_heart_rate(state: &AppState, machine: Option<u8>) -> Option<u8> {
+    fresh_heart_rate(state).or(machine)
+}
+
+async fn find_strap(central: &Adapter) -> Result<Peripheral, CommandError> {
+    for p in central.peripherals().await? {
+        let properties = match p.properties().await {
+            Ok(Some(properties)) => properties,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Error reading peripheral properties: {:?}", e);
+                continue;
+            }
+        };
+        if properties.services.contains(&HEART_RATE_SERVICE_UUID) {
+            println!("Found heart rate strap {:?}.", properties.local_name);
+            return Ok(p);
+        }
+    }
+    Err(CommandError::DeviceNotFound)
+}
+
+pub async fn connect(app: &AppHandle, central: &Adapter) -> Result<HeartRateStrap, CommandError> {
+    central.start_scan(ScanFilter { services: vec![HEART_RATE_SERVICE_UUID] }).await?;
+    time::sleep(SCAN_TIME).await;
+    let found = find_strap(central).await;
+    if let Err(e) = central.stop_scan().await {
+        eprintln!("Error stopping scan: {:?}", e);
+    }
+    let peripheral = found?;
+
+    peripheral.connect().await?;
+    let characteristics = discover_characteristics(&peripheral).await?;
+    let measurement = characteristics
+        .iter()
+        .find(|c| c.uuid == HEART_RATE_MEASUREMENT_CHARACTERISTIC_UUID)
+        .ok_or_else(|| CommandError::NotSupported("Heart Rate Measurement".to_string()))?;
+    // Opened before subscribing so the first readings aren't lost, like the treadmill's
+    let mut sub = peripheral.notifications().await?;
+    let app = app.clone();
+    let consumer = async move {
+        while let Some(notification) = sub.next().await {
+            if notification.uuid != HEART_RATE_MEASUREMENT_CHARACTERISTIC_UUID {
+                continue;
+            }
+            let heart_rate = match decode_heart_rate_measurement(&notification.value) {
+                Ok(heart_rate) => heart_rate,
+                Err(e) => {
+                    eprintln!("Error decoding heart rate measurement: {:?}", e);
+                    continue;
+                }
+            };
+            *app.state::<AppState>().strap_heart_rate.lock().unwrap() = Some((heart_rate, Instant::now()));
+            if let Err(e) = app.emit_all("strap-heart-rate", heart_rate) {
+                eprintln!("Error emitting strap heart rate: {:?}", e);
+            }
+        }
+    };
+
+    let notifications =
+        consume_before_subscribing(consumer, subscribe(&peripheral, measurement, "Heart Rate Measurement")).await?;
+    Ok(HeartRateStrap { peripheral, notifications })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(state: &AppState, heart_rate: u8, age: Duration) {
+        *state.strap_heart_rate.lock().unwrap() = Some((heart_rate, Instant::now() - age));
+    }
+
+    #[test]
+    fn strap_heart_rate_takes_precedence() {
+        let state = AppState::default();
+        assert_eq!(merge_heart_rate(&state, Some(120)), Some(120));
+        reading(&state, 142, Duration::from_secs(1));
+        assert_eq!(merge_heart_rate(&state, Some(120)), Some(142));
+        assert_eq!(merge_heart_rate(&state, None), Some(142));
+    }
+
+    #[test]
+    fn machine_heart_rate_shows_again_once_the_strap_goes_quiet() {
+        let state = AppState::default();
+        reading(&state, 142, FRESHNESS + Duration::from_secs(1));
+        assert_eq!(merge_heart_rate(&state, Some(120)), Some(120));
+        assert_eq!(merge_heart_rate(&state, None), None);
+    }
+
+    #[test]
+    fn decodes_both_heart_rate_formats() {
+        assert_eq!(decode_heart_rate_measurement(&[0x00, 0x8C]).unwrap(), 140);
+        assert_eq!(decode_heart_rate_measurement(&[0x01, 0x8C, 0x00]).unwrap(), 140);
+        assert_eq!(decode_heart_rate_measurement(&[0x01, 0x2C, 0x01]).unwrap(), u8::MAX);
+        assert!(matches!(decode_heart_rate_measurement(&[0x01, 0x8C]), Err(DecodeError::NotEnoughData)));
+    }
+}
@@ -0,0 +1,110 @@
+// Opt-in convenience for heart rate zone training: nudges the target speed up while heart rate is
+// below the zone and down while it's above, in proportion to how far out it is. Like the heart rate
+// limit this is not a medical device, it only reacts to whatever heart rate is reported.
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// Km/h at 0.01 precision per beat per minute outside the zone
+const GAIN: u16 = 2;
+// Largest single change, 0.5 km/h
+const MAX_STEP: u16 = 50;
+// Heart rate lags a speed change by a good while, so give each change time to show
+const SETTLE_TIME: Duration = Duration::from_secs(20);
+// Used when the machine doesn't report its supported speed range, 1 to 16 km/h
+pub const FALLBACK_MINIMUM_SPEED: u16 = 100;
+pub const FALLBACK_MAXIMUM_SPEED: u16 = 1600;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct HeartRateZone {
+    // Beats per minute
+    pub min: u8,
+    pub max: u8,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneReason {
+    BelowZone,
+    AboveZone,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct HeartRateZoneAdjustment {
+    pub heart_rate: u8,
+    pub zone: HeartRateZone,
+    pub reason: ZoneReason,
+    // Km/h at 0.01 precision
+    pub from_speed: u16,
+    pub to_speed: u16,
+}
+
+// The speed to move to for `heart_rate`, kept within `minimum..=maximum`. `None` inside the zone
+// or when the speed is already at the edge of the range.
+pub fn adjusted_speed(zone: &HeartRateZone, heart_rate: u8, speed: u16, minimum: u16, maximum: u16) -> Option<(u16, ZoneReason)> {
+    let (target, reason) = if heart_rate < zone.min {
+        let step = (GAIN * (zone.min - heart_rate) as u16).min(MAX_STEP);
+        (speed.saturating_add(step), ZoneReason::BelowZone)
+    } else if heart_rate > zone.max {
+        let step = (GAIN * (heart_rate - zone.max) as u16).min(MAX_STEP);
+        (speed.saturating_sub(step), ZoneReason::AboveZone)
+    } else {
+        return None;
+    };
+
+    let target = target.clamp(minimum, maximum.max(minimum));
+    (target != speed).then_some((target, reason))
+}
+
+#[derive(Debug)]
+pub struct ZoneController {
+    pub zone: HeartRateZone,
+    last_adjusted: Option<Instant>,
+}
+
+impl ZoneController {
+    pub fn new(zone: HeartRateZone) -> ZoneController {
+        ZoneController { zone, last_adjusted: None }
+    }
+
+    // Like `adjusted_speed`, but holds off until the previous change has had time to settle.
+    pub fn update(&mut self, heart_rate: u8, speed: u16, minimum: u16, maximum: u16) -> Option<HeartRateZoneAdjustment> {
+        if self.last_adjusted.is_some_and(|at| at.elapsed() < SETTLE_TIME) {
+            return None;
+        }
+        let (to_speed, reason) = adjusted_speed(&self.zone, heart_rate, speed, minimum, maximum)?;
+        self.last_adjusted = Some(Instant::now());
+        Some(HeartRateZoneAdjustment { heart_rate, zone: self.zone, reason, from_speed: speed, to_speed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: HeartRateZone = HeartRateZone { min: 130, max: 150 };
+
+    #[test]
+    fn speeds_up_below_the_zone_and_slows_down_above_it() {
+        assert_eq!(adjusted_speed(&ZONE, 120, 800, 100, 1600), Some((820, ZoneReason::BelowZone)));
+        assert_eq!(adjusted_speed(&ZONE, 160, 800, 100, 1600), Some((780, ZoneReason::AboveZone)));
+        assert_eq!(adjusted_speed(&ZONE, 130, 800, 100, 1600), None);
+        assert_eq!(adjusted_speed(&ZONE, 150, 800, 100, 1600), None);
+    }
+
+    #[test]
+    fn changes_are_capped_and_kept_in_range() {
+        assert_eq!(adjusted_speed(&ZONE, 60, 800, 100, 1600), Some((800 + MAX_STEP, ZoneReason::BelowZone)));
+        assert_eq!(adjusted_speed(&ZONE, 120, 1590, 100, 1600), Some((1600, ZoneReason::BelowZone)));
+        assert_eq!(adjusted_speed(&ZONE, 120, 1600, 100, 1600), None);
+        assert_eq!(adjusted_speed(&ZONE, 190, 100, 100, 1600), None);
+    }
+
+    #[test]
+    fn controller_waits_for_a_change_to_settle() {
+        let mut controller = ZoneController::new(ZONE);
+        let adjustment = controller.update(120, 800, 100, 1600).unwrap();
+        assert_eq!((adjustment.from_speed, adjustment.to_speed), (800, 820));
+        assert!(controller.update(120, 820, 100, 1600).is_none());
+    }
+}
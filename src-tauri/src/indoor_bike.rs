@@ -0,0 +1,100 @@
+use crate::{read_u24_le, DecodeError};
+use serde::Serialize;
+
+// Decoding based on https://github.com/oesmith/gatt-xml/blob/master/org.bluetooth.characteristic.indoor_bike_data.xml
+#[derive(Debug, Serialize, Clone)]
+pub struct IndoorBikeData {
+    // Km/h at 0.01 precision
+    pub speed: u16,
+    pub average_speed: Option<u16>,
+    // Revolutions per minute at 0.5 precision
+    pub cadence: Option<u16>,
+    pub average_cadence: Option<u16>,
+    // Meters
+    pub total_distance: Option<u32>,
+    pub resistance_level: Option<i16>,
+    // Watts
+    pub power: Option<i16>,
+    pub average_power: Option<i16>,
+    pub total_energy: Option<u16>,
+    pub energy_per_hour: Option<u16>,
+    pub energy_per_minute: Option<u8>,
+    pub heart_rate: Option<u8>,
+    // Raw value in 0.1 MET units
+    pub metabolic_equivalent: Option<u8>,
+    // Seconds
+    pub elapsed_time: Option<u16>,
+    pub remaining_time: Option<u16>,
+}
+
+// Two flag bytes plus the instantaneous speed
+const MIN_INDOOR_BIKE_DATA_LEN: usize = 4;
+
+fn flag(flags: u16, bit: u16) -> bool {
+    flags & (1 << bit) != 0
+}
+
+// Returns the next `len` bytes when `present`, moving the cursor past them.
+fn field<'a>(data: &'a [u8], cursor: &mut usize, present: bool, len: usize) -> Result<Option<&'a [u8]>, DecodeError> {
+    if !present {
+        return Ok(None);
+    }
+    let bytes = data.get(*cursor..*cursor + len).ok_or(DecodeError::NotEnoughData)?;
+    *cursor += len;
+    Ok(Some(bytes))
+}
+
+fn u16_field(data: &[u8], cursor: &mut usize, present: bool) -> Result<Option<u16>, DecodeError> {
+    Ok(field(data, cursor, present, 2)?.map(|b| u16::from_le_bytes([b[0], b[1]])))
+}
+
+fn i16_field(data: &[u8], cursor: &mut usize, present: bool) -> Result<Option<i16>, DecodeError> {
+    Ok(field(data, cursor, present, 2)?.map(|b| i16::from_le_bytes([b[0], b[1]])))
+}
+
+fn u8_field(data: &[u8], cursor: &mut usize, present: bool) -> Result<Option<u8>, DecodeError> {
+    Ok(field(data, cursor, present, 1)?.map(|b| b[0]))
+}
+
+pub fn decode_indoor_bike_data(data: &[u8]) -> Result<IndoorBikeData, DecodeError> {
+    if data.len() < MIN_INDOOR_BIKE_DATA_LEN {
+        return Err(DecodeError::NotEnoughData);
+    }
+
+    let flags = u16::from_le_bytes([data[0], data[1]]);
+    let speed = u16::from_le_bytes([data[2], data[3]]);
+    let mut cursor = MIN_INDOOR_BIKE_DATA_LEN;
+
+    let average_speed = u16_field(data, &mut cursor, flag(flags, 1))?;
+    let cadence = u16_field(data, &mut cursor, flag(flags, 2))?;
+    let average_cadence = u16_field(data, &mut cursor, flag(flags, 3))?;
+    let total_distance = field(data, &mut cursor, flag(flags, 4), 3)?.map(read_u24_le).transpose()?;
+    let resistance_level = i16_field(data, &mut cursor, flag(flags, 5))?;
+    let power = i16_field(data, &mut cursor, flag(flags, 6))?;
+    let average_power = i16_field(data, &mut cursor, flag(flags, 7))?;
+    let total_energy = u16_field(data, &mut cursor, flag(flags, 8))?;
+    let energy_per_hour = u16_field(data, &mut cursor, flag(flags, 8))?;
+    let energy_per_minute = u8_field(data, &mut cursor, flag(flags, 8))?;
+    let heart_rate = u8_field(data, &mut cursor, flag(flags, 9))?;
+    let metabolic_equivalent = u8_field(data, &mut cursor, flag(flags, 10))?;
+    let elapsed_time = u16_field(data, &mut cursor, flag(flags, 11))?;
+    let remaining_time = u16_field(data, &mut cursor, flag(flags, 12))?;
+
+    Ok(IndoorBikeData {
+        speed,
+        average_speed,
+        cadence,
+        average_cadence,
+        total_distance,
+        resistance_level,
+        power,
+        average_power,
+        total_energy,
+        energy_per_hour,
+        energy_per_minute,
+        heart_rate,
+        metabolic_equivalent,
+        elapsed_time,
+        remaining_time,
+    })
+}
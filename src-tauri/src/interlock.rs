@@ -0,0 +1,55 @@
+// Guards against the belt starting by accident. With an arming window set, the first command that
+// moves the belt after connecting or stopping is refused unless `arm_treadmill` was called within
+// the window. Once the belt is moving, later commands go through until the next stop.
+
+use crate::{error::CommandError, TreadmillCommands};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Interlock {
+    // `None` turns the interlock off
+    window: Option<Duration>,
+    armed_at: Option<Instant>,
+    moving: bool,
+}
+
+fn moves_belt(command: &TreadmillCommands) -> bool {
+    matches!(command, TreadmillCommands::StartOrResume | TreadmillCommands::SetTargetSpeed(_))
+}
+
+impl Interlock {
+    pub fn set_window(&mut self, window: Option<Duration>) {
+        self.window = window;
+    }
+
+    pub fn arm(&mut self) {
+        self.armed_at = Some(Instant::now());
+    }
+
+    // Called when the belt stops or the connection changes, the next start needs arming again.
+    pub fn reset(&mut self) {
+        self.armed_at = None;
+        self.moving = false;
+    }
+
+    // Whether a command that moves the belt would go through right now.
+    pub fn ready(&self) -> bool {
+        let Some(window) = self.window else {
+            return true;
+        };
+        self.moving || self.armed_at.is_some_and(|armed_at| armed_at.elapsed() <= window)
+    }
+
+    // Refuses the whole batch if any command would move the belt before it's armed.
+    pub fn check(&mut self, commands: &[TreadmillCommands]) -> Result<(), CommandError> {
+        if !commands.iter().any(moves_belt) {
+            return Ok(());
+        }
+        if !self.ready() {
+            return Err(CommandError::NotArmed);
+        }
+        self.moving = true;
+        self.armed_at = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+// When each workout was last started, by workout name, kept as JSON in the app data dir for the
+// workout list.
+
+use crate::{error::CommandError, timestamp_millis};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use tauri::AppHandle;
+
+const LAST_RUN_FILE: &str = "last_run.json";
+
+fn last_run_path(app: &AppHandle) -> Option<PathBuf> {
+    app.path_resolver().app_data_dir().map(|dir| dir.join(LAST_RUN_FILE))
+}
+
+// Milliseconds since the unix epoch by workout name, empty when nothing has run yet or the file
+// can't be read.
+pub fn load(app: &AppHandle) -> BTreeMap<String, u64> {
+    let Some(path) = last_run_path(app) else {
+        return BTreeMap::new();
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => {
+            eprintln!("Error reading last runs from {:?}: {:?}", path, e);
+            return BTreeMap::new();
+        }
+    };
+
+    match serde_json::from_str(&content) {
+        Ok(last_runs) => last_runs,
+        Err(e) => {
+            eprintln!("Error parsing last runs: {:?}", e);
+            BTreeMap::new()
+        }
+    }
+}
+
+pub fn record(app: &AppHandle, workout: &str) -> Result<(), CommandError> {
+    let path = last_run_path(app).ok_or_else(|| CommandError::Io("No app data directory.".to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| CommandError::Io(e.to_string()))?;
+    }
+    let mut last_runs = load(app);
+    last_runs.insert(workout.to_string(), timestamp_millis());
+    let content = serde_json::to_string_pretty(&last_runs).map_err(|e| CommandError::Io(e.to_string()))?;
+    fs::write(&path, content).map_err(|e| CommandError::Io(e.to_string()))
+}
@@ -0,0 +1,53 @@
+// Fitness Machine Status notifications, which report changes on the machine's side such as the
+// target it actually applied after clamping a request to its range.
+
+use crate::DecodeError;
+use std::time::Instant;
+
+const TARGET_SPEED_CHANGED: u8 = 0x05;
+const TARGET_INCLINE_CHANGED: u8 = 0x06;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineStatus {
+    // Km/h at 0.01 precision
+    TargetSpeedChanged(u16),
+    // Percent grade at 0.1 precision
+    TargetInclineChanged(i16),
+    // Any other status, by opcode
+    Other(u8),
+}
+
+pub fn decode_machine_status(data: &[u8]) -> Result<MachineStatus, DecodeError> {
+    let opcode = *data.first().ok_or(DecodeError::NotEnoughData)?;
+    let parameter = || data.get(1..3).map(|b| [b[0], b[1]]).ok_or(DecodeError::NotEnoughData);
+    match opcode {
+        TARGET_SPEED_CHANGED => Ok(MachineStatus::TargetSpeedChanged(u16::from_le_bytes(parameter()?))),
+        TARGET_INCLINE_CHANGED => Ok(MachineStatus::TargetInclineChanged(i16::from_le_bytes(parameter()?))),
+        opcode => Ok(MachineStatus::Other(opcode)),
+    }
+}
+
+// The latest targets the machine reported applying, and when they arrived.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AppliedTargets {
+    pub speed: Option<(u16, Instant)>,
+    pub incline: Option<(i16, Instant)>,
+}
+
+impl AppliedTargets {
+    pub fn update(&mut self, status: MachineStatus) {
+        match status {
+            MachineStatus::TargetSpeedChanged(speed) => self.speed = Some((speed, Instant::now())),
+            MachineStatus::TargetInclineChanged(incline) => self.incline = Some((incline, Instant::now())),
+            MachineStatus::Other(_) => {}
+        }
+    }
+
+    pub fn speed_since(&self, since: Instant) -> Option<u16> {
+        self.speed.filter(|(_, at)| *at >= since).map(|(speed, _)| speed)
+    }
+
+    pub fn incline_since(&self, since: Instant) -> Option<i16> {
+        self.incline.filter(|(_, at)| *at >= since).map(|(incline, _)| incline)
+    }
+}
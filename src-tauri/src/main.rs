@@ -2,22 +2,357 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use btleplug::api::{
-    bleuuid::uuid_from_u16, Central, Manager as _, Peripheral as _, ScanFilter, WriteType,
+    bleuuid::{uuid_from_u16, BleUuid as _}, CentralEvent, CharPropFlags, Central, Characteristic, Manager as _, Peripheral as _, ScanFilter,
+    WriteType,
 };
-use futures::StreamExt;
-use btleplug::platform::{Adapter, Manager, Peripheral};
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
+use btleplug::platform::{Adapter, Manager, Peripheral, PeripheralId};
+use capabilities::MachineCapabilities;
+use control_point::{ControlPointResponse, ResponseBuffer, ResultCode};
+use device_profile::{DeviceProfile, TreadmillDecoder};
+use error::CommandError;
+use heart_rate_limit::{HeartRateAction, HeartRateLimit, HeartRateLimitEvent};
+use heart_rate_strap::HeartRateStrap;
+use heart_rate_zone::{HeartRateZone, ZoneController};
+use interlock::Interlock;
+use machine_status::{AppliedTargets, MachineStatus};
+use schemars::JsonSchema;
+use runner::WorkoutPosition;
+use session::{DistanceCalibration, DistanceEstimate, SessionDistance, SessionStats, SessionTimer, Splits};
+use session_log::SessionLog;
+use settings::{ExtraStep, Settings};
+use snapshot::Snapshot;
 use serde::{Deserialize, Serialize};
-use std::{fs, sync::{Arc, Mutex}, time::Duration};
-use tokio::time;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::Write as _,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tauri::{async_runtime::JoinHandle, AppHandle, GlobalWindowEvent, Manager as _, State, WindowEvent};
+use tokio::{
+    sync::{broadcast, Notify},
+    time,
+};
+use units::KM_PER_MILE;
 use uuid::Uuid;
 
+mod calories;
+mod capabilities;
+mod control_point;
+#[cfg(feature = "data-server")]
+mod data_server;
+mod device_profile;
+mod error;
+mod heart_rate_limit;
+mod heart_rate_strap;
+mod heart_rate_zone;
+mod indoor_bike;
+mod interlock;
+mod last_run;
+mod machine_status;
+#[cfg(test)]
+mod mock_machine;
+mod replay;
+mod runner;
+mod session;
+mod session_log;
+mod settings;
+mod simulator;
+mod snapshot;
+mod speed_ramp;
+mod units;
+mod workout_migration;
+mod workout_text;
+
+#[derive(Default)]
 struct AppState {
-    central: Adapter,
-    treadmill: Option<Arc<Mutex<Peripheral>>>,
+    central: Mutex<Option<Adapter>>,
+    treadmill: Mutex<Option<TreadmillConnection>>,
+    signal_monitor: Mutex<Option<JoinHandle<()>>>,
+    notifications: Mutex<Option<JoinHandle<()>>>,
+    reconnect_watcher: Mutex<Option<JoinHandle<()>>>,
+    capabilities: Mutex<Option<MachineCapabilities>>,
+    settings: Mutex<Settings>,
+    // Whether the heart rate limit is what paused the belt
+    heart_rate_paused: Mutex<bool>,
+    // Fields present in the most recent Treadmill Data frame
+    data_fields: Mutex<Option<Vec<&'static str>>>,
+    latest_data: Mutex<Option<TreadmillData>>,
+    data_emit: Mutex<EmitThrottle>,
+    workout_runner: Mutex<Option<JoinHandle<()>>>,
+    session_distance: Mutex<SessionDistance>,
+    distance_estimate: Mutex<DistanceEstimate>,
+    running_metrics: Mutex<Option<calories::RunningMetrics>>,
+    session_timer: Mutex<SessionTimer>,
+    splits: Mutex<Splits>,
+    // Everything the connected device exposed at its last service discovery
+    characteristics: Mutex<Option<Vec<CharacteristicInfo>>>,
+    // Profile of the connected model, `None` uses the standard one
+    device_profile: Mutex<Option<&'static DeviceProfile>>,
+    session_log: Mutex<Option<SessionLog>>,
+    session_stats: Mutex<SessionStats>,
+    control_point_buffer: Mutex<ResponseBuffer>,
+    // Fired by `cancel_connect` while `connect_to_treadmill` is running
+    connect_cancel: Mutex<Option<Arc<Notify>>>,
+    // Latest speed from `set_target_speed` waiting out the debounce interval
+    pending_target_speed: Mutex<Option<u16>>,
+    // Whether the machine last acknowledged our request for control, shared with the connection,
+    // which clears it when a procedure times out
+    control_granted: Arc<Mutex<bool>>,
+    // Last targets the machine acknowledged
+    targets: Mutex<TargetsUpdated>,
+    // What Fitness Machine Status says the machine applied, which can differ from what we asked for
+    applied_targets: Mutex<AppliedTargets>,
+    applied_targets_changed: Notify,
+    // The treadmill we last connected to, for reconnecting without a scan
+    last_peripheral_id: Mutex<Option<PeripheralId>>,
+    // Shared with the connection, which checks it before every write
+    interlock: Arc<Mutex<Interlock>>,
+    // Kept on disk as it changes, see `snapshot`
+    snapshot: Mutex<Snapshot>,
+    // What the previous run left behind, until it's restored
+    restorable: Mutex<Option<Snapshot>>,
+    // Whether losing window focus is what paused the belt
+    blur_paused: Mutex<bool>,
+    // Connected next to the treadmill, which keeps everything above to itself
+    heart_rate_strap: Mutex<Option<HeartRateStrap>>,
+    // Latest reading from the strap and when it arrived
+    strap_heart_rate: Mutex<Option<(u8, Instant)>>,
+    // Kind of data the connected machine was last set up to send
+    machine_type: Mutex<Option<MachineType>>,
+    // Steers the target speed while a heart rate zone is set
+    heart_rate_zone: Mutex<Option<ZoneController>>,
+    // Kept up to date by the runner while a workout is running
+    workout_position: Mutex<Option<WorkoutPosition>>,
+    // Where the workout was when the connection dropped, until it's resumed or stopped
+    interrupted_workout: Mutex<Option<WorkoutPosition>>,
+    // One per finished step of the current or last workout
+    laps: Mutex<Vec<runner::Lap>>,
+    // Recorded session being played back as `treadmill-data`
+    replay: Mutex<Option<replay::Replay>>,
+    #[cfg(feature = "data-server")]
+    data_server: Mutex<Option<data_server::DataServer>>,
+}
+
+impl AppState {
+    fn connection(&self) -> Result<TreadmillConnection, CommandError> {
+        self.treadmill.lock().unwrap().clone().ok_or(CommandError::NotConnected)
+    }
+}
+
+// Limits how often `treadmill-data` reaches the frontend, see `emit_treadmill_data`.
+#[derive(Default)]
+struct EmitThrottle {
+    last_emit: Option<Instant>,
+    // Latest frame held back, with an emit scheduled for when the interval is up
+    pending: Option<TreadmillData>,
+}
+
+#[derive(Debug)]
+enum Throttled {
+    Emit(TreadmillData),
+    // Held back, emit whatever `take_pending` returns after waiting this long
+    EmitLater(Duration),
+    // Took the place of a frame already held back
+    Replaced,
+}
+
+impl EmitThrottle {
+    fn offer(&mut self, data: TreadmillData, interval: Duration, now: Instant) -> Throttled {
+        let since = self.last_emit.map(|at| now.saturating_duration_since(at));
+        match since.filter(|since| *since < interval) {
+            None => {
+                self.last_emit = Some(now);
+                self.pending = None;
+                Throttled::Emit(data)
+            }
+            Some(since) => match self.pending.replace(data) {
+                Some(_) => Throttled::Replaced,
+                None => Throttled::EmitLater(interval - since),
+            },
+        }
+    }
+
+    fn take_pending(&mut self, now: Instant) -> Option<TreadmillData> {
+        let data = self.pending.take()?;
+        self.last_emit = Some(now);
+        Some(data)
+    }
+}
+
+// Notices machine data going quiet for longer than `timeout`, see `setup_services`.
+struct StallWatchdog {
+    timeout: Duration,
+    deadline: time::Instant,
+    stalled: bool,
+}
+
+#[derive(Debug)]
+enum Watched<T> {
+    Next(T),
+    // Nothing came in time, only returned once until the next frame
+    Stalled,
+    Ended,
+}
+
+impl StallWatchdog {
+    fn new(timeout: Duration) -> Self {
+        StallWatchdog { timeout, deadline: time::Instant::now() + timeout, stalled: false }
+    }
+
+    async fn next<S: futures::Stream + Unpin>(&mut self, stream: &mut S) -> Watched<S::Item> {
+        let next = if self.stalled { Ok(stream.next().await) } else { time::timeout_at(self.deadline, stream.next()).await };
+        match next {
+            Ok(Some(item)) => Watched::Next(item),
+            Ok(None) => Watched::Ended,
+            Err(_) => {
+                self.stalled = true;
+                Watched::Stalled
+            }
+        }
+    }
+
+    // Pushes the deadline back for a data frame, true when it ends a stall
+    fn frame(&mut self) -> bool {
+        self.deadline = time::Instant::now() + self.timeout;
+        std::mem::take(&mut self.stalled)
+    }
+}
+
+#[derive(Clone)]
+enum Transport {
+    Ble { peripheral: Peripheral, control_point: Characteristic },
+    Simulator(simulator::Simulator),
+    #[cfg(test)]
+    Mock(mock_machine::MockMachine),
+}
+
+#[derive(Clone)]
+struct TreadmillConnection {
+    transport: Transport,
+    responses: broadcast::Sender<ControlPointResponse>,
+    interlock: Arc<Mutex<Interlock>>,
+    control_granted: Arc<Mutex<bool>>,
+    procedure_timeout: Duration,
+}
+
+impl TreadmillConnection {
+    fn peripheral(&self) -> Result<&Peripheral, CommandError> {
+        match &self.transport {
+            Transport::Ble { peripheral, .. } => Ok(peripheral),
+            Transport::Simulator(_) => Err(CommandError::NotSupported("Bluetooth access on the simulator".to_string())),
+            #[cfg(test)]
+            Transport::Mock(_) => Err(CommandError::NotSupported("Bluetooth access on the mock machine".to_string())),
+        }
+    }
+
+    async fn write_control_point(&self, message: &[u8]) -> Result<(), CommandError> {
+        match &self.transport {
+            Transport::Ble { peripheral, control_point } => {
+                peripheral.write(control_point, message, WriteType::WithoutResponse).await?;
+            }
+            Transport::Simulator(simulator) => simulator.write(message),
+            #[cfg(test)]
+            Transport::Mock(machine) => machine.write(message)?,
+        }
+        Ok(())
+    }
+
+    // Writes the commands back-to-back, then waits until the treadmill has acknowledged each one.
+    async fn send_commands(&self, commands: Vec<TreadmillCommands>) -> Result<Vec<ControlPointResponse>, CommandError> {
+        self.interlock.lock().unwrap().check(&commands)?;
+        let mut responses = self.responses.subscribe();
+
+        let mut opcodes = Vec::new();
+        for command in commands {
+            let message = treadmill_command_to_message(command);
+            opcodes.push(message[0]);
+            self.write_control_point(&message).await?;
+        }
+
+        let responses = match control_point::wait_for_responses(&mut responses, &opcodes, self.procedure_timeout).await {
+            Ok(responses) => responses,
+            Err(e @ CommandError::ProcedureTimeout(opcode)) => {
+                // No telling whether the machine still considers us in control, ask again next time
+                eprintln!("Control point request {:#04x} timed out.", opcode);
+                *self.control_granted.lock().unwrap() = false;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(failed) = responses.iter().find(|r| r.result != ResultCode::Success) {
+            eprintln!("Control point request {:#04x} failed: {:?}", failed.request_opcode, failed.result);
+            if failed.result == ResultCode::ControlNotPermitted {
+                return Err(CommandError::ControlNotPermitted);
+            }
+            return Err(CommandError::ControlRejected(format!("{:?}", failed.result)));
+        }
+
+        Ok(responses)
+    }
+
+    // Asks for control, recording whether the machine granted it.
+    async fn request_control(&self) -> Result<(), CommandError> {
+        let result = self.send_commands(vec![TreadmillCommands::RequestControl]).await;
+        *self.control_granted.lock().unwrap() = result.is_ok();
+        result.map(|_| ())
+    }
+
+    // Sends `command`, and when the machine says it took control back, requests control and sends it
+    // once more. Returns whether control had to be regained.
+    async fn send_regaining_control(&self, command: TreadmillCommands) -> Result<bool, CommandError> {
+        match self.send_commands(vec![command]).await {
+            Err(e) if control_lost(&e) => {
+                println!("Treadmill revoked control, requesting it again.");
+                self.request_control().await?;
+                self.send_commands(vec![command]).await?;
+                Ok(true)
+            }
+            result => result.map(|_| false),
+        }
+    }
 }
 
+const DEFAULT_SIGNAL_MONITOR_INTERVAL_MS: u64 = 1000;
+const DEFAULT_SPEED_DEBOUNCE_MS: u32 = 250;
+const DEFAULT_DATA_EMIT_INTERVAL_MS: u32 = 250;
+const CONNECT_ATTEMPTS: u32 = 3;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_VERIFY_TIMEOUT: Duration = Duration::from_secs(2);
+const CONNECT_VERIFY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const SERVICE_DISCOVERY_ATTEMPTS: u32 = 3;
+const SERVICE_DISCOVERY_DELAY: Duration = Duration::from_millis(500);
+const SUBSCRIBE_ATTEMPTS: u32 = 3;
+// How often to check whether a powered off adapter is back
+const ADAPTER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const SUBSCRIBE_RETRY_DELAY: Duration = Duration::from_millis(500);
+// How long to wait for the first machine data frame after subscribing
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(3);
+
+const KNOWN_TREADMILL_NAME: &str = "HORIZON_7.0AT";
+
+const FITNESS_MACHINE_SERVICE_UUID: Uuid = uuid_from_u16(0x1826);
 const TREADMILL_DATA_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2ACD);
+const INDOOR_BIKE_DATA_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2AD2);
 const TREADMILL_CONTROL_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2AD9);
+const FITNESS_MACHINE_FEATURE_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2ACC);
+const SUPPORTED_SPEED_RANGE_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2AD4);
+const SUPPORTED_INCLINATION_RANGE_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2AD5);
+const SUPPORTED_POWER_RANGE_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2AD8);
+const MACHINE_STATUS_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2ADA);
+// Standard Battery Service, mostly found on heart rate straps
+const BATTERY_LEVEL_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2A19);
+// Device Information Service strings
+const MANUFACTURER_NAME_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2A29);
+const MODEL_NUMBER_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2A24);
+const FIRMWARE_REVISION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0x2A26);
 
 #[derive(Debug, Serialize, Deserialize)]
 struct TreadmillDataFlags {
@@ -36,13 +371,48 @@ struct TreadmillDataFlags {
     force_on_belt_and_power_output: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Fitness machines we can decode data for, told apart by the data characteristic they expose.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum MachineType {
+    Treadmill,
+    IndoorBike,
+}
+
+impl MachineType {
+    fn data_characteristic(self) -> Uuid {
+        match self {
+            MachineType::Treadmill => TREADMILL_DATA_CHARACTERISTIC_UUID,
+            MachineType::IndoorBike => INDOOR_BIKE_DATA_CHARACTERISTIC_UUID,
+        }
+    }
+
+    fn from_data_characteristic(uuid: Uuid) -> Option<MachineType> {
+        [MachineType::Treadmill, MachineType::IndoorBike].into_iter().find(|t| t.data_characteristic() == uuid)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 struct TreadmillData {
+    // Km/h at 0.01 precision
     speed: u16,
+    // Km/h at 0.01 precision, only on machines that flag it
     average_speed: Option<u16>,
+    // Converted from the raw fields above and below, see `convert_units`
+    speed_kmh: f32,
+    speed_mph: f32,
+    average_speed_kmh: Option<f32>,
+    average_speed_mph: Option<f32>,
+    // Current pace as "m:ss" per km and per mile, "--" while the belt is stopped
+    pace_per_km: String,
+    pace_per_mile: String,
     total_distance: Option<u32>,
+    // Percent grade at 0.1 precision
     inclination: Option<i16>,
+    inclination_percent: Option<f32>,
+    // Degrees at 0.1 precision
     ramp_angle: Option<i16>,
+    ramp_angle_degrees: Option<f32>,
     positive_elevation: Option<u16>,
     negative_elevation: Option<u16>,
     instantaneous_pace: Option<u16>,
@@ -51,17 +421,96 @@ struct TreadmillData {
     energy_per_hour: Option<u16>,
     energy_per_minute: Option<u8>,
     heart_rate: Option<u8>,
+    // Raw value in 0.1 MET units, e.g. 80 is 8.0 METs
     metabolic_equivalent: Option<u8>,
+    // METs converted from the raw 0.1 resolution value
+    metabolic_equivalent_met: Option<f32>,
     elapsed_time: Option<u16>,
     remaining_time: Option<u16>,
+    // Newtons, negative when the belt pushes against the user
     force_on_belt: Option<i16>,
+    // Watts
     power_output: Option<i16>,
+    // Bytes left over after the flagged fields, see `decode_treadmill_data`
+    #[serde(default)]
+    trailing_bytes: usize,
+    // Meters since the session started, worked out from total distance rather than decoded
+    session_distance: Option<u32>,
+    // Meters since the session started from integrating speed, on frames without a total distance
+    estimated_distance: Option<u32>,
+    // What the raw speeds were multiplied by to get 0.01 km/h, 1 unless the device profile says otherwise
+    speed_scale: f64,
+    // Kcal per hour worked out from speed and incline, `None` without a body weight in the settings
+    estimated_energy_per_hour: Option<f64>,
+}
+
+impl TreadmillData {
+    // For devices that report speed in other units than the spec's 0.01 km/h.
+    fn scale_speeds(&mut self, scale: f64) {
+        if scale == 1.0 {
+            return;
+        }
+        let scaled = |speed: u16| (speed as f64 * scale).round().min(u16::MAX as f64) as u16;
+        self.average_speed = self.average_speed.map(scaled);
+        self.speed = scaled(self.speed);
+        self.speed_scale = scale;
+        self.convert_units();
+    }
+
+    // Fills in the float and text versions of the raw fixed point fields.
+    fn convert_units(&mut self) {
+        self.speed_kmh = units::kmh(self.speed) as f32;
+        self.speed_mph = units::mph(self.speed) as f32;
+        self.inclination_percent = self.inclination.map(|inclination| units::percent(inclination) as f32);
+        self.ramp_angle_degrees = self.ramp_angle.map(|angle| units::degrees(angle) as f32);
+        self.metabolic_equivalent_met = self.metabolic_equivalent.map(|met| units::mets(met) as f32);
+        self.average_speed_kmh = self.average_speed.map(|speed| units::kmh(speed) as f32);
+        self.average_speed_mph = self.average_speed.map(|speed| units::mph(speed) as f32);
+        self.pace_per_km = units::pace(self.speed, 1.0);
+        self.pace_per_mile = units::pace(self.speed, KM_PER_MILE);
+    }
+
+    // Names of the payload fields this frame carries, which depends on the machine's flags.
+    fn populated_fields(&self) -> Vec<&'static str> {
+        let optional = [
+            ("average_speed", self.average_speed.is_some()),
+            ("average_speed_kmh", self.average_speed_kmh.is_some()),
+            ("average_speed_mph", self.average_speed_mph.is_some()),
+            ("total_distance", self.total_distance.is_some()),
+            ("inclination", self.inclination.is_some()),
+            ("inclination_percent", self.inclination_percent.is_some()),
+            ("ramp_angle", self.ramp_angle.is_some()),
+            ("ramp_angle_degrees", self.ramp_angle_degrees.is_some()),
+            ("positive_elevation", self.positive_elevation.is_some()),
+            ("negative_elevation", self.negative_elevation.is_some()),
+            ("instantaneous_pace", self.instantaneous_pace.is_some()),
+            ("average_pace", self.average_pace.is_some()),
+            ("total_energy", self.total_energy.is_some()),
+            ("energy_per_hour", self.energy_per_hour.is_some()),
+            ("energy_per_minute", self.energy_per_minute.is_some()),
+            ("heart_rate", self.heart_rate.is_some()),
+            ("metabolic_equivalent", self.metabolic_equivalent.is_some()),
+            ("metabolic_equivalent_met", self.metabolic_equivalent_met.is_some()),
+            ("elapsed_time", self.elapsed_time.is_some()),
+            ("remaining_time", self.remaining_time.is_some()),
+            ("force_on_belt", self.force_on_belt.is_some()),
+            ("power_output", self.power_output.is_some()),
+        ];
+
+        let mut fields = vec!["speed"];
+        fields.extend(optional.iter().filter(|(_, present)| *present).map(|(name, _)| *name));
+        fields
+    }
 }
 
+#[derive(Debug)]
 enum DecodeError {
     NotEnoughData,
 }
 
+// From the frontend as e.g. `{ "command": "set_target_speed", "value": 500 }`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "command", content = "value", rename_all = "snake_case")]
 enum TreadmillCommands {
     RequestControl,
     Reset,
@@ -69,14 +518,33 @@ enum TreadmillCommands {
     SetTargetInclination(i16),
     StartOrResume,
     StopOrPause,
-    // TODO: This is a u24, not a u32
+    // Sent as a u24, anything above 0xFFFFFF is clamped
     SetTargetedDistance(u32),
     SetTargetedTrainingTime(u16),
 }
 
+const U24_MAX: u32 = 0xFFFFFF;
+
+// Reads the first three bytes, anything after them is left to the caller.
+fn read_u24_le(bytes: &[u8]) -> Result<u32, DecodeError> {
+    match bytes {
+        [b0, b1, b2, ..] => Ok(u32::from_le_bytes([*b0, *b1, *b2, 0])),
+        _ => Err(DecodeError::NotEnoughData),
+    }
+}
+
+fn write_u24_le(value: u32) -> [u8; 3] {
+    let bytes = value.min(U24_MAX).to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+// Two flag bytes plus the mandatory instantaneous speed. Anything shorter, including an empty
+// frame, is rejected before the flags are read, whatever they would have said.
+const MIN_TREADMILL_DATA_LEN: usize = 4;
+
 // Decoding based on https://github.com/oesmith/gatt-xml/blob/master/org.bluetooth.characteristic.treadmill_data.xml
 fn decode_treadmill_data(data: &[u8]) -> Result<TreadmillData, DecodeError> {
-    if data.len() < 4 {
+    if data.len() < MIN_TREADMILL_DATA_LEN {
         return Err(DecodeError::NotEnoughData);
     }
 
@@ -96,7 +564,7 @@ fn decode_treadmill_data(data: &[u8]) -> Result<TreadmillData, DecodeError> {
         force_on_belt_and_power_output: data[1] & 0b00010000 != 0,
     };
     let speed = u16::from_le_bytes([data[2], data[3]]);
-    let mut cursor = 4;
+    let mut cursor = MIN_TREADMILL_DATA_LEN;
 
     let mut average_speed = None;
     if flags.average_speed {
@@ -109,11 +577,7 @@ fn decode_treadmill_data(data: &[u8]) -> Result<TreadmillData, DecodeError> {
 
     let mut total_distance = None;
     if flags.total_distance {
-        if data.len() < cursor + 3 {
-            return Err(DecodeError::NotEnoughData);
-        }
-        // TODO: 0 might be in the wrong place, this is a u24...
-        total_distance = Some(u32::from_le_bytes([data[cursor], data[cursor + 1], data[cursor + 2], 0]));
+        total_distance = Some(read_u24_le(&data[cursor..])?);
         cursor += 3;
     }
 
@@ -188,6 +652,8 @@ fn decode_treadmill_data(data: &[u8]) -> Result<TreadmillData, DecodeError> {
         cursor += 1;
     }
 
+    // Elapsed and remaining time are flagged separately, some machines only count down and send
+    // remaining time alone, so each advances the cursor on its own
     let mut elapsed_time = None;
     if flags.elapsed_time {
         if data.len() < cursor + 2 {
@@ -214,14 +680,27 @@ fn decode_treadmill_data(data: &[u8]) -> Result<TreadmillData, DecodeError> {
         }
         force_on_belt = Some(i16::from_le_bytes([data[cursor], data[cursor + 1]]));
         power_output = Some(i16::from_le_bytes([data[cursor + 2], data[cursor + 3]]));
+        cursor += 4;
     }
 
-    Ok(TreadmillData {
+    // Bytes past the last flagged field usually mean the flags were misread or the vendor tacked
+    // something on. The fields we did read are still good, so they're only counted.
+    let trailing_bytes = data.len() - cursor;
+
+    let mut data = TreadmillData {
         speed,
         average_speed,
+        speed_kmh: 0.0,
+        speed_mph: 0.0,
+        average_speed_kmh: None,
+        average_speed_mph: None,
+        pace_per_km: String::new(),
+        pace_per_mile: String::new(),
         total_distance,
         inclination,
+        inclination_percent: None,
         ramp_angle,
+        ramp_angle_degrees: None,
         positive_elevation,
         negative_elevation,
         instantaneous_pace,
@@ -231,11 +710,19 @@ fn decode_treadmill_data(data: &[u8]) -> Result<TreadmillData, DecodeError> {
         energy_per_minute,
         heart_rate,
         metabolic_equivalent,
+        metabolic_equivalent_met: None,
         elapsed_time,
         remaining_time,
         force_on_belt,
         power_output,
-    })
+        trailing_bytes,
+        session_distance: None,
+        estimated_distance: None,
+        speed_scale: 1.0,
+        estimated_energy_per_hour: None,
+    };
+    data.convert_units();
+    Ok(data)
 }
 
 fn treadmill_command_to_message(command: TreadmillCommands) -> Vec<u8> {
@@ -246,11 +733,22 @@ fn treadmill_command_to_message(command: TreadmillCommands) -> Vec<u8> {
         TreadmillCommands::SetTargetInclination(inclination) => vec![0x03, inclination.to_le_bytes()[0], inclination.to_le_bytes()[1]],
         TreadmillCommands::StartOrResume => vec![0x07],
         TreadmillCommands::StopOrPause => vec![0x08],
-        TreadmillCommands::SetTargetedDistance(distance) => vec![0x0C, distance.to_le_bytes()[0], distance.to_le_bytes()[1], distance.to_le_bytes()[2]],
+        TreadmillCommands::SetTargetedDistance(distance) => {
+            let distance = write_u24_le(distance);
+            vec![0x0C, distance[0], distance[1], distance[2]]
+        }
         TreadmillCommands::SetTargetedTrainingTime(time) => vec![0x0D, time.to_le_bytes()[0], time.to_le_bytes()[1]],
     }
 }
 
+// The bytes `command` would be written to the control point as, without sending anything.
+#[tauri::command]
+fn preview_command(command: TreadmillCommands) -> Result<Vec<u8>, CommandError> {
+    Ok(treadmill_command_to_message(command))
+}
+
+// Prefers a treadmill we know by name, otherwise takes the first device advertising the Fitness
+// Machine Service so unlisted models are still found.
 async fn find_treadmill(central: &Adapter) -> Option<Peripheral> {
     let peripherals = match central.peripherals().await {
         Ok(p) => p,
@@ -260,29 +758,49 @@ async fn find_treadmill(central: &Adapter) -> Option<Peripheral> {
         }
     };
 
+    let mut fitness_machine = None;
     for p in peripherals {
-        if p.properties().await.unwrap().unwrap().local_name.iter().any(|name| name.contains("HORIZON_7.0AT")) {
+        let properties = match p.properties().await {
+            Ok(Some(properties)) => properties,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("Error reading peripheral properties: {:?}", e);
+                continue;
+            }
+        };
+        if properties.local_name.iter().any(|name| name.contains(KNOWN_TREADMILL_NAME)) {
             return Some(p);
         }
+        if fitness_machine.is_none() && properties.services.contains(&FITNESS_MACHINE_SERVICE_UUID) {
+            println!("Found fitness machine {:?}.", properties.local_name);
+            fitness_machine = Some(p);
+        }
     }
 
-    return None;
+    fitness_machine
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Despite the name this covers both ways of describing how fast a step runs. Mph and Kph are
+// speeds given as a decimal number ("10.5"), MinPerMi and MinPerKm are paces given as time per
+// distance ("8:30").
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "unit", content = "value")]
 enum PaceRaw {
+    // Speed, miles per hour
     #[serde(rename = "mph")]
-    MPH(String),
+    Mph(String),
+    // Speed, kilometers per hour
     #[serde(rename = "kph")]
-    KPH(String),
+    Kph(String),
+    // Pace, minutes:seconds per mile
     #[serde(rename = "min/mi")]
     MinPerMi(String),
+    // Pace, minutes:seconds per kilometer
     #[serde(rename = "min/km")]
     MinPerKm(String),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type")]
 enum WorkoutStepRaw {
     #[serde(rename = "repeat")]
@@ -299,7 +817,7 @@ enum WorkoutStepRaw {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct WorkoutRaw {
     name: String,
     description: String,
@@ -309,199 +827,4354 @@ struct WorkoutRaw {
 #[derive(Debug, Serialize, Clone)]
 struct WorkoutStep {
     name: String,
+    // Seconds
     duration: u16,
-    distance: u16,
+    // Meters
+    distance: u32,
     // Km/h at 0.01 precision
     pace: u16,
     angle: i16,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 struct Workout {
+    // Seconds
     duration: u16,
-    distance: u16,
+    // Meters
+    distance: u32,
     steps: Vec<WorkoutStep>,
     name: String,
     description: String,
 }
 
-fn parse_pace(pace: &PaceRaw) -> u16 {
-    match pace {
-        PaceRaw::MinPerMi(value) => {
-            let parts = value.split(":").collect::<Vec<_>>();
-            let minutes = parts.get(0).unwrap_or(&"0").parse::<u16>().unwrap();
-            let seconds = parts.get(1).unwrap_or(&"0").parse::<u16>().unwrap();
-            let seconds_per_mile = (minutes * 60 + seconds) as f64;
-            let km_per_hour = 1. / seconds_per_mile * (60.0 * 60.0) * (1.60934/1.);
-            println!("Seconds per mile: {:?}", seconds_per_mile as u16);
-            println!("Km per hour: {:?}", (km_per_hour * 100.) as u16);
-            (km_per_hour * 100.) as u16
+const WORKOUTS_DIR: &str = "/Users/kyle/Projects/run/workouts";
+// How long a workout saved from the current targets runs for
+const QUICK_WORKOUT_DURATION: &str = "30:00";
+
+// Target speed and inclination over time, one point per step boundary plus one at the end so the
+// last step has a width when drawn as a step chart.
+#[derive(Debug, Serialize)]
+struct WorkoutProfile {
+    // (elapsed seconds, km/h at 0.01 precision)
+    speed: Vec<(u32, u16)>,
+    // (elapsed seconds, step angle)
+    inclination: Vec<(u32, i16)>,
+}
+
+impl Workout {
+    fn profile(&self) -> WorkoutProfile {
+        let mut speed = Vec::new();
+        let mut inclination = Vec::new();
+        let mut elapsed = 0u32;
+        for step in &self.steps {
+            speed.push((elapsed, step.pace));
+            inclination.push((elapsed, step.angle));
+            elapsed += step.duration as u32;
+        }
+        if let Some(last) = self.steps.last() {
+            speed.push((elapsed, last.pace));
+            inclination.push((elapsed, last.angle));
+        }
+
+        WorkoutProfile { speed, inclination }
+    }
+}
+
+#[tauri::command]
+fn workout_profile(
+    state: State<'_, AppState>,
+    workout: WorkoutRaw,
+    include_warm_up: Option<bool>,
+) -> Result<WorkoutProfile, CommandError> {
+    Ok(prepare_workout(&state, &workout, include_warm_up)?.profile())
+}
+
+// A step the connected machine can't run as written, with the closest targets it can do.
+#[derive(Debug, Serialize, Clone)]
+struct StepRangeProblem {
+    index: usize,
+    // Km/h at 0.01 precision, only set when the step's speed is out of range
+    suggested_speed: Option<u16>,
+    // Percent grade at 0.1 precision, only set when the step's incline is out of range
+    suggested_incline: Option<i16>,
+}
+
+fn validate_workout(workout: &Workout, capabilities: &MachineCapabilities) -> Vec<StepRangeProblem> {
+    workout
+        .steps
+        .iter()
+        .enumerate()
+        .filter_map(|(index, step)| {
+            let suggested_speed = capabilities
+                .speed_range
+                .map(|range| step.pace.max(range.minimum).min(range.maximum))
+                .filter(|speed| *speed != step.pace);
+            let suggested_incline = capabilities
+                .inclination_range
+                .map(|range| step.angle.max(range.minimum).min(range.maximum))
+                .filter(|incline| *incline != step.angle);
+            if suggested_speed.is_none() && suggested_incline.is_none() {
+                return None;
+            }
+            Some(StepRangeProblem { index, suggested_speed, suggested_incline })
+        })
+        .collect()
+}
+
+#[tauri::command]
+async fn validate_workout_against_machine(
+    state: State<'_, AppState>,
+    workout: WorkoutRaw,
+) -> Result<Vec<StepRangeProblem>, CommandError> {
+    let capabilities = machine_capabilities(&state).await?;
+    Ok(validate_workout(&parse_workout(&workout)?, &capabilities))
+}
+
+// Runs the workout on the connected treadmill, replacing any workout already running. Steps the
+// machine can't do are reported with `workout-out-of-range` but the workout still starts.
+#[tauri::command]
+async fn start_workout(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    workout: WorkoutRaw,
+    include_warm_up: Option<bool>,
+) -> Result<(), CommandError> {
+    // The runner can't be armed on its own, so refuse up front rather than stall on the first write
+    if !state.interlock.lock().unwrap().ready() {
+        return Err(CommandError::NotArmed);
+    }
+    let capabilities = machine_capabilities(&state).await?;
+    let workout = prepare_workout(&state, &workout, include_warm_up)?;
+
+    let problems = validate_workout(&workout, &capabilities);
+    if !problems.is_empty() {
+        println!("Workout {} has steps outside the machine's range: {:?}", workout.name, problems);
+        if let Err(e) = app.emit_all("workout-out-of-range", problems) {
+            eprintln!("Error emitting workout range problems: {:?}", e);
+        }
+    }
+
+    *state.interrupted_workout.lock().unwrap() = None;
+    let mut current = state.workout_runner.lock().unwrap();
+    if let Some(previous) = current.take() {
+        previous.abort();
+    }
+    *current = Some(tauri::async_runtime::spawn(runner::run_workout(app, workout, None)));
+    Ok(())
+}
+
+// Stops the runner of a workout the connection dropped out of, or whose commands stopped going
+// through, and keeps its position for `resume_workout`. The runner would otherwise keep its step
+// clock going while nothing can reach the treadmill.
+fn interrupt_workout(app: &AppHandle) {
+    let Some(position) = hold_interrupted_workout(&app.state::<AppState>()) else {
+        return;
+    };
+    println!("Workout {} interrupted, holding it at {:?}.", position.workout.name, position);
+    if let Err(e) = app.emit_all("workout-interrupted", position) {
+        eprintln!("Error emitting workout interrupted: {:?}", e);
+    }
+}
+
+// Stops the runner and keeps where it had got to as the interrupted workout. `None` when no
+// workout was running.
+fn hold_interrupted_workout(state: &AppState) -> Option<WorkoutPosition> {
+    let runner = state.workout_runner.lock().unwrap().take()?;
+    runner.abort();
+    let position = state.workout_position.lock().unwrap().take()?;
+    if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+        log.marker("workout-interrupted");
+    }
+    *state.interrupted_workout.lock().unwrap() = Some(position.clone());
+    Some(position)
+}
+
+// After a reconnect, carries on with an interrupted workout when the settings allow it without
+// asking, otherwise tells the frontend it can be resumed.
+async fn offer_workout_resume(app: &AppHandle) {
+    let state = app.state::<AppState>();
+    let Some(position) = state.interrupted_workout.lock().unwrap().clone() else {
+        return;
+    };
+    let auto_resume = {
+        let settings = state.settings.lock().unwrap();
+        settings.auto_resume_workout && settings.arm_window().is_none()
+    };
+    if auto_resume {
+        match resume_interrupted_workout(app, &state).await {
+            Ok(()) => return,
+            Err(e) => eprintln!("Error resuming workout automatically: {:?}", e),
+        }
+    }
+    if let Err(e) = app.emit_all("workout-resumable", position) {
+        eprintln!("Error emitting workout resumable: {:?}", e);
+    }
+}
+
+async fn resume_interrupted_workout(app: &AppHandle, state: &AppState) -> Result<(), CommandError> {
+    let position = match reclaim_interrupted_workout(state).await {
+        Ok(position) => position,
+        Err(CommandError::ControlNotPermitted) => {
+            control_refused(app);
+            return Err(CommandError::ControlNotPermitted);
         }
-        _ => {
-            0
+        Err(e) => return Err(e),
+    };
+
+    println!("Resuming workout {} at step {}.", position.workout.name, position.step_index);
+    if let Err(e) = app.emit_all("workout-resumed", position.clone()) {
+        eprintln!("Error emitting workout resumed: {:?}", e);
+    }
+    let mut current = state.workout_runner.lock().unwrap();
+    if let Some(previous) = current.take() {
+        previous.abort();
+    }
+    let workout = position.workout.clone();
+    *current = Some(tauri::async_runtime::spawn(runner::run_workout(app.clone(), workout, Some(position))));
+    Ok(())
+}
+
+// Time and distance left in the running workout, or in an interrupted one.
+#[tauri::command]
+fn workout_remaining(state: State<'_, AppState>) -> Result<runner::WorkoutRemaining, CommandError> {
+    let running = state.workout_position.lock().unwrap().as_ref().map(runner::workout_remaining);
+    let remaining = running.or_else(|| state.interrupted_workout.lock().unwrap().as_ref().map(runner::workout_remaining));
+    remaining.ok_or(CommandError::WorkoutNotRunning)
+}
+
+// Takes the interrupted workout to resume once the interlock allows moving and control is back.
+// Reconnecting hands control back to the console and the runner's first write needs it.
+async fn reclaim_interrupted_workout(state: &AppState) -> Result<WorkoutPosition, CommandError> {
+    if !state.interlock.lock().unwrap().ready() {
+        return Err(CommandError::NotArmed);
+    }
+    if state.interrupted_workout.lock().unwrap().is_none() {
+        return Err(CommandError::WorkoutNotRunning);
+    }
+    state.connection()?.request_control().await?;
+    state.interrupted_workout.lock().unwrap().take().ok_or(CommandError::WorkoutNotRunning)
+}
+
+// Picks an interrupted workout back up at the step it was in, re-applying that step's targets.
+#[tauri::command]
+async fn resume_workout(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    resume_interrupted_workout(&app, &state).await
+}
+
+// Ends the running workout early, or drops an interrupted one, stopping the belt and saving the
+// session log. Returns the session's stats up to now.
+#[tauri::command]
+async fn stop_workout(app: AppHandle, state: State<'_, AppState>) -> Result<SessionStats, CommandError> {
+    let stats = end_workout_early(&state)?;
+    persist_state(&app, |snapshot| {
+        snapshot.workout = None;
+        snapshot.step_index = None;
+    });
+    println!("Workout stopped early: {:?}", stats);
+    if let Err(e) = app.emit_all("workout-ended", stats.clone()) {
+        eprintln!("Error emitting workout ended: {:?}", e);
+    }
+
+    state.connection()?.send_commands(vec![TreadmillCommands::StopOrPause]).await?;
+    Ok(stats)
+}
+
+// Cancels the runner and saves the session log, leaving the belt to `stop_workout`.
+fn end_workout_early(state: &AppState) -> Result<SessionStats, CommandError> {
+    let runner = state.workout_runner.lock().unwrap().take();
+    let interrupted = state.interrupted_workout.lock().unwrap().take();
+    if runner.is_none() && interrupted.is_none() {
+        return Err(CommandError::WorkoutNotRunning);
+    }
+    if let Some(runner) = runner {
+        runner.abort();
+    }
+    *state.workout_position.lock().unwrap() = None;
+    state.interlock.lock().unwrap().reset();
+
+    // Saved before stopping the belt, so the session is on disk even if the stop command fails
+    let stats = state.session_stats.lock().unwrap().clone();
+    if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+        log.marker("workout-stopped");
+    }
+    if let Err(e) = close_session_log(state) {
+        eprintln!("Error saving session log: {:?}", e);
+    }
+    Ok(stats)
+}
+
+// Seconds per unit of distance to km/h at 0.01 precision
+fn pace_to_speed(value: &str, km_per_unit: f64) -> Result<u16, String> {
+    let seconds_per_unit = parse_duration(value)? as f64;
+    if seconds_per_unit == 0.0 {
+        return Ok(0);
+    }
+    let km_per_hour = 1. / seconds_per_unit * (60.0 * 60.0) * km_per_unit;
+    Ok((km_per_hour * 100.).round() as u16)
+}
+
+// Speed in units per hour to km/h at 0.01 precision
+fn speed_to_speed(value: &str, km_per_unit: f64) -> Result<u16, String> {
+    let units_per_hour = value.trim().parse::<f64>().map_err(|_| format!("Invalid speed '{}'", value))?;
+    if !units_per_hour.is_finite() || units_per_hour < 0.0 {
+        return Err(format!("Invalid speed '{}'", value));
+    }
+    Ok((units_per_hour * km_per_unit * 100.).round() as u16)
+}
+
+// Returns the treadmill speed in km/h at 0.01 precision. Paces are inverted, speeds are only scaled.
+fn parse_pace(pace: &PaceRaw) -> Result<u16, String> {
+    match pace {
+        PaceRaw::MinPerMi(value) => pace_to_speed(value, KM_PER_MILE),
+        PaceRaw::MinPerKm(value) => pace_to_speed(value, 1.0),
+        PaceRaw::Mph(value) => speed_to_speed(value, KM_PER_MILE),
+        PaceRaw::Kph(value) => speed_to_speed(value, 1.0),
+    }
+}
+
+// Restates a pace or speed in another unit, "mph", "kph", "min/mi" or "min/km", e.g. "5:00" min/km
+// comes out as "8:03" min/mi. Goes through the treadmill's own 0.01 km/h precision, so it matches
+// what the belt would actually run.
+#[tauri::command]
+fn convert_pace(input: PaceRaw, to_unit: String) -> Result<String, CommandError> {
+    let speed = parse_pace(&input).map_err(CommandError::WorkoutParse)?;
+    match to_unit.as_str() {
+        "kph" => Ok(format!("{:.1}", units::kmh(speed))),
+        "mph" => Ok(format!("{:.1}", units::mph(speed))),
+        "min/km" | "min/mi" if speed == 0 => {
+            Err(CommandError::WorkoutParse("A stopped belt has no pace".to_string()))
         }
+        "min/km" => Ok(units::pace(speed, 1.0)),
+        "min/mi" => Ok(units::pace(speed, KM_PER_MILE)),
+        _ => Err(CommandError::WorkoutParse(format!("Unknown pace unit '{}'", to_unit))),
     }
 }
 
-fn parse_duration(pace: &str) -> u16 {
-    let parts = pace.split(":").collect::<Vec<_>>();
-    let minutes = parts.get(0).unwrap_or(&"0").parse::<u16>().unwrap();
-    let seconds = parts.get(1).unwrap_or(&"0").parse::<u16>().unwrap();
-    minutes * 60 + seconds
+// "m:ss" or plain "m" to seconds
+fn parse_duration(duration: &str) -> Result<u16, String> {
+    let invalid = || format!("Invalid duration '{}'", duration);
+    let (minutes, seconds) = duration.trim().split_once(':').unwrap_or((duration.trim(), "0"));
+    let minutes = minutes.parse::<u16>().map_err(|_| invalid())?;
+    let seconds = seconds.parse::<u16>().map_err(|_| invalid())?;
+    if seconds >= 60 {
+        return Err(invalid());
+    }
+    minutes.checked_mul(60).and_then(|m| m.checked_add(seconds)).ok_or_else(invalid)
+}
+
+// Pace is in 0.01 km/h, so meters per second is pace / 100 * 1000 / 3600 = pace / 360.
+// Rounded rather than truncated so repeated steps don't drift short, and computed in f64 so
+// long steps can't overflow before the conversion.
+fn step_distance(pace: u16, duration: u16) -> u32 {
+    (pace as f64 * duration as f64 / 360.0).round() as u32
+}
+
+fn parse_workout_steps(steps: &[WorkoutStepRaw]) -> Result<Vec<WorkoutStep>, String> {
+    let mut result = Vec::new();
+    for step in steps {
+        result.extend(parse_workout_step(step)?);
+    }
+    Ok(result)
 }
 
-fn parse_workout_step(step: &WorkoutStepRaw) -> Vec<WorkoutStep> {
+fn parse_workout_step(step: &WorkoutStepRaw) -> Result<Vec<WorkoutStep>, String> {
     match step {
         WorkoutStepRaw::Repeat { times, steps } => {
-            let steps = steps.iter().flat_map(|s| parse_workout_step(s)).collect::<Vec<_>>();
+            if steps.is_empty() {
+                return Err("Repeat has no steps".to_string());
+            }
+            let steps = parse_workout_steps(steps)?;
             let mut result = Vec::new();
             for _ in 0..*times {
                 result.extend(steps.clone());
             }
-            result
+            Ok(result)
         },
         WorkoutStepRaw::Run { name, duration, pace, angle } => {
-            let pace = parse_pace(pace);
-            let duration = parse_duration(duration);
-            let distance = (pace as f32 * duration as f32 / 1000.0) as u16;
-            vec![WorkoutStep {
+            let pace = parse_pace(pace).map_err(|e| format!("{}: {}", name, e))?;
+            let duration = parse_duration(duration).map_err(|e| format!("{}: {}", name, e))?;
+            let distance = step_distance(pace, duration);
+            Ok(vec![WorkoutStep {
                 name: name.clone(),
                 duration,
                 distance,
                 pace,
                 angle: *angle,
-            }]
+            }])
         }
     }
 }
 
-fn parse_workout(workout: &WorkoutRaw) -> Workout {
-    let steps = workout.steps.iter().flat_map(|s| parse_workout_step(s)).collect::<Vec<_>>();
+fn parse_workout(workout: &WorkoutRaw) -> Result<Workout, CommandError> {
+    let steps = parse_workout_steps(&workout.steps).map_err(CommandError::WorkoutParse)?;
     let mut distance = 0;
-    let mut duration = 0;
+    let mut duration: u16 = 0;
     for step in &steps {
         distance += step.distance;
-        duration += step.duration;
+        duration = duration.checked_add(step.duration).ok_or_else(|| {
+            CommandError::WorkoutParse(format!("{} is longer than {} seconds", workout.name, u16::MAX))
+        })?;
     }
 
-    Workout {
+    Ok(Workout {
         duration,
         distance,
         steps,
         name: workout.name.clone(),
         description: workout.description.clone(),
+    })
+}
+
+fn extra_step(name: &str, step: ExtraStep) -> WorkoutStep {
+    WorkoutStep {
+        name: name.to_string(),
+        duration: step.duration,
+        distance: step_distance(step.pace, step.duration),
+        pace: step.pace,
+        angle: 0,
+    }
+}
+
+// Parses the workout and, unless `include_warm_up` is `Some(false)`, wraps it in the warm-up and
+// cool-down from the settings. Only the parsed copy changes, the workout file is left alone.
+fn prepare_workout(
+    state: &AppState,
+    workout: &WorkoutRaw,
+    include_warm_up: Option<bool>,
+) -> Result<Workout, CommandError> {
+    let mut workout = parse_workout(workout)?;
+    if include_warm_up == Some(false) {
+        return Ok(workout);
+    }
+
+    let settings = state.settings.lock().unwrap().clone();
+    if let Some(warm_up) = settings.warm_up {
+        workout.steps.insert(0, extra_step("Warm up", warm_up));
+    }
+    if let Some(cool_down) = settings.cool_down {
+        workout.steps.push(extra_step("Cool down", cool_down));
     }
+    workout.distance = workout.steps.iter().map(|step| step.distance).sum();
+    workout.duration = workout
+        .steps
+        .iter()
+        .try_fold(0u16, |total, step| total.checked_add(step.duration))
+        .ok_or_else(|| CommandError::WorkoutParse(format!("{} is longer than {} seconds", workout.name, u16::MAX)))?;
+    Ok(workout)
+}
+
+// The steps that will actually run, with repeats expanded, for previewing in the editor.
+#[tauri::command]
+fn expand_workout(
+    state: State<'_, AppState>,
+    workout: WorkoutRaw,
+    include_warm_up: Option<bool>,
+) -> Result<Vec<WorkoutStep>, CommandError> {
+    Ok(prepare_workout(&state, &workout, include_warm_up)?.steps)
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct WorkoutFileError {
+    filename: String,
+    error: String,
+}
+
+// A workout with the file it came from. The file name is the id, names can be shared.
+#[derive(Debug, Serialize)]
+struct WorkoutFile {
+    id: String,
+    #[serde(flatten)]
+    workout: Workout,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct DuplicateWorkoutName {
+    name: String,
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct WorkoutsRead {
+    workouts: Vec<WorkoutFile>,
+    // Files that couldn't be read or parsed, so they can be fixed all at once
+    errors: Vec<WorkoutFileError>,
+    // Names used by more than one file, which would look the same in a list
+    duplicate_names: Vec<DuplicateWorkoutName>,
 }
 
+// Every workout in the directory. A file that doesn't read or parse is reported in `errors`
+// instead of hiding the rest.
 #[tauri::command]
-fn read_workouts() -> Result<Vec<String>, String> {
-    let paths = match fs::read_dir("/Users/kyle/Projects/run/workouts") {
+fn read_workouts() -> Result<WorkoutsRead, CommandError> {
+    read_workouts_in(std::path::Path::new(WORKOUTS_DIR))
+}
+
+fn read_workouts_in(dir: &std::path::Path) -> Result<WorkoutsRead, CommandError> {
+    let paths = match fs::read_dir(dir) {
         Ok(p) => p,
         Err(e) => {
             eprintln!("Error reading workouts directory: {:?}", e);
-            return Err("Error reading workouts directory.".to_string());
+            return Err(CommandError::Io(e.to_string()));
         }
     };
 
-    let mut workouts = Vec::new();
+    let mut read = WorkoutsRead { workouts: Vec::new(), errors: Vec::new(), duplicate_names: Vec::new() };
     for path in paths {
-        let path = path.unwrap();
-        workouts.push(path.file_name().into_string().unwrap());
-
-        let file = fs::read_to_string(path.path());
-        let content = match file {
-            Ok(f) => f,
-            Err(e) => {
-                eprintln!("Error reading file: {:?}", e);
-                return Err("Error reading file.".to_string());
+        let path = path.map_err(|e| CommandError::Io(e.to_string()))?;
+        let filename = path.file_name().to_string_lossy().into_owned();
+        match read_workout_file(&path.path()).and_then(|raw| parse_workout(&raw)) {
+            Ok(workout) => {
+                println!("Parsed Workout {:?}", workout);
+                read.workouts.push(WorkoutFile { id: filename, workout });
             }
-        };
-        let workout: WorkoutRaw = match serde_json::from_str(&content) {
-            Ok(w) => w,
             Err(e) => {
-                eprintln!("Error parsing JSON: {:?}", e);
-                return Err("Error parsing JSON.".to_string());
+                eprintln!("Error loading workout {}: {}", filename, e);
+                read.errors.push(WorkoutFileError { filename, error: e.to_string() });
             }
-        };
-        println!("Raw Workout {:?}", workout);
-        let parsed_workout = parse_workout(&workout);
-        println!("Parsed Workout {:?}", parsed_workout);
+        }
     }
 
-    Ok(workouts)
-}
-
-// Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
-#[tauri::command]
-async fn connect_to_treadmill(name: String) -> Result<String, String> {
-    let manager = Manager::new().await.unwrap();
-
-    let central = manager
-        .adapters()
-        .await
-        .expect("Unable to fetch adapter list.")
+    let mut ids_by_name: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for file in &read.workouts {
+        ids_by_name.entry(&file.workout.name).or_default().push(file.id.clone());
+    }
+    let duplicate_names: Vec<_> = ids_by_name
         .into_iter()
-        .nth(0)
-        .expect("Unable to find adapters.");
-
-    match central.start_scan(ScanFilter::default()).await {
-        Ok(_) => println!("Scanning for devices..."),
-        Err(e) => eprintln!("Error scanning: {:?}", e),
+        .filter(|(_, ids)| ids.len() > 1)
+        .map(|(name, mut ids)| {
+            ids.sort();
+            DuplicateWorkoutName { name: name.to_string(), ids }
+        })
+        .collect();
+    for duplicate in &duplicate_names {
+        eprintln!("Workout name {:?} is used by {:?}.", duplicate.name, duplicate.ids);
     }
+    read.duplicate_names = duplicate_names;
 
-    time::sleep(Duration::from_secs(2)).await;
+    Ok(read)
+}
 
-    let treadmill = match find_treadmill(&central).await {
-        Some(p) => p,
-        None => {
-            eprintln!("Treadmill not found.");
-            return Ok("Treadmill not found.".to_string());
+fn read_workout_file(path: &std::path::Path) -> Result<WorkoutRaw, CommandError> {
+    let content = match fs::read_to_string(path) {
+        Ok(f) => f,
+        Err(e) => {
+            eprintln!("Error reading file: {:?}", e);
+            return Err(CommandError::Io(e.to_string()));
         }
     };
-
-    match treadmill.connect().await {
-        Ok(_) => println!("Connected to treadmill."),
+    match serde_json::from_str(&content) {
+        Ok(w) => Ok(w),
         Err(e) => {
-            eprintln!("Error connecting to treadmill: {:?}", e);
-            return Ok("Error connecting to treadmill.".to_string());
+            eprintln!("Error parsing JSON: {:?}", e);
+            Err(CommandError::WorkoutParse(e.to_string()))
         }
     }
+}
 
-    treadmill.discover_services().await.unwrap();
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+enum WorkoutSort {
+    #[default]
+    Name,
+    Duration,
+    Distance,
+    Modified,
+    LastRun,
+}
 
-    let characteristics = treadmill.characteristics();
-    let char = characteristics.iter().find(|c| c.uuid == TREADMILL_DATA_CHARACTERISTIC_UUID).unwrap();
-    treadmill.subscribe(char).await.unwrap();
+#[derive(Debug, Serialize)]
+struct WorkoutSummary {
+    // File name, what `get_workout` takes
+    id: String,
+    name: String,
+    description: String,
+    // Seconds
+    duration: u16,
+    // Meters
+    distance: u32,
+    step_count: usize,
+    // Milliseconds since the unix epoch
+    modified: Option<u64>,
+    last_run: Option<u64>,
+}
 
-    let mut sub = treadmill.notifications().await.unwrap();
-    tokio::spawn(async move {
-        while let Some(notification) = sub.next().await {
-            match decode_treadmill_data(&notification.value) {
-                Ok(data) => {
-                    println!("Data: {:?}", data);
-                },
-                Err(_) => {
-                    println!("Error decoding data.");
+// Every workout with its totals, sorted by name unless asked otherwise. Times sort newest first
+// with never run last. Files that don't parse are left out rather than failing the whole list.
+#[tauri::command]
+fn list_workouts(app: AppHandle, sort: Option<WorkoutSort>) -> Result<Vec<WorkoutSummary>, CommandError> {
+    summarize_workouts(std::path::Path::new(WORKOUTS_DIR), &last_run::load(&app), sort.unwrap_or_default())
+}
+
+fn summarize_workouts(
+    dir: &std::path::Path,
+    last_runs: &BTreeMap<String, u64>,
+    sort: WorkoutSort,
+) -> Result<Vec<WorkoutSummary>, CommandError> {
+    let paths = match fs::read_dir(dir) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("Error reading workouts directory: {:?}", e);
+            return Err(CommandError::Io(e.to_string()));
+        }
+    };
+
+    let mut workouts = Vec::new();
+    for entry in paths {
+        let entry = entry.map_err(|e| CommandError::Io(e.to_string()))?;
+        let workout = match read_workout_file(&entry.path()).and_then(|raw| parse_workout(&raw)) {
+            Ok(workout) => workout,
+            Err(e) => {
+                eprintln!("Skipping workout {:?}: {:?}", entry.file_name(), e);
+                continue;
+            }
+        };
+        let modified = entry.metadata().and_then(|m| m.modified()).ok();
+        workouts.push(WorkoutSummary {
+            id: entry.file_name().to_string_lossy().into_owned(),
+            duration: workout.duration,
+            distance: workout.distance,
+            step_count: workout.steps.len(),
+            modified: modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_millis() as u64),
+            last_run: last_runs.get(&workout.name).copied(),
+            name: workout.name,
+            description: workout.description,
+        });
+    }
+
+    match sort {
+        WorkoutSort::Name => workouts.sort_by_key(|w| w.name.to_lowercase()),
+        WorkoutSort::Duration => workouts.sort_by_key(|w| w.duration),
+        WorkoutSort::Distance => workouts.sort_by_key(|w| w.distance),
+        WorkoutSort::Modified => workouts.sort_by_key(|w| std::cmp::Reverse(w.modified)),
+        WorkoutSort::LastRun => workouts.sort_by_key(|w| std::cmp::Reverse(w.last_run)),
+    }
+    Ok(workouts)
+}
+
+// One workout by its file name, as listed by `list_workouts`, without reading the rest.
+#[tauri::command]
+fn get_workout(id: String) -> Result<Workout, CommandError> {
+    get_workout_in(std::path::Path::new(WORKOUTS_DIR), id)
+}
+
+fn get_workout_in(dir: &std::path::Path, id: String) -> Result<Workout, CommandError> {
+    if !valid_workout_id(&id) {
+        return Err(CommandError::WorkoutNotFound(id));
+    }
+    let path = dir.join(&id);
+    if !path.is_file() {
+        return Err(CommandError::WorkoutNotFound(id));
+    }
+    parse_workout(&read_workout_file(&path)?)
+}
+
+// Ids are plain file names, anything else could point outside the workouts directory
+fn valid_workout_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(['/', '\\']) && !id.starts_with('.')
+}
+
+const EXPORTS_DIR: &str = "exports";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundledWorkout {
+    // File name in the workouts directory it came from
+    id: String,
+    workout: WorkoutRaw,
+}
+
+// Every workout file in one JSON document, for sharing a workout library.
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkoutBundle {
+    // Milliseconds since the unix epoch
+    exported_at: u64,
+    workouts: Vec<BundledWorkout>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+struct WorkoutImport {
+    // Ids the workouts were saved under, renamed when the original was taken
+    imported: Vec<String>,
+    // Already in the workouts directory with the same content
+    skipped: Vec<String>,
+    errors: Vec<WorkoutFileError>,
+}
+
+// Writes every workout that parses to a bundle in the app data dir and returns its path. Files
+// that don't parse are left out, `read_workouts` reports them.
+#[tauri::command]
+fn export_workouts(app: AppHandle) -> Result<String, CommandError> {
+    let dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| CommandError::Io("No app data directory.".to_string()))?
+        .join(EXPORTS_DIR);
+    let path = export_workouts_to(std::path::Path::new(WORKOUTS_DIR), &dir)?;
+    Ok(path.display().to_string())
+}
+
+fn export_workouts_to(workouts_dir: &std::path::Path, dir: &std::path::Path) -> Result<std::path::PathBuf, CommandError> {
+    let paths = fs::read_dir(workouts_dir).map_err(|e| CommandError::Io(e.to_string()))?;
+    let mut bundle = WorkoutBundle { exported_at: timestamp_millis(), workouts: Vec::new() };
+    for entry in paths {
+        let entry = entry.map_err(|e| CommandError::Io(e.to_string()))?;
+        let id = entry.file_name().to_string_lossy().into_owned();
+        match read_workout_file(&entry.path()).and_then(|raw| parse_workout(&raw).map(|_| raw)) {
+            Ok(workout) => bundle.workouts.push(BundledWorkout { id, workout }),
+            Err(e) => eprintln!("Leaving workout {} out of the export: {}", id, e),
+        }
+    }
+    bundle.workouts.sort_by(|a, b| a.id.cmp(&b.id));
+
+    fs::create_dir_all(dir).map_err(|e| CommandError::Io(e.to_string()))?;
+    let path = dir.join(format!("workouts-{}.json", bundle.exported_at));
+    let content = serde_json::to_string_pretty(&bundle).map_err(|e| CommandError::Io(e.to_string()))?;
+    fs::write(&path, content).map_err(|e| CommandError::Io(format!("{}: {}", path.display(), e)))?;
+
+    println!("Exported {} workouts to {:?}.", bundle.workouts.len(), path);
+    Ok(path)
+}
+
+// Adds the workouts in a bundle from `export_workouts` to the workouts directory. Each is checked
+// the same way a workout file is before it's written, and one that's already there is skipped. A
+// different workout under a taken id is saved as "<id>-2.json" and so on.
+#[tauri::command]
+fn import_workouts(path: String) -> Result<WorkoutImport, CommandError> {
+    import_workouts_into(std::path::Path::new(WORKOUTS_DIR), &path)
+}
+
+fn import_workouts_into(dir: &std::path::Path, path: &str) -> Result<WorkoutImport, CommandError> {
+    let content = fs::read_to_string(path).map_err(|e| CommandError::Io(format!("{}: {}", path, e)))?;
+    let bundle: WorkoutBundle = serde_json::from_str(&content).map_err(|e| CommandError::WorkoutParse(e.to_string()))?;
+
+    let mut import = WorkoutImport::default();
+    for BundledWorkout { id, workout } in bundle.workouts {
+        let checked = if valid_workout_id(&id) {
+            parse_workout(&workout).map(|_| ())
+        } else {
+            Err(CommandError::WorkoutParse(format!("Invalid workout id '{}'", id)))
+        };
+        let result = checked.and_then(|()| {
+            let content = serde_json::to_string_pretty(&workout).map_err(|e| CommandError::WorkoutParse(e.to_string()))?;
+            import_workout_file(dir, &id, &content)
+        });
+        match result {
+            Ok(Some(saved)) => import.imported.push(saved),
+            Ok(None) => import.skipped.push(id),
+            Err(e) => {
+                eprintln!("Error importing workout {}: {}", id, e);
+                import.errors.push(WorkoutFileError { filename: id, error: e.to_string() });
+            }
+        }
+    }
+
+    println!("Imported workouts from {}: {:?}", path, import);
+    Ok(import)
+}
+
+// The id `content` was saved under, or `None` when an identical workout already has `id`.
+fn import_workout_file(dir: &std::path::Path, id: &str, content: &str) -> Result<Option<String>, CommandError> {
+    let (stem, extension) = id.rsplit_once('.').unwrap_or((id, "json"));
+    let mut n = 1;
+    loop {
+        let candidate = if n == 1 { id.to_string() } else { format!("{}-{}.{}", stem, n, extension) };
+        let path = dir.join(&candidate);
+        if let Ok(existing) = fs::read_to_string(&path) {
+            let same = serde_json::from_str::<serde_json::Value>(&existing).ok()
+                == serde_json::from_str::<serde_json::Value>(content).ok();
+            if same {
+                return Ok(None);
+            }
+            n += 1;
+            continue;
+        }
+        let mut file = match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                n += 1;
+                continue;
+            }
+            Err(e) => return Err(CommandError::Io(format!("{}: {}", path.display(), e))),
+        };
+        file.write_all(content.as_bytes()).map_err(|e| CommandError::Io(e.to_string()))?;
+        return Ok(Some(candidate));
+    }
+}
+
+const WORKOUT_BACKUPS_DIR: &str = "workout_backups";
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum WorkoutIssueKind {
+    // Couldn't be read at all
+    Unreadable,
+    // Not JSON, or JSON that no migration turns into a workout
+    Malformed,
+    // Deserializes but doesn't make a runnable workout, e.g. a bad pace or a workout that's too long
+    Invalid,
+    // Written for an older version of the format, `repair` updates it
+    Outdated,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct WorkoutIssue {
+    filename: String,
+    kind: WorkoutIssueKind,
+    message: String,
+    // Whether `repair` migrated the file
+    repaired: bool,
+}
+
+// Checks every file in the workouts directory against the current format. With `repair`, outdated
+// files are migrated in place after copying the original to the app data dir. Files that check
+// out aren't listed.
+#[tauri::command]
+fn check_workouts(app: AppHandle, repair: Option<bool>) -> Result<Vec<WorkoutIssue>, CommandError> {
+    let paths = fs::read_dir(WORKOUTS_DIR).map_err(|e| CommandError::Io(e.to_string()))?;
+    let mut issues = Vec::new();
+    for entry in paths {
+        let entry = entry.map_err(|e| CommandError::Io(e.to_string()))?;
+        let filename = entry.file_name().to_string_lossy().into_owned();
+        if let Some((kind, message, migrated)) = check_workout_file(&entry.path(), &filename) {
+            let mut issue = WorkoutIssue { filename, kind, message, repaired: false };
+            if let (true, Some(migrated)) = (repair.unwrap_or(false), migrated) {
+                match repair_workout_file(&app, &entry.path(), &migrated) {
+                    Ok(()) => issue.repaired = true,
+                    Err(e) => issue.message = format!("{}, repair failed: {}", issue.message, e),
+                }
+            }
+            issues.push(issue);
+        }
+    }
+    issues.sort_by(|a, b| a.filename.cmp(&b.filename));
+    println!("Workout check found {} issues: {:?}", issues.len(), issues);
+    Ok(issues)
+}
+
+// `None` when the file is fine, otherwise what's wrong with it and, for outdated files, the
+// migrated content.
+fn check_workout_file(
+    path: &std::path::Path,
+    filename: &str,
+) -> Option<(WorkoutIssueKind, String, Option<serde_json::Value>)> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => return Some((WorkoutIssueKind::Unreadable, e.to_string(), None)),
+    };
+    let mut value: serde_json::Value = match serde_json::from_str(&content) {
+        Ok(value) => value,
+        Err(e) => return Some((WorkoutIssueKind::Malformed, e.to_string(), None)),
+    };
+    let error = match serde_json::from_value::<WorkoutRaw>(value.clone()) {
+        Ok(raw) => return parse_workout(&raw).err().map(|e| (WorkoutIssueKind::Invalid, e.to_string(), None)),
+        Err(e) => e,
+    };
+
+    let stem = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    let changes = workout_migration::migrate(&mut value, stem);
+    let migrated = serde_json::from_value::<WorkoutRaw>(value.clone())
+        .map_err(|e| CommandError::WorkoutParse(e.to_string()))
+        .and_then(|raw| parse_workout(&raw));
+    match migrated {
+        Ok(_) if !changes.is_empty() => Some((WorkoutIssueKind::Outdated, changes.join(", "), Some(value))),
+        _ => Some((WorkoutIssueKind::Malformed, error.to_string(), None)),
+    }
+}
+
+fn repair_workout_file(app: &AppHandle, path: &std::path::Path, migrated: &serde_json::Value) -> Result<(), CommandError> {
+    let dir = app
+        .path_resolver()
+        .app_data_dir()
+        .ok_or_else(|| CommandError::Io("No app data directory.".to_string()))?
+        .join(WORKOUT_BACKUPS_DIR);
+    migrate_workout_file(&dir, path, migrated)
+}
+
+// Copies the file at `path` into `backups` before overwriting it with `migrated`.
+fn migrate_workout_file(
+    backups: &std::path::Path,
+    path: &std::path::Path,
+    migrated: &serde_json::Value,
+) -> Result<(), CommandError> {
+    let Some(filename) = path.file_name() else {
+        return Err(CommandError::Io(format!("{} has no file name", path.display())));
+    };
+    fs::create_dir_all(backups).map_err(|e| CommandError::Io(e.to_string()))?;
+    let backup = backups.join(format!("{}.{}", filename.to_string_lossy(), timestamp_millis()));
+    fs::copy(path, &backup).map_err(|e| CommandError::Io(format!("{}: {}", backup.display(), e)))?;
+
+    let content = serde_json::to_string_pretty(migrated).map_err(|e| CommandError::WorkoutParse(e.to_string()))?;
+    fs::write(path, content).map_err(|e| CommandError::Io(format!("{}: {}", path.display(), e)))?;
+    println!("Migrated {:?}, the original is at {:?}.", path, backup);
+    Ok(())
+}
+
+// JSON Schema for workout files, generated from `WorkoutRaw` so it can't drift from what
+// `read_workouts` accepts. Includes an example workout for the editor to show.
+#[tauri::command]
+fn workout_json_schema() -> Result<String, CommandError> {
+    let mut example = workout_text::parse_workout_text("10:00 @ 6:00/km, 3x(400m @ 5:00/km ^2, 200m @ 7:00/km)")
+        .map_err(CommandError::WorkoutParse)?;
+    example.name = "Intervals".to_string();
+    example.description = "Warm up, then three rounds of 400m hard and 200m easy.".to_string();
+
+    let to_json_error = |e: serde_json::Error| CommandError::WorkoutParse(e.to_string());
+    let mut schema = serde_json::to_value(schemars::schema_for!(WorkoutRaw)).map_err(to_json_error)?;
+    schema["examples"] = serde_json::Value::Array(vec![serde_json::to_value(example).map_err(to_json_error)?]);
+    serde_json::to_string_pretty(&schema).map_err(to_json_error)
+}
+
+// Saves whatever the treadmill is set to now as a one step workout. Falls back to the reported
+// speed and incline when no targets have been set through the app.
+#[tauri::command]
+fn save_current_as_workout(state: State<'_, AppState>, name: String) -> Result<String, CommandError> {
+    let targets = *state.targets.lock().unwrap();
+    let latest = state.latest_data.lock().unwrap().clone();
+    let speed = targets.speed.or(latest.as_ref().map(|data| data.speed)).ok_or(CommandError::NotConnected)?;
+    let incline = targets.incline.or(latest.as_ref().and_then(|data| data.inclination)).unwrap_or(0);
+
+    let workout = WorkoutRaw {
+        name: name.clone(),
+        description: String::new(),
+        steps: vec![WorkoutStepRaw::Run {
+            name: name.clone(),
+            duration: QUICK_WORKOUT_DURATION.to_string(),
+            pace: PaceRaw::Kph(format!("{:.1}", units::kmh(speed))),
+            angle: incline,
+        }],
+    };
+
+    let file_name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    if file_name.trim_matches('-').is_empty() {
+        return Err(CommandError::WorkoutParse(format!("Can't name a workout file after '{}'", name)));
+    }
+    let path = std::path::Path::new(WORKOUTS_DIR).join(format!("{}.json", file_name));
+    let content = serde_json::to_string_pretty(&workout).map_err(|e| CommandError::WorkoutParse(e.to_string()))?;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| CommandError::Io(format!("{}: {}", path.display(), e)))?;
+    file.write_all(content.as_bytes()).map_err(|e| CommandError::Io(e.to_string()))?;
+
+    println!("Saved current targets as {:?}.", path);
+    Ok(path.display().to_string())
+}
+
+#[tauri::command]
+fn import_workout_from_text(text: String) -> Result<WorkoutRaw, CommandError> {
+    workout_text::parse_workout_text(&text).map_err(CommandError::WorkoutParse)
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct SignalStrength {
+    rssi: Option<i16>,
+    // Milliseconds since the unix epoch
+    timestamp: u64,
+}
+
+fn timestamp_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// Polls the peripheral's RSSI so the frontend can chart connection quality. Stops once the
+// peripheral reports it is no longer connected.
+fn spawn_signal_monitor(app: AppHandle, treadmill: Peripheral, interval: Duration) -> JoinHandle<()> {
+    tauri::async_runtime::spawn(async move {
+        loop {
+            time::sleep(interval).await;
+
+            match treadmill.is_connected().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    println!("Treadmill disconnected, stopping signal monitor.");
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Error checking connection: {:?}", e);
+                    break;
+                }
+            }
+
+            let rssi = match treadmill.properties().await {
+                Ok(properties) => properties.and_then(|p| p.rssi),
+                Err(e) => {
+                    eprintln!("Error reading treadmill properties: {:?}", e);
+                    None
+                }
+            };
+
+            let payload = SignalStrength { rssi, timestamp: timestamp_millis() };
+            if let Err(e) = app.emit_all("signal-strength", payload) {
+                eprintln!("Error emitting signal strength: {:?}", e);
+            }
+        }
+    })
+}
+
+fn restart_signal_monitor(app: &AppHandle, state: &AppState, treadmill: Peripheral, interval: Duration) {
+    let monitor = spawn_signal_monitor(app.clone(), treadmill, interval);
+    if let Some(previous) = state.signal_monitor.lock().unwrap().replace(monitor) {
+        previous.abort();
+    }
+}
+
+#[tauri::command]
+fn start_signal_monitor(app: AppHandle, state: State<'_, AppState>, interval_ms: u64) -> Result<(), CommandError> {
+    let connection = state.connection()?;
+    let peripheral = connection.peripheral()?.clone();
+    restart_signal_monitor(&app, &state, peripheral, Duration::from_millis(interval_ms));
+    Ok(())
+}
+
+// Reads a characteristic by UUID, returning `None` when the treadmill doesn't expose it.
+async fn read_optional_characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Option<Vec<u8>>, CommandError> {
+    match peripheral.characteristics().into_iter().find(|c| c.uuid == uuid) {
+        Some(characteristic) => Ok(Some(peripheral.read(&characteristic).await?)),
+        None => Ok(None),
+    }
+}
+
+// Each field is `None` when the treadmill has no Device Information Service or leaves it out.
+#[derive(Debug, Serialize, Clone, Default)]
+struct DeviceInfo {
+    manufacturer: Option<String>,
+    model: Option<String>,
+    firmware_revision: Option<String>,
+}
+
+async fn read_string_characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Option<String>, CommandError> {
+    let value = read_optional_characteristic(peripheral, uuid).await?;
+    // Some firmwares pad the string with NULs
+    Ok(value.map(|v| String::from_utf8_lossy(&v).trim_end_matches('\0').trim().to_string()))
+}
+
+async fn read_device_info(peripheral: &Peripheral) -> Result<DeviceInfo, CommandError> {
+    Ok(DeviceInfo {
+        manufacturer: read_string_characteristic(peripheral, MANUFACTURER_NAME_CHARACTERISTIC_UUID).await?,
+        model: read_string_characteristic(peripheral, MODEL_NUMBER_CHARACTERISTIC_UUID).await?,
+        firmware_revision: read_string_characteristic(peripheral, FIRMWARE_REVISION_CHARACTERISTIC_UUID).await?,
+    })
+}
+
+// Full UUIDs, or 16 bit ones like "2A19" or "0x2A19".
+fn parse_uuid(uuid: &str) -> Result<Uuid, CommandError> {
+    let trimmed = uuid.trim();
+    let short = trimmed.strip_prefix("0x").or_else(|| trimmed.strip_prefix("0X")).unwrap_or(trimmed);
+    if short.len() == 4 {
+        if let Ok(short) = u16::from_str_radix(short, 16) {
+            return Ok(uuid_from_u16(short));
+        }
+    }
+    Uuid::parse_str(trimmed).map_err(|e| CommandError::OutOfRange(format!("UUID '{}': {}", uuid, e)))
+}
+
+fn find_characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Characteristic, CommandError> {
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid)
+        .ok_or_else(|| CommandError::NotSupported(format!("characteristic {}", uuid)))
+}
+
+// Reads any characteristic on the connected device, for working out vendor extensions.
+#[tauri::command]
+async fn read_characteristic(state: State<'_, AppState>, uuid: String) -> Result<Vec<u8>, CommandError> {
+    let connection = state.connection()?;
+    let peripheral = connection.peripheral()?;
+    let characteristic = find_characteristic(peripheral, parse_uuid(&uuid)?)?;
+    if !characteristic.properties.contains(CharPropFlags::READ) {
+        return Err(CommandError::NotSupported(format!("reading {}", characteristic.uuid)));
+    }
+    let value = peripheral.read(&characteristic).await?;
+    println!("Read {}: {:02x?}", characteristic.uuid, value);
+    Ok(value)
+}
+
+// Writes raw bytes to any characteristic, bypassing every check the rest of the app makes. Only
+// allowed with `allow_raw_writes` turned on in the settings.
+#[tauri::command]
+async fn write_characteristic(state: State<'_, AppState>, uuid: String, bytes: Vec<u8>) -> Result<(), CommandError> {
+    if !state.settings.lock().unwrap().allow_raw_writes {
+        return Err(CommandError::NotSupported("raw writes without allow_raw_writes".to_string()));
+    }
+    let connection = state.connection()?;
+    let peripheral = connection.peripheral()?;
+    let characteristic = find_characteristic(peripheral, parse_uuid(&uuid)?)?;
+    let write_type = if characteristic.properties.contains(CharPropFlags::WRITE) {
+        WriteType::WithResponse
+    } else if characteristic.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE) {
+        WriteType::WithoutResponse
+    } else {
+        return Err(CommandError::NotSupported(format!("writing {}", characteristic.uuid)));
+    };
+    println!("Writing {:02x?} to {}.", bytes, characteristic.uuid);
+    peripheral.write(&characteristic, &bytes, write_type).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_device_info(state: State<'_, AppState>) -> Result<DeviceInfo, CommandError> {
+    let connection = state.connection()?;
+    read_device_info(connection.peripheral()?).await
+}
+
+async fn read_machine_capabilities(peripheral: &Peripheral) -> Result<MachineCapabilities, CommandError> {
+    let feature = read_optional_characteristic(peripheral, FITNESS_MACHINE_FEATURE_CHARACTERISTIC_UUID).await?;
+    let speed_range = read_optional_characteristic(peripheral, SUPPORTED_SPEED_RANGE_CHARACTERISTIC_UUID).await?;
+    let inclination_range =
+        read_optional_characteristic(peripheral, SUPPORTED_INCLINATION_RANGE_CHARACTERISTIC_UUID).await?;
+    let power_range = read_optional_characteristic(peripheral, SUPPORTED_POWER_RANGE_CHARACTERISTIC_UUID).await?;
+
+    let (features, target_settings) = match feature.map(|f| capabilities::decode_fitness_machine_feature(&f)) {
+        Some(Ok((features, target_settings))) => (Some(features), Some(target_settings)),
+        Some(Err(e)) => {
+            eprintln!("Error decoding fitness machine feature: {:?}", e);
+            (None, None)
+        }
+        None => (None, None),
+    };
+
+    Ok(MachineCapabilities {
+        features,
+        control_opcodes: capabilities::control_opcodes(target_settings.as_ref()),
+        target_settings,
+        speed_range: speed_range.and_then(|r| capabilities::decode_supported_speed_range(&r).ok()),
+        inclination_range: inclination_range.and_then(|r| capabilities::decode_supported_inclination_range(&r).ok()),
+        power_range: power_range.and_then(|r| capabilities::decode_supported_power_range(&r).ok()),
+    })
+}
+
+// Capabilities only change with the connected machine, so they're read once and cached.
+async fn machine_capabilities(state: &AppState) -> Result<MachineCapabilities, CommandError> {
+    if let Some(capabilities) = state.capabilities.lock().unwrap().clone() {
+        return Ok(capabilities);
+    }
+
+    let capabilities = match state.connection()?.transport {
+        Transport::Ble { peripheral, .. } => read_machine_capabilities(&peripheral).await?,
+        Transport::Simulator(simulator) => simulator.capabilities(),
+        #[cfg(test)]
+        Transport::Mock(_) => MachineCapabilities {
+            features: None,
+            target_settings: None,
+            speed_range: None,
+            inclination_range: None,
+            power_range: None,
+            control_opcodes: Vec::new(),
+        },
+    };
+    *state.capabilities.lock().unwrap() = Some(capabilities.clone());
+    Ok(capabilities)
+}
+
+// One of each `TreadmillCommands` variant, the values are only there to encode them.
+const ALL_COMMANDS: [TreadmillCommands; 8] = [
+    TreadmillCommands::RequestControl,
+    TreadmillCommands::Reset,
+    TreadmillCommands::SetTargetSpeed(0),
+    TreadmillCommands::SetTargetInclination(0),
+    TreadmillCommands::StartOrResume,
+    TreadmillCommands::StopOrPause,
+    TreadmillCommands::SetTargetedDistance(0),
+    TreadmillCommands::SetTargetedTrainingTime(0),
+];
+
+// The `command` tag the frontend sends a command with, e.g. "set_target_speed".
+fn command_name(command: TreadmillCommands) -> String {
+    let tagged = serde_json::to_value(command).expect("commands always serialize");
+    tagged["command"].as_str().unwrap_or_default().to_string()
+}
+
+// Names of the commands sent with one of `opcodes`.
+fn commands_for_opcodes(opcodes: &[u8]) -> Vec<String> {
+    ALL_COMMANDS
+        .into_iter()
+        .filter(|command| opcodes.contains(&treadmill_command_to_message(*command)[0]))
+        .map(command_name)
+        .collect()
+}
+
+// Which `TreadmillCommands` the connected machine accepts, so the UI can disable the rest.
+#[tauri::command]
+async fn supported_commands(state: State<'_, AppState>) -> Result<Vec<String>, CommandError> {
+    let capabilities = machine_capabilities(&state).await?;
+    Ok(commands_for_opcodes(&capabilities.control_opcodes))
+}
+
+// Percent, `None` when the device doesn't expose the Battery Service.
+#[tauri::command]
+async fn get_battery_level(state: State<'_, AppState>) -> Result<Option<u8>, CommandError> {
+    let connection = state.connection()?;
+    let level = read_optional_characteristic(connection.peripheral()?, BATTERY_LEVEL_CHARACTERISTIC_UUID).await?;
+    Ok(level.and_then(|level| level.first().copied()))
+}
+
+#[tauri::command]
+async fn get_machine_capabilities(state: State<'_, AppState>) -> Result<MachineCapabilities, CommandError> {
+    machine_capabilities(&state).await
+}
+
+// The machine answers Control Not Permitted once it has taken control back, e.g. after a timeout
+// or a button press on the console.
+fn control_lost(e: &CommandError) -> bool {
+    matches!(e, CommandError::ControlNotPermitted)
+}
+
+// Answered with Control Not Permitted while another app or the console holds the machine, in which
+// case nothing we send will move the belt until it lets go.
+async fn acquire_control(app: &AppHandle) -> Result<(), CommandError> {
+    let result = app.state::<AppState>().connection()?.request_control().await;
+    if let Err(CommandError::ControlNotPermitted) = result {
+        control_refused(app);
+    }
+    result
+}
+
+fn control_refused(app: &AppHandle) {
+    eprintln!("Treadmill refused control, something else is controlling it.");
+    if let Err(e) = app.emit_all("control-not-permitted", ()) {
+        eprintln!("Error emitting control not permitted: {:?}", e);
+    }
+}
+
+// Asks for control again without reconnecting, for when the machine has revoked it.
+#[tauri::command]
+async fn request_control(app: AppHandle) -> Result<(), CommandError> {
+    acquire_control(&app).await?;
+    println!("Control granted.");
+    if let Err(e) = app.emit_all("control-granted", ()) {
+        eprintln!("Error emitting control granted: {:?}", e);
+    }
+    Ok(())
+}
+
+// Allows the belt to start for the configured arming window, see `interlock`.
+#[tauri::command]
+fn arm_treadmill(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    state.connection()?;
+    state.interlock.lock().unwrap().arm();
+    println!("Treadmill armed.");
+    if let Err(e) = app.emit_all("treadmill-armed", ()) {
+        eprintln!("Error emitting treadmill armed: {:?}", e);
+    }
+    Ok(())
+}
+
+// Starts the belt at whatever target the machine has, connecting never does this on its own.
+#[tauri::command]
+async fn start_treadmill(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    if !*state.control_granted.lock().unwrap() {
+        acquire_control(&app).await?;
+    }
+    state.connection()?.send_commands(vec![TreadmillCommands::StartOrResume]).await?;
+    println!("Treadmill started.");
+    Ok(())
+}
+
+// How long the belt runs during `self_test`
+const SELF_TEST_RUN_TIME: Duration = Duration::from_secs(1);
+// Used when the machine doesn't report its speed range, 1 km/h
+const SELF_TEST_FALLBACK_SPEED: u16 = 100;
+
+#[derive(Debug, Serialize, Clone)]
+struct SelfTestStep {
+    name: &'static str,
+    // `None` when the step passed
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct SelfTestResult {
+    passed: bool,
+    steps: Vec<SelfTestStep>,
+}
+
+// Checks a connected treadmill works without starting a workout: requests control, reads the
+// capabilities and, only with `run_belt` set, runs the belt at its lowest speed for a second. The
+// belt part goes through the interlock like any other start. Every step is reported rather than
+// stopping at the first failure, except that nothing moves without control.
+#[tauri::command]
+async fn self_test(app: AppHandle, state: State<'_, AppState>, run_belt: bool) -> Result<SelfTestResult, CommandError> {
+    let connection = state.connection()?;
+    let control = acquire_control(&app).await;
+    let result = run_self_test(&state, connection, control, run_belt).await;
+    println!("Self test: {:?}", result);
+    if let Err(e) = app.emit_all("self-test-complete", result.clone()) {
+        eprintln!("Error emitting self test result: {:?}", e);
+    }
+    Ok(result)
+}
+
+// The steps of `self_test` after asking for control, which `control` is the outcome of.
+async fn run_self_test(
+    state: &AppState,
+    connection: TreadmillConnection,
+    control: Result<(), CommandError>,
+    run_belt: bool,
+) -> SelfTestResult {
+    let mut steps = Vec::new();
+    let mut record = |name, result: Result<(), CommandError>| {
+        let ok = result.is_ok();
+        steps.push(SelfTestStep { name, error: result.err().map(|e| e.to_string()) });
+        ok
+    };
+
+    let controlled = record("request-control", control);
+    *state.capabilities.lock().unwrap() = None;
+    let capabilities = machine_capabilities(state).await;
+    let speed = capabilities.as_ref().ok().and_then(|c| c.speed_range).map_or(SELF_TEST_FALLBACK_SPEED, |r| r.minimum);
+    record("read-capabilities", capabilities.map(|_| ()));
+
+    if run_belt && controlled {
+        let run = async {
+            if !state.interlock.lock().unwrap().ready() {
+                return Err(CommandError::NotArmed);
+            }
+            connection.send_commands(vec![TreadmillCommands::StartOrResume, TreadmillCommands::SetTargetSpeed(speed)]).await?;
+            time::sleep(SELF_TEST_RUN_TIME).await;
+            Ok(())
+        };
+        let ran = run.await;
+        // Stopped whatever happened above, in case the start got through before a later write failed
+        let stopped = connection.send_commands(vec![TreadmillCommands::StopOrPause]).await.map(|_| ());
+        state.interlock.lock().unwrap().reset();
+        record("run-belt", ran);
+        record("stop-belt", stopped);
+    }
+
+    let passed = steps.iter().all(|step| step.error.is_none());
+    SelfTestResult { passed, steps }
+}
+
+// Stops the belt and clears every target on the machine. Reset also ends our control session, so
+// control is requested again afterwards.
+#[tauri::command]
+async fn reset_treadmill(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let connection = state.connection()?;
+    connection.send_commands(vec![TreadmillCommands::Reset]).await?;
+    state.interlock.lock().unwrap().reset();
+    *state.control_granted.lock().unwrap() = false;
+    acquire_control(&app).await?;
+
+    println!("Treadmill reset.");
+    if let Err(e) = app.emit_all("machine-reset", ()) {
+        eprintln!("Error emitting machine reset: {:?}", e);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Clone, Copy, Default)]
+struct TargetsUpdated {
+    speed: Option<u16>,
+    incline: Option<i16>,
+}
+
+fn record_targets(app: &AppHandle, speed: Option<u16>, incline: Option<i16>) {
+    let state = app.state::<AppState>();
+    let targets = {
+        let mut targets = state.targets.lock().unwrap();
+        targets.speed = speed.or(targets.speed);
+        targets.incline = incline.or(targets.incline);
+        *targets
+    };
+    persist_state(app, |snapshot| {
+        snapshot.speed = targets.speed;
+        snapshot.incline = targets.incline;
+    });
+}
+
+// Applies `update` to the crash snapshot and writes it out when anything changed.
+fn persist_state(app: &AppHandle, update: impl FnOnce(&mut Snapshot)) {
+    let state = app.state::<AppState>();
+    let mut snapshot = state.snapshot.lock().unwrap();
+    let previous = snapshot.clone();
+    update(&mut snapshot);
+    if *snapshot == previous {
+        return;
+    }
+    if let Err(e) = snapshot::save(app, &mut snapshot) {
+        eprintln!("Error saving snapshot: {:?}", e);
+    }
+}
+
+// What the previous run was doing when the app went away, if it was doing anything. Nothing moves
+// unless `confirmed` is set, then the targets are applied to the connected treadmill, starting the
+// belt if it was running. The interlock still applies.
+#[tauri::command]
+async fn restore_state(app: AppHandle, state: State<'_, AppState>, confirmed: bool) -> Result<Option<Snapshot>, CommandError> {
+    let Some(saved) = state.restorable.lock().unwrap().clone() else {
+        return Ok(None);
+    };
+    if !confirmed {
+        return Ok(Some(saved));
+    }
+
+    let connection = state.connection()?;
+    let capabilities = machine_capabilities(&state).await?;
+    if let Some(speed) = saved.speed {
+        check_target_speed(&state, &capabilities, speed)?;
+    }
+    if saved.running {
+        connection.send_commands(vec![TreadmillCommands::StartOrResume]).await?;
+    }
+
+    let mut commands = Vec::new();
+    if let Some(speed) = saved.speed {
+        ramp_target_speed(&state, &connection, speed).await?;
+        commands.push(TreadmillCommands::SetTargetSpeed(speed));
+    }
+    let incline = saved.incline.map(|incline| match capabilities.inclination_range {
+        Some(range) => incline.max(range.minimum).min(range.maximum),
+        None => incline,
+    });
+    if let Some(incline) = incline {
+        commands.push(TreadmillCommands::SetTargetInclination(incline));
+    }
+    if !commands.is_empty() {
+        connection.send_commands(commands).await?;
+    }
+    record_targets(&app, saved.speed, incline);
+    *state.restorable.lock().unwrap() = None;
+
+    println!("Restored {:?}.", saved);
+    if let Err(e) = app.emit_all("state-restored", saved.clone()) {
+        eprintln!("Error emitting state restored: {:?}", e);
+    }
+    Ok(Some(saved))
+}
+
+// Walks the target speed towards `target` in steps of the configured ramp, starting from the last
+// reported speed. Stops one step short so the caller sends the final target itself.
+async fn ramp_target_speed(
+    state: &AppState,
+    connection: &TreadmillConnection,
+    target: u16,
+) -> Result<(), CommandError> {
+    for speed in ramp_speeds(state, target) {
+        connection.send_commands(vec![TreadmillCommands::SetTargetSpeed(speed)]).await?;
+        time::sleep(speed_ramp::STEP_DELAY).await;
+    }
+    Ok(())
+}
+
+// The intermediate speeds on the way to `target`, none without a ramp or a reported speed.
+fn ramp_speeds(state: &AppState, target: u16) -> Vec<u16> {
+    let Some(max_change) = state.settings.lock().unwrap().max_speed_change_per_command else {
+        return Vec::new();
+    };
+    let Some(current) = state.latest_data.lock().unwrap().as_ref().map(|data| data.speed) else {
+        return Vec::new();
+    };
+    speed_ramp::intermediate_speeds(current, target, max_change)
+}
+
+// Degrees of incline to 0.1% grade, grade being rise over run: tan(angle) * 100. Treadmill
+// consoles show grade, so 3° comes out as about 5.2%. Negative angles are declines. Anything past
+// 45° (100% grade) is rejected outright, no treadmill gets anywhere near it.
+fn degrees_to_inclination(degrees: f64) -> Result<i16, CommandError> {
+    if !degrees.is_finite() || degrees.abs() >= 45.0 {
+        return Err(CommandError::OutOfRange(format!("incline of {} degrees", degrees)));
+    }
+    Ok((degrees.to_radians().tan() * 100.0 * 10.0).round() as i16)
+}
+
+#[tauri::command]
+async fn set_inclination_degrees(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    degrees: f64,
+) -> Result<TargetsUpdated, CommandError> {
+    let incline = degrees_to_inclination(degrees)?;
+    set_targets(app, state, None, Some(incline)).await
+}
+
+const LATENCY_SAMPLES: usize = 3;
+
+#[derive(Debug, Serialize, Clone)]
+struct LatencyMeasured {
+    // Milliseconds from each write to its indication
+    samples: Vec<u64>,
+    average: u64,
+}
+
+// Times how long the machine takes to answer a control point write. Request control is used as
+// the probe since it doesn't change anything on a machine we already control.
+#[tauri::command]
+async fn measure_control_latency(app: AppHandle, state: State<'_, AppState>) -> Result<u64, CommandError> {
+    let connection = state.connection()?;
+    let mut samples = Vec::with_capacity(LATENCY_SAMPLES);
+    for _ in 0..LATENCY_SAMPLES {
+        let started = std::time::Instant::now();
+        connection.send_commands(vec![TreadmillCommands::RequestControl]).await?;
+        samples.push(started.elapsed().as_millis() as u64);
+    }
+
+    let average = samples.iter().sum::<u64>() / samples.len() as u64;
+    println!("Control point latency: {}ms average over {:?}", average, samples);
+    if let Err(e) = app.emit_all("latency-measured", LatencyMeasured { samples, average }) {
+        eprintln!("Error emitting latency: {:?}", e);
+    }
+    Ok(average)
+}
+
+// Keeps the session that's already being logged, so a reconnect or a workout started mid-session
+// carries on in the same file. Returns the session id.
+fn open_session_log(app: &AppHandle, state: &AppState) -> Result<String, CommandError> {
+    let mut current = state.session_log.lock().unwrap();
+    if let Some(log) = current.as_ref() {
+        return Ok(log.id.clone());
+    }
+
+    let log = SessionLog::start(app)?;
+    *state.session_stats.lock().unwrap() = SessionStats::default();
+    println!("Logging session {} to {:?}.", log.id, log.path);
+    let id = log.id.clone();
+    *current = Some(log);
+    Ok(id)
+}
+
+fn close_session_log(state: &AppState) -> Result<(), CommandError> {
+    let Some(log) = state.session_log.lock().unwrap().take() else {
+        return Ok(());
+    };
+    let stats = state.session_stats.lock().unwrap().clone();
+    let path = log.finish(&stats)?;
+    println!("Session log saved to {:?}.", path);
+    Ok(())
+}
+
+#[tauri::command]
+fn start_session_log(app: AppHandle, state: State<'_, AppState>) -> Result<String, CommandError> {
+    open_session_log(&app, &state)
+}
+
+#[tauri::command]
+fn end_session_log(state: State<'_, AppState>) -> Result<(), CommandError> {
+    close_session_log(&state)
+}
+
+#[tauri::command]
+fn list_sessions(app: AppHandle) -> Result<Vec<session_log::SessionSummary>, CommandError> {
+    session_log::list(&app)
+}
+
+// The recorded rows of a past session, for charting.
+#[tauri::command]
+fn load_session(app: AppHandle, id: String) -> Result<Vec<session_log::Trackpoint>, CommandError> {
+    session_log::load(&app, &id)
+}
+
+// Plays session `id` back as `treadmill-data` events, `speed_multiplier` times faster than it was
+// recorded. Returns how many frames it will emit, and replaces a replay that's already running.
+#[tauri::command]
+fn replay_session(app: AppHandle, state: State<'_, AppState>, id: String, speed_multiplier: f64) -> Result<usize, CommandError> {
+    if !replay::SPEED_MULTIPLIER_RANGE.contains(&speed_multiplier) {
+        return Err(CommandError::OutOfRange(format!(
+            "replay speed of {} is outside {:?}",
+            speed_multiplier,
+            replay::SPEED_MULTIPLIER_RANGE
+        )));
+    }
+    let trackpoints = session_log::load(&app, &id)?;
+    println!("Replaying session {} at {}x.", id, speed_multiplier);
+    let (replay, frames) = replay::Replay::start(app.clone(), id, &trackpoints, speed_multiplier);
+    if let Some(previous) = state.replay.lock().unwrap().replace(replay) {
+        previous.stop();
+    }
+    Ok(frames)
+}
+
+fn set_replay_paused(state: &AppState, paused: bool) -> Result<(), CommandError> {
+    let replay = state.replay.lock().unwrap();
+    replay.as_ref().ok_or(CommandError::ReplayNotRunning)?.set_paused(paused);
+    Ok(())
+}
+
+#[tauri::command]
+fn pause_replay(state: State<'_, AppState>) -> Result<(), CommandError> {
+    set_replay_paused(&state, true)
+}
+
+#[tauri::command]
+fn resume_replay(state: State<'_, AppState>) -> Result<(), CommandError> {
+    set_replay_paused(&state, false)
+}
+
+#[tauri::command]
+fn stop_replay(state: State<'_, AppState>) -> Result<(), CommandError> {
+    let replay = state.replay.lock().unwrap().take().ok_or(CommandError::ReplayNotRunning)?;
+    replay.stop();
+    println!("Replay stopped.");
+    Ok(())
+}
+
+#[tauri::command]
+fn get_session_stats(state: State<'_, AppState>) -> Result<SessionStats, CommandError> {
+    Ok(state.session_stats.lock().unwrap().clone())
+}
+
+// Zeroes the session distance mid-run, the next reported distance becomes the new start.
+#[tauri::command]
+fn reset_session_distance(state: State<'_, AppState>) -> Result<(), CommandError> {
+    state.session_distance.lock().unwrap().reset();
+    state.distance_estimate.lock().unwrap().reset();
+    state.splits.lock().unwrap().reset();
+    Ok(())
+}
+
+// How the distance estimate for frames without a distance is being corrected this session.
+#[tauri::command]
+fn get_distance_calibration(state: State<'_, AppState>) -> Result<DistanceCalibration, CommandError> {
+    Ok(state.distance_estimate.lock().unwrap().calibration())
+}
+
+// Pass `None` to send target speed changes in one jump.
+#[tauri::command]
+fn set_speed_ramp(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    max_speed_change_per_command: Option<u16>,
+) -> Result<(), CommandError> {
+    let settings = Settings { max_speed_change_per_command, ..state.settings.lock().unwrap().clone() };
+    apply_settings(&app, &state, settings)
+}
+
+fn check_target_speed(state: &AppState, capabilities: &MachineCapabilities, speed: u16) -> Result<(), CommandError> {
+    check_speed_cap(state, speed)?;
+    if let Some(range) = capabilities.speed_range {
+        if speed < range.minimum || speed > range.maximum {
+            return Err(CommandError::OutOfRange(format!(
+                "speed {} is outside {}..={}",
+                speed, range.minimum, range.maximum
+            )));
+        }
+    }
+    Ok(())
+}
+
+// For rapid changes such as dragging a slider. Speeds arriving within the debounce interval are
+// coalesced and only the latest one is written, once the interval is up.
+#[tauri::command]
+async fn set_target_speed(app: AppHandle, state: State<'_, AppState>, speed: u16) -> Result<(), CommandError> {
+    state.connection()?;
+    let capabilities = machine_capabilities(&state).await?;
+    check_target_speed(&state, &capabilities, speed)?;
+
+    if state.pending_target_speed.lock().unwrap().replace(speed).is_some() {
+        return Ok(());
+    }
+
+    let interval = state.settings.lock().unwrap().speed_debounce_ms.unwrap_or(DEFAULT_SPEED_DEBOUNCE_MS);
+    tauri::async_runtime::spawn(async move {
+        time::sleep(Duration::from_millis(interval as u64)).await;
+        let state = app.state::<AppState>();
+        let Some(speed) = state.pending_target_speed.lock().unwrap().take() else {
+            return;
+        };
+
+        let result = match state.connection() {
+            Ok(connection) => match ramp_target_speed(&state, &connection, speed).await {
+                Ok(()) => connection.send_commands(vec![TreadmillCommands::SetTargetSpeed(speed)]).await.map(|_| ()),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                record_targets(&app, Some(speed), None);
+                if let Err(e) = app.emit_all("targets-updated", TargetsUpdated { speed: Some(speed), incline: None }) {
+                    eprintln!("Error emitting targets update: {:?}", e);
+                }
+            }
+            Err(e) => eprintln!("Error setting target speed: {:?}", e),
+        }
+    });
+    Ok(())
+}
+
+// How long to wait after a write for Fitness Machine Status to report the applied target
+const APPLIED_TARGET_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Serialize, Clone)]
+struct TargetClamped {
+    requested: TargetsUpdated,
+    applied: TargetsUpdated,
+}
+
+// The targets the machine reports applying since each was last written, waiting a little for them.
+// Falls back to what was requested when it doesn't say, as machines without status notifications
+// never will.
+async fn applied_targets(
+    state: &AppState,
+    speed_sent: Instant,
+    incline_sent: Instant,
+    requested: TargetsUpdated,
+) -> TargetsUpdated {
+    let read = || {
+        let applied = state.applied_targets.lock().unwrap();
+        TargetsUpdated {
+            speed: requested.speed.and(applied.speed_since(speed_sent)),
+            incline: requested.incline.and(applied.incline_since(incline_sent)),
+        }
+    };
+    let reported = time::timeout(APPLIED_TARGET_TIMEOUT, async {
+        loop {
+            let changed = state.applied_targets_changed.notified();
+            let applied = read();
+            let speed_done = applied.speed.is_some() == requested.speed.is_some();
+            if speed_done && applied.incline.is_some() == requested.incline.is_some() {
+                return;
+            }
+            changed.await;
+        }
+    })
+    .await;
+    if reported.is_err() {
+        println!("Machine didn't report every applied target within {:?}.", APPLIED_TARGET_TIMEOUT);
+    }
+    let applied = read();
+    TargetsUpdated { speed: applied.speed.or(requested.speed), incline: applied.incline.or(requested.incline) }
+}
+
+// Records the targets a Fitness Machine Status notification says were applied, waking up
+// `applied_targets`.
+fn handle_machine_status(state: &AppState, value: &[u8]) {
+    match machine_status::decode_machine_status(value) {
+        Ok(status) => {
+            println!("Machine status: {:?}", status);
+            if !matches!(status, MachineStatus::Other(_)) {
+                state.applied_targets.lock().unwrap().update(status);
+                state.applied_targets_changed.notify_waiters();
+            }
+        }
+        Err(e) => eprintln!("Error decoding machine status: {:?}", e),
+    }
+}
+
+// The speed cap from the settings, which unlike the machine's range is never left to the machine.
+fn check_speed_cap(state: &AppState, speed: u16) -> Result<(), CommandError> {
+    if let Some(max_speed) = state.settings.lock().unwrap().max_speed.filter(|max| speed > *max) {
+        return Err(CommandError::OutOfRange(format!("speed {} is above the cap of {}", speed, max_speed)));
+    }
+    Ok(())
+}
+
+// Sends the `requested` targets, one write per command with speed then incline back to back, and
+// returns what the machine applied. With a speed ramp the incline goes out next to the ramp's
+// first speed instead of waiting for the whole ramp, and the rest of the ramp follows.
+async fn send_targets(
+    state: &AppState,
+    connection: &TreadmillConnection,
+    capabilities: &MachineCapabilities,
+    requested: TargetsUpdated,
+) -> Result<TargetsUpdated, CommandError> {
+    let mut speeds = Vec::new();
+    if let Some(speed) = requested.speed {
+        check_target_speed(state, capabilities, speed)?;
+        speeds = ramp_speeds(state, speed);
+        speeds.push(speed);
+    }
+    let mut speeds = speeds.into_iter();
+    let mut commands: Vec<_> = speeds.next().map(TreadmillCommands::SetTargetSpeed).into_iter().collect();
+    if let Some(incline) = requested.incline {
+        if let Some(range) = capabilities.inclination_range {
+            if incline < range.minimum || incline > range.maximum {
+                return Err(CommandError::OutOfRange(format!(
+                    "incline {} is outside {}..={}",
+                    incline, range.minimum, range.maximum
+                )));
+            }
+        }
+        commands.push(TreadmillCommands::SetTargetInclination(incline));
+    }
+
+    let incline_sent = Instant::now();
+    connection.send_commands(commands).await?;
+    let mut speed_sent = incline_sent;
+    for speed in speeds {
+        time::sleep(speed_ramp::STEP_DELAY).await;
+        speed_sent = Instant::now();
+        connection.send_commands(vec![TreadmillCommands::SetTargetSpeed(speed)]).await?;
+    }
+    Ok(applied_targets(state, speed_sent, incline_sent, requested).await)
+}
+
+// Sets speed and incline together so the treadmill isn't left with only one of them applied.
+// Returns the targets the machine says it applied, which it can still clamp within its range.
+#[tauri::command]
+async fn set_targets(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    speed: Option<u16>,
+    incline: Option<i16>,
+) -> Result<TargetsUpdated, CommandError> {
+    let connection = state.connection()?;
+    let capabilities = machine_capabilities(&state).await?;
+    let requested = TargetsUpdated { speed, incline };
+    if speed.is_none() && incline.is_none() {
+        return Ok(requested);
+    }
+    let applied = send_targets(&state, &connection, &capabilities, requested).await?;
+    record_targets(&app, applied.speed, applied.incline);
+
+    if applied.speed != speed || applied.incline != incline {
+        println!("Machine clamped targets {:?} to {:?}.", requested, applied);
+        if let Err(e) = app.emit_all("target-clamped", TargetClamped { requested, applied }) {
+            eprintln!("Error emitting target clamped: {:?}", e);
+        }
+    }
+    if let Err(e) = app.emit_all("targets-updated", applied) {
+        eprintln!("Error emitting targets update: {:?}", e);
+    }
+    Ok(applied)
+}
+
+// Shared by the real treadmill and the simulator so both produce the same events.
+fn handle_notification(
+    app: &AppHandle,
+    responses: &broadcast::Sender<ControlPointResponse>,
+    uuid: Uuid,
+    value: &[u8],
+) {
+    if uuid == TREADMILL_CONTROL_CHARACTERISTIC_UUID {
+        let state = app.state::<AppState>();
+        let decoded = state.control_point_buffer.lock().unwrap().push(value);
+        for response in decoded {
+            println!("Control point response: {:?}", response);
+            if let Some(targets) = response.spin_down_targets() {
+                println!(
+                    "Spin down started, speed up past {} and let the belt coast below {} (0.01 km/h).",
+                    targets.speed_high, targets.speed_low
+                );
+                if let Err(e) = app.emit_all("spin-down-targets", targets) {
+                    eprintln!("Error emitting spin down targets: {:?}", e);
+                }
+            }
+            // Nobody waiting on a response isn't an error
+            let _ = responses.send(response);
+        }
+        return;
+    }
+
+    if uuid == MACHINE_STATUS_CHARACTERISTIC_UUID {
+        handle_machine_status(&app.state::<AppState>(), value);
+        return;
+    }
+
+    if uuid == BATTERY_LEVEL_CHARACTERISTIC_UUID {
+        match value.first() {
+            Some(level) => {
+                if let Err(e) = app.emit_all("battery-level", *level) {
+                    eprintln!("Error emitting battery level: {:?}", e);
+                }
+            }
+            None => {
+                println!("Error decoding battery level.");
+            }
+        }
+        return;
+    }
+
+    if MachineType::from_data_characteristic(uuid) == Some(MachineType::IndoorBike) {
+        match indoor_bike::decode_indoor_bike_data(value) {
+            Ok(mut data) => {
+                data.heart_rate = heart_rate_strap::merge_heart_rate(&app.state::<AppState>(), data.heart_rate);
+                println!("Indoor bike data: {:?}", data);
+                if let Some(heart_rate) = data.heart_rate {
+                    apply_heart_rate_limit(app, heart_rate);
+                }
+                if let Err(e) = app.emit_all("indoor-bike-data", data) {
+                    eprintln!("Error emitting indoor bike data: {:?}", e);
+                }
+            }
+            Err(_) => {
+                println!("Error decoding indoor bike data.");
+            }
+        }
+        return;
+    }
+
+    let state = app.state::<AppState>();
+    let profile = *state.device_profile.lock().unwrap();
+    let decode = profile.map_or(decode_treadmill_data as TreadmillDecoder, |p| p.decode);
+    match decode(value) {
+        Ok(mut data) => {
+            if let Some(profile) = profile {
+                data.scale_speeds(profile.speed_scale);
+            }
+            if data.trailing_bytes > 0 && state.settings.lock().unwrap().warn_trailing_bytes {
+                eprintln!(
+                    "Treadmill Data has {} trailing bytes after the flagged fields: {:02x?}",
+                    data.trailing_bytes, value
+                );
+            }
+            let implausible_speed = state.settings.lock().unwrap().implausible_speed;
+            if let Some(frame) = implausible_frame(&data, implausible_speed, value) {
+                println!("Dropping frame with an implausible speed of {}: {:02x?}", data.speed, value);
+                if let Err(e) = app.emit_all("implausible-frame", frame) {
+                    eprintln!("Error emitting implausible frame: {:?}", e);
+                }
+                return;
+            }
+            data.heart_rate = heart_rate_strap::merge_heart_rate(&state, data.heart_rate);
+            // Fall back to the incline we asked for on machines that don't report it
+            let inclination = data.inclination.or(state.targets.lock().unwrap().incline);
+            if let Some(weight) = state.settings.lock().unwrap().body_weight_kg {
+                data.estimated_energy_per_hour = Some(calories::calories_per_hour(data.speed, inclination, weight as f64));
+            }
+            update_running_metrics(app, calories::running_metrics(data.speed, inclination, data.metabolic_equivalent_met));
+            if let Some(total_distance) = data.total_distance {
+                let (distance, drop) = state.session_distance.lock().unwrap().update(total_distance);
+                data.session_distance = Some(distance);
+                if let Some(drop) = drop {
+                    println!("Total distance went back to {} ({:?}), carrying the session distance over.", total_distance, drop);
+                    if let Err(e) = app.emit_all("distance-wrap-detected", drop) {
+                        eprintln!("Error emitting distance wrap: {:?}", e);
+                    }
+                }
+            }
+            let estimated = state.distance_estimate.lock().unwrap().update(data.speed, data.session_distance);
+            if data.session_distance.is_none() {
+                data.estimated_distance = Some(estimated);
+            }
+            println!("Data: {:?}", data);
+            state.session_stats.lock().unwrap().add(&data);
+            if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+                log.append(&data);
+            }
+            apply_splits(app, &data);
+            if let Some(heart_rate) = data.heart_rate {
+                apply_heart_rate_limit(app, heart_rate);
+                apply_heart_rate_zone(app, heart_rate, data.speed);
+            }
+            apply_session_timeout(app, &data);
+            update_data_fields(app, &data);
+            *state.latest_data.lock().unwrap() = Some(data.clone());
+            let running = data.speed > 0;
+            persist_state(app, |snapshot| snapshot.running = running);
+            emit_treadmill_data(app, data);
+        },
+        Err(_) => {
+            println!("Error decoding data.");
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct ImplausibleFrame {
+    // Km/h at 0.01 precision
+    speed: u16,
+    limit: u16,
+    raw: Vec<u8>,
+}
+
+// `Some` when a `limit` is set and the frame's speed is above it.
+fn implausible_frame(data: &TreadmillData, limit: Option<u16>, raw: &[u8]) -> Option<ImplausibleFrame> {
+    let limit = limit.filter(|limit| data.speed > *limit)?;
+    Some(ImplausibleFrame { speed: data.speed, limit, raw: raw.to_vec() })
+}
+
+// Emits at most one frame per configured interval. Frames arriving sooner replace each other and
+// the latest goes out once the interval is up, so the last one before the belt stops isn't lost.
+// Everything else sees every frame, this only spares the frontend.
+fn emit_treadmill_data(app: &AppHandle, data: TreadmillData) {
+    let state = app.state::<AppState>();
+    #[cfg(feature = "data-server")]
+    if let Some(server) = state.data_server.lock().unwrap().as_ref() {
+        server.send(&data);
+    }
+    let interval = state.settings.lock().unwrap().data_emit_interval_ms.unwrap_or(DEFAULT_DATA_EMIT_INTERVAL_MS);
+    let interval = Duration::from_millis(interval as u64);
+    let offered = state.data_emit.lock().unwrap().offer(data, interval, Instant::now());
+    let wait = match offered {
+        Throttled::Emit(data) => {
+            if let Err(e) = app.emit_all("treadmill-data", data) {
+                eprintln!("Error emitting treadmill data: {:?}", e);
+            }
+            return;
+        }
+        Throttled::EmitLater(wait) => wait,
+        Throttled::Replaced => return,
+    };
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        time::sleep(wait).await;
+        let Some(data) = app.state::<AppState>().data_emit.lock().unwrap().take_pending(Instant::now()) else {
+            return;
+        };
+        if let Err(e) = app.emit_all("treadmill-data", data) {
+            eprintln!("Error emitting treadmill data: {:?}", e);
+        }
+    });
+}
+
+// Serves decoded data to local tools over a WebSocket on `port`, see `data_server`. Returns the
+// port, which is picked for you when `port` is 0. Replaces a server that's already running.
+#[tauri::command]
+async fn start_data_server(state: State<'_, AppState>, port: u16) -> Result<u16, CommandError> {
+    #[cfg(feature = "data-server")]
+    {
+        let server = data_server::DataServer::start(port).await?;
+        let port = server.port;
+        if let Some(previous) = state.data_server.lock().unwrap().replace(server) {
+            previous.stop();
+        }
+        Ok(port)
+    }
+    #[cfg(not(feature = "data-server"))]
+    {
+        let _ = (state, port);
+        Err(CommandError::NotSupported("data server, this build doesn't include it".to_string()))
+    }
+}
+
+#[tauri::command]
+fn stop_data_server(state: State<'_, AppState>) -> Result<(), CommandError> {
+    #[cfg(feature = "data-server")]
+    if let Some(server) = state.data_server.lock().unwrap().take() {
+        server.stop();
+    }
+    #[cfg(not(feature = "data-server"))]
+    let _ = state;
+    Ok(())
+}
+
+fn update_running_metrics(app: &AppHandle, metrics: calories::RunningMetrics) {
+    let state = app.state::<AppState>();
+    if state.running_metrics.lock().unwrap().replace(metrics) == Some(metrics) {
+        return;
+    }
+    if let Err(e) = app.emit_all("running-metrics", metrics) {
+        eprintln!("Error emitting running metrics: {:?}", e);
+    }
+}
+
+// Stats for each step the current or last workout finished, in order.
+#[tauri::command]
+fn get_lap_data(state: State<'_, AppState>) -> Result<Vec<runner::Lap>, CommandError> {
+    Ok(runner::laps(&state))
+}
+
+// Estimated VO2 and METs from the latest speed and incline, see `calories` for the equations.
+#[tauri::command]
+fn get_running_metrics(state: State<'_, AppState>) -> Result<Option<calories::RunningMetrics>, CommandError> {
+    Ok(*state.running_metrics.lock().unwrap())
+}
+
+fn apply_splits(app: &AppHandle, data: &TreadmillData) {
+    let state = app.state::<AppState>();
+    let Some(distance) = data.session_distance else {
+        return;
+    };
+    let unit = state.settings.lock().unwrap().split_unit;
+    let Some(split) = state.splits.lock().unwrap().update(unit, distance, data.elapsed_time) else {
+        return;
+    };
+
+    println!("Split {:?}.", split);
+    if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+        log.split(data);
+    }
+    if let Err(e) = app.emit_all("split", split) {
+        eprintln!("Error emitting split: {:?}", e);
+    }
+}
+
+fn update_data_fields(app: &AppHandle, data: &TreadmillData) {
+    let fields = data.populated_fields();
+    let state = app.state::<AppState>();
+    let mut current = state.data_fields.lock().unwrap();
+    if current.as_ref() == Some(&fields) {
+        return;
+    }
+
+    println!("Treadmill data fields changed: {:?}", fields);
+    if let Err(e) = app.emit_all("data-fields-changed", fields.clone()) {
+        eprintln!("Error emitting data fields: {:?}", e);
+    }
+    *current = Some(fields);
+}
+
+// Which `TreadmillData` fields this machine fills in. Uses the latest frame when there is one,
+// otherwise what the Fitness Machine Feature characteristic advertises.
+#[tauri::command]
+async fn describe_data_fields(state: State<'_, AppState>) -> Result<Vec<&'static str>, CommandError> {
+    if let Some(fields) = state.data_fields.lock().unwrap().clone() {
+        return Ok(fields);
+    }
+
+    let capabilities = machine_capabilities(&state).await?;
+    match capabilities.features {
+        Some(features) => Ok(capabilities::data_fields(&features)),
+        None => Err(CommandError::NotSupported("Fitness Machine Feature".to_string())),
+    }
+}
+
+fn apply_heart_rate_limit(app: &AppHandle, heart_rate: u8) {
+    let state = app.state::<AppState>();
+    let Some(limit) = state.settings.lock().unwrap().heart_rate_limit else {
+        return;
+    };
+
+    let action = {
+        let mut paused = state.heart_rate_paused.lock().unwrap();
+        let Some(action) = heart_rate_limit::check_heart_rate(&limit, *paused, heart_rate) else {
+            return;
+        };
+        *paused = action == HeartRateAction::Pause;
+        action
+    };
+
+    let (command, event) = match action {
+        HeartRateAction::Pause => (TreadmillCommands::StopOrPause, "hr-limit-exceeded"),
+        HeartRateAction::Resume => (TreadmillCommands::StartOrResume, "hr-limit-resumed"),
+    };
+    println!("Heart rate {} crossed the limit {:?}, sending {:?}.", heart_rate, limit, action);
+    if let Err(e) = app.emit_all(event, HeartRateLimitEvent { heart_rate, limit }) {
+        eprintln!("Error emitting heart rate limit event: {:?}", e);
+    }
+
+    let Ok(connection) = state.connection() else {
+        return;
+    };
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = connection.send_commands(vec![command]).await {
+            eprintln!("Error applying heart rate limit: {:?}", e);
+        }
+    });
+}
+
+// Leaves the belt alone while it's stopped or the heart rate limit has paused it.
+fn apply_heart_rate_zone(app: &AppHandle, heart_rate: u8, speed: u16) {
+    let state = app.state::<AppState>();
+    if speed == 0 || *state.heart_rate_paused.lock().unwrap() {
+        return;
+    }
+    let range = state.capabilities.lock().unwrap().as_ref().and_then(|c| c.speed_range);
+    let minimum = range.map_or(heart_rate_zone::FALLBACK_MINIMUM_SPEED, |r| r.minimum);
+    let mut maximum = range.map_or(heart_rate_zone::FALLBACK_MAXIMUM_SPEED, |r| r.maximum);
+    if let Some(max_speed) = state.settings.lock().unwrap().max_speed {
+        maximum = maximum.min(max_speed);
+    }
+    let adjustment = {
+        let mut controller = state.heart_rate_zone.lock().unwrap();
+        let Some(adjustment) = controller.as_mut().and_then(|c| c.update(heart_rate, speed, minimum, maximum)) else {
+            return;
+        };
+        adjustment
+    };
+
+    println!("Heart rate zone adjustment: {:?}", adjustment);
+    if let Err(e) = app.emit_all("hr-zone-adjustment", adjustment.clone()) {
+        eprintln!("Error emitting heart rate zone adjustment: {:?}", e);
+    }
+
+    let Ok(connection) = state.connection() else {
+        return;
+    };
+    let app = app.clone();
+    let speed = adjustment.to_speed;
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = connection.send_commands(vec![TreadmillCommands::SetTargetSpeed(speed)]).await {
+            eprintln!("Error applying heart rate zone: {:?}", e);
+            return;
+        }
+        record_targets(&app, Some(speed), None);
+        if let Err(e) = app.emit_all("targets-updated", TargetsUpdated { speed: Some(speed), incline: None }) {
+            eprintln!("Error emitting targets update: {:?}", e);
+        }
+    });
+}
+
+fn apply_session_timeout(app: &AppHandle, data: &TreadmillData) {
+    let state = app.state::<AppState>();
+    let Some(limit) = state.settings.lock().unwrap().max_session_duration else {
+        return;
+    };
+    let limit = Duration::from_secs(limit as u64);
+    if !state.session_timer.lock().unwrap().check(limit, data.speed > 0, data.elapsed_time) {
+        return;
+    }
+
+    println!("Session ran past {:?}, stopping the belt.", limit);
+    if let Err(e) = app.emit_all("session-timeout", limit.as_secs()) {
+        eprintln!("Error emitting session timeout: {:?}", e);
+    }
+
+    let Ok(connection) = state.connection() else {
+        return;
+    };
+    state.interlock.lock().unwrap().reset();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = connection.send_commands(vec![TreadmillCommands::StopOrPause]).await {
+            eprintln!("Error stopping timed out session: {:?}", e);
+        }
+    });
+}
+
+// Seconds, pass `None` to turn the session time limit off.
+#[tauri::command]
+fn set_max_session_duration(app: AppHandle, state: State<'_, AppState>, seconds: Option<u32>) -> Result<(), CommandError> {
+    let settings = Settings { max_session_duration: seconds, ..state.settings.lock().unwrap().clone() };
+    apply_settings(&app, &state, settings)
+}
+
+// Pass `None` to turn the heart rate limit off.
+#[tauri::command]
+fn set_heart_rate_limit(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    limit: Option<HeartRateLimit>,
+) -> Result<(), CommandError> {
+    let settings = Settings { heart_rate_limit: limit, ..state.settings.lock().unwrap().clone() };
+    apply_settings(&app, &state, settings)
+}
+
+// Steers the target speed to keep heart rate within `zone`, pass `None` to stop steering.
+#[tauri::command]
+fn set_heart_rate_zone(state: State<'_, AppState>, zone: Option<HeartRateZone>) -> Result<(), CommandError> {
+    if let Some(zone) = zone {
+        if zone.min == 0 || zone.min >= zone.max {
+            return Err(CommandError::OutOfRange(format!("heart rate zone {}..={}", zone.min, zone.max)));
+        }
+    }
+    println!("Heart rate zone set to {:?}.", zone);
+    *state.heart_rate_zone.lock().unwrap() = zone.map(ZoneController::new);
+    Ok(())
+}
+
+// Validates, saves and switches to `settings`. Limits that changed start over, so e.g. a new heart
+// rate limit doesn't resume a belt the old one paused.
+fn apply_settings(app: &AppHandle, state: &AppState, settings: Settings) -> Result<(), CommandError> {
+    settings.validate()?;
+    settings::save(app, &settings)?;
+
+    let previous = std::mem::replace(&mut *state.settings.lock().unwrap(), settings.clone());
+    if previous.heart_rate_limit != settings.heart_rate_limit {
+        *state.heart_rate_paused.lock().unwrap() = false;
+    }
+    if previous.max_session_duration != settings.max_session_duration {
+        *state.session_timer.lock().unwrap() = SessionTimer::default();
+    }
+    state.interlock.lock().unwrap().set_window(settings.arm_window());
+    if let Some(connection) = state.treadmill.lock().unwrap().as_mut() {
+        connection.procedure_timeout = settings.procedure_timeout();
+    }
+
+    println!("Settings changed: {:?}", settings);
+    if let Err(e) = app.emit_all("settings-changed", settings) {
+        eprintln!("Error emitting settings: {:?}", e);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn get_settings(state: State<'_, AppState>) -> Result<Settings, CommandError> {
+    Ok(state.settings.lock().unwrap().clone())
+}
+
+#[tauri::command]
+fn update_settings(app: AppHandle, state: State<'_, AppState>, settings: Settings) -> Result<(), CommandError> {
+    apply_settings(&app, &state, settings)
+}
+
+#[tauri::command]
+fn start_simulator(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let (responses, _) = broadcast::channel(16);
+    let simulator = simulator::Simulator::start(app, responses.clone());
+    let connection = TreadmillConnection {
+        transport: Transport::Simulator(simulator),
+        responses,
+        interlock: state.interlock.clone(),
+        control_granted: state.control_granted.clone(),
+        procedure_timeout: state.settings.lock().unwrap().procedure_timeout(),
+    };
+
+    if let Some(previous) = state.treadmill.lock().unwrap().replace(connection) {
+        if let Transport::Simulator(simulator) = previous.transport {
+            simulator.stop();
+        }
+    }
+    *state.capabilities.lock().unwrap() = None;
+    *state.device_profile.lock().unwrap() = None;
+    state.control_point_buffer.lock().unwrap().clear();
+    state.interlock.lock().unwrap().reset();
+    println!("Simulator started.");
+    Ok(())
+}
+
+#[tauri::command]
+fn stop_simulator(state: State<'_, AppState>) -> Result<(), CommandError> {
+    let mut treadmill = state.treadmill.lock().unwrap();
+    match treadmill.as_ref().map(|c| &c.transport) {
+        Some(Transport::Simulator(simulator)) => {
+            simulator.stop();
+            *treadmill = None;
+            *state.capabilities.lock().unwrap() = None;
+            println!("Simulator stopped.");
+            Ok(())
+        }
+        _ => Err(CommandError::NotConnected),
+    }
+}
+
+// Names for the UUIDs we know about, used to make characteristic listings readable.
+fn known_uuid_name(uuid: Uuid) -> Option<&'static str> {
+    let known = [
+        (FITNESS_MACHINE_SERVICE_UUID, "Fitness Machine Service"),
+        (uuid_from_u16(0x180F), "Battery Service"),
+        (uuid_from_u16(0x180A), "Device Information"),
+        (uuid_from_u16(0x180D), "Heart Rate Service"),
+        (TREADMILL_DATA_CHARACTERISTIC_UUID, "Treadmill Data"),
+        (INDOOR_BIKE_DATA_CHARACTERISTIC_UUID, "Indoor Bike Data"),
+        (TREADMILL_CONTROL_CHARACTERISTIC_UUID, "Fitness Machine Control Point"),
+        (FITNESS_MACHINE_FEATURE_CHARACTERISTIC_UUID, "Fitness Machine Feature"),
+        (SUPPORTED_SPEED_RANGE_CHARACTERISTIC_UUID, "Supported Speed Range"),
+        (SUPPORTED_INCLINATION_RANGE_CHARACTERISTIC_UUID, "Supported Inclination Range"),
+        (SUPPORTED_POWER_RANGE_CHARACTERISTIC_UUID, "Supported Power Range"),
+        (MACHINE_STATUS_CHARACTERISTIC_UUID, "Fitness Machine Status"),
+        (uuid_from_u16(0x2AD3), "Training Status"),
+        (BATTERY_LEVEL_CHARACTERISTIC_UUID, "Battery Level"),
+        (MANUFACTURER_NAME_CHARACTERISTIC_UUID, "Manufacturer Name String"),
+        (MODEL_NUMBER_CHARACTERISTIC_UUID, "Model Number String"),
+        (FIRMWARE_REVISION_CHARACTERISTIC_UUID, "Firmware Revision String"),
+        (uuid_from_u16(0x2A37), "Heart Rate Measurement"),
+    ];
+    known.iter().find(|(known, _)| *known == uuid).map(|(_, name)| *name)
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct CharacteristicInfo {
+    // Short form for 16 and 32 bit Bluetooth UUIDs, e.g. "0x2acd"
+    uuid: String,
+    name: Option<&'static str>,
+    service_uuid: String,
+    service_name: Option<&'static str>,
+    read: bool,
+    write: bool,
+    write_without_response: bool,
+    notify: bool,
+    indicate: bool,
+}
+
+impl From<&Characteristic> for CharacteristicInfo {
+    fn from(characteristic: &Characteristic) -> CharacteristicInfo {
+        let properties = characteristic.properties;
+        CharacteristicInfo {
+            uuid: characteristic.uuid.to_short_string(),
+            name: known_uuid_name(characteristic.uuid),
+            service_uuid: characteristic.service_uuid.to_short_string(),
+            service_name: known_uuid_name(characteristic.service_uuid),
+            read: properties.contains(CharPropFlags::READ),
+            write: properties.contains(CharPropFlags::WRITE),
+            write_without_response: properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE),
+            notify: properties.contains(CharPropFlags::NOTIFY),
+            indicate: properties.contains(CharPropFlags::INDICATE),
+        }
+    }
+}
+
+// For diagnosing devices that don't work, lists what the connected device exposed.
+#[tauri::command]
+fn list_characteristics(state: State<'_, AppState>) -> Result<Vec<CharacteristicInfo>, CommandError> {
+    state.connection()?.peripheral()?;
+    state
+        .characteristics
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| CommandError::NotSupported("Characteristics before service discovery".to_string()))
+}
+
+// btleplug picks notifications or indications from the characteristic's properties, so all we can
+// do is make sure at least one of them is there and say which will be used.
+async fn subscribe(peripheral: &Peripheral, characteristic: &Characteristic, name: &str) -> Result<(), CommandError> {
+    let properties = characteristic.properties;
+    if properties.contains(CharPropFlags::INDICATE) {
+        println!("Subscribing to {} indications.", name);
+    } else if properties.contains(CharPropFlags::NOTIFY) {
+        println!("Subscribing to {} notifications.", name);
+    } else {
+        eprintln!("{} supports neither notify nor indicate: {:?}", name, properties);
+        return Err(CommandError::NotSupported(format!("{} notifications", name)));
+    }
+
+    ble_with_retry(&format!("subscribing to {}", name), || peripheral.subscribe(characteristic)).await
+}
+
+// Spawns the consumer of an already opened notification stream and only then subscribes, so frames
+// a device sends straight after subscribing queue up on the stream instead of arriving before
+// anything reads it. The consumer is stopped again if subscribing fails.
+async fn consume_before_subscribing(
+    consumer: impl Future<Output = ()> + Send + 'static,
+    subscriptions: impl Future<Output = Result<(), CommandError>>,
+) -> Result<JoinHandle<()>, CommandError> {
+    let task = tauri::async_runtime::spawn(consumer);
+    if let Err(e) = subscriptions.await {
+        task.abort();
+        return Err(e);
+    }
+    Ok(task)
+}
+
+// Subscribing and opening the notification stream can fail right after connecting on some
+// platforms and work a moment later.
+async fn ble_with_retry<T, F: Future<Output = Result<T, btleplug::Error>>>(
+    what: &str,
+    mut operation: impl FnMut() -> F,
+) -> Result<T, CommandError> {
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < SUBSCRIBE_ATTEMPTS => {
+                eprintln!("Error {} (attempt {}/{}): {:?}", what, attempt, SUBSCRIBE_ATTEMPTS, e);
+                time::sleep(SUBSCRIBE_RETRY_DELAY).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                eprintln!("Error {}, giving up after {} attempts: {:?}", what, SUBSCRIBE_ATTEMPTS, e);
+                return Err(CommandError::BleError(format!("{} failed: {}", what, e)));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct ConnectRetry {
+    attempt: u32,
+    max_attempts: u32,
+    error: String,
+}
+
+// Some platforms report a connect as done before the link is up, or when it never comes up at all,
+// so wait for the peripheral to say it's connected before discovering services on it.
+async fn connection_verified(treadmill: &Peripheral) -> bool {
+    wait_until_connected(|| treadmill.is_connected(), CONNECT_VERIFY_TIMEOUT, CONNECT_VERIFY_POLL_INTERVAL).await
+}
+
+// Polls until the link reports connected, counting check errors as not connected.
+async fn wait_until_connected<F: Future<Output = Result<bool, btleplug::Error>>>(
+    mut is_connected: impl FnMut() -> F,
+    timeout: Duration,
+    poll_interval: Duration,
+) -> bool {
+    let started = Instant::now();
+    loop {
+        match is_connected().await {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(e) => eprintln!("Error checking connection: {:?}", e),
+        }
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        time::sleep(poll_interval).await;
+    }
+}
+
+// First connects fail often on some adapters and succeed straight after, so give it a few tries,
+// waiting a little longer each time.
+async fn connect_with_retry(app: &AppHandle, treadmill: &Peripheral) -> Result<(), CommandError> {
+    let mut attempt = 1;
+    loop {
+        let e = match treadmill.connect().await {
+            Ok(_) if connection_verified(treadmill).await => {
+                println!("Connected to treadmill.");
+                return Ok(());
+            }
+            Ok(_) => {
+                let e = format!("connect succeeded but the treadmill wasn't connected within {:?}", CONNECT_VERIFY_TIMEOUT);
+                if let Err(e) = app.emit_all("connect-verification-failed", e.clone()) {
+                    eprintln!("Error emitting connect verification failure: {:?}", e);
+                }
+                e
+            }
+            Err(btleplug::Error::PermissionDenied) => return Err(CommandError::PermissionDenied),
+            Err(e) => e.to_string(),
+        };
+
+        eprintln!("Error connecting to treadmill (attempt {}/{}): {:?}", attempt, CONNECT_ATTEMPTS, e);
+        if attempt == CONNECT_ATTEMPTS {
+            return Err(CommandError::ConnectFailed(format!("{} attempts, last error: {}", attempt, e)));
+        }
+
+        let retry = ConnectRetry { attempt, max_attempts: CONNECT_ATTEMPTS, error: e };
+        if let Err(e) = app.emit_all("connect-retry", retry) {
+            eprintln!("Error emitting connect retry: {:?}", e);
+        }
+        time::sleep(CONNECT_RETRY_DELAY * attempt).await;
+        attempt += 1;
+    }
+}
+
+// Discovery can finish with nothing found when it runs too soon after connecting on some
+// platforms, so try again a few times before deciding the device really has no services.
+async fn discover_characteristics(treadmill: &Peripheral) -> Result<BTreeSet<Characteristic>, CommandError> {
+    for attempt in 1..=SERVICE_DISCOVERY_ATTEMPTS {
+        treadmill.discover_services().await?;
+        let characteristics = treadmill.characteristics();
+        if !characteristics.is_empty() {
+            return Ok(characteristics);
+        }
+
+        eprintln!("No characteristics discovered (attempt {}/{}).", attempt, SERVICE_DISCOVERY_ATTEMPTS);
+        time::sleep(SERVICE_DISCOVERY_DELAY).await;
+    }
+
+    Err(CommandError::BleError(format!(
+        "Treadmill reported no services after {} attempts.",
+        SERVICE_DISCOVERY_ATTEMPTS
+    )))
+}
+
+// Picks the profile for `treadmill` from its model number or name, honouring the one forced in
+// the settings.
+async fn apply_device_profile(app: &AppHandle, state: &AppState, treadmill: &Peripheral) -> Result<(), CommandError> {
+    let local_name = treadmill.properties().await?.and_then(|p| p.local_name);
+    let model = match read_device_info(treadmill).await {
+        Ok(info) => info.model,
+        Err(e) => {
+            eprintln!("Error reading device information: {:?}", e);
+            None
+        }
+    };
+    let forced = state.settings.lock().unwrap().device_profile.clone();
+    let profile = device_profile::select(&[model.as_deref(), local_name.as_deref()], forced.as_deref());
+
+    println!("Using the {} device profile for {:?} ({:?}).", profile.name, local_name, model);
+    *state.device_profile.lock().unwrap() = Some(profile);
+    if let Err(e) = app.emit_all("profile-selected", profile.name) {
+        eprintln!("Error emitting profile selection: {:?}", e);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn list_device_profiles() -> Result<&'static [DeviceProfile], CommandError> {
+    Ok(device_profile::PROFILES)
+}
+
+// Forces a profile for hardware that isn't matched by name, `None` goes back to matching by name.
+// Applies to the connected treadmill straight away and is saved for later connections.
+#[tauri::command]
+async fn set_device_profile(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    name: Option<String>,
+) -> Result<(), CommandError> {
+    let settings = Settings { device_profile: name, ..state.settings.lock().unwrap().clone() };
+    apply_settings(&app, &state, settings)?;
+
+    let peripheral = state.connection().ok().and_then(|c| c.peripheral().ok().cloned());
+    if let Some(treadmill) = peripheral {
+        apply_device_profile(&app, &state, &treadmill).await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct MachineTypesDiscovered {
+    available: Vec<MachineType>,
+    selected: MachineType,
+}
+
+// Picks which data a machine exposing several kinds sends, `None` prefers treadmill data. Takes
+// effect straight away when connected.
+#[tauri::command]
+async fn set_machine_type(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    machine_type: Option<MachineType>,
+) -> Result<(), CommandError> {
+    let settings = Settings { machine_type, ..state.settings.lock().unwrap().clone() };
+    apply_settings(&app, &state, settings)?;
+    let treadmill = match state.connection() {
+        Ok(connection) => connection.peripheral()?.clone(),
+        Err(_) => return Ok(()),
+    };
+    setup_services(&app, &state, &treadmill).await
+}
+
+// The user's choice when the machine exposes it, treadmill data otherwise.
+fn select_machine_type(available: &[MachineType], preferred: Option<MachineType>) -> Option<MachineType> {
+    available.iter().copied().min_by_key(|t| (Some(*t) != preferred, *t != MachineType::Treadmill))
+}
+
+// Discovers services, resolves the FTMS characteristics and subscribes to them. Runs on the first
+// connect and again after a reconnect, since cached characteristics and subscriptions don't survive
+// the BLE stack reconnecting underneath us.
+async fn setup_services(app: &AppHandle, state: &AppState, treadmill: &Peripheral) -> Result<(), CommandError> {
+    let characteristics = discover_characteristics(treadmill).await?;
+    *state.characteristics.lock().unwrap() = Some(characteristics.iter().map(CharacteristicInfo::from).collect());
+    // Multi-function machines expose a data characteristic per type. Only the chosen one is
+    // subscribed to, the user's choice when it's there and treadmill data otherwise.
+    let data_chars: Vec<_> = characteristics
+        .iter()
+        .filter_map(|c| MachineType::from_data_characteristic(c.uuid).map(|t| (t, c)))
+        .collect();
+    let available: Vec<_> = data_chars.iter().map(|(t, _)| *t).collect();
+    let preferred = state.settings.lock().unwrap().machine_type;
+    let (machine_type, char) = select_machine_type(&available, preferred)
+        .and_then(|selected| data_chars.iter().copied().find(|(t, _)| *t == selected))
+        .ok_or_else(|| CommandError::NotSupported("Treadmill or Indoor Bike Data characteristic".to_string()))?;
+    println!("Connected machine is a {:?}.", machine_type);
+    let discovered = MachineTypesDiscovered { available, selected: machine_type };
+    if let Err(e) = app.emit_all("machine-types-discovered", discovered) {
+        eprintln!("Error emitting machine types: {:?}", e);
+    }
+    let previous_type = state.machine_type.lock().unwrap().replace(machine_type);
+    let control_char = characteristics
+        .iter()
+        .find(|c| c.uuid == TREADMILL_CONTROL_CHARACTERISTIC_UUID)
+        .ok_or_else(|| CommandError::NotSupported("Fitness Machine Control Point".to_string()))?;
+    let (responses, _) = broadcast::channel(16);
+    let connection = TreadmillConnection {
+        transport: Transport::Ble { peripheral: treadmill.clone(), control_point: control_char.clone() },
+        responses: responses.clone(),
+        interlock: state.interlock.clone(),
+        control_granted: state.control_granted.clone(),
+        procedure_timeout: state.settings.lock().unwrap().procedure_timeout(),
+    };
+    *state.treadmill.lock().unwrap() = Some(connection);
+    *state.capabilities.lock().unwrap() = None;
+    *state.data_fields.lock().unwrap() = None;
+    *state.control_granted.lock().unwrap() = false;
+    state.control_point_buffer.lock().unwrap().clear();
+    state.interlock.lock().unwrap().reset();
+    apply_device_profile(app, state, treadmill).await?;
+
+    let mut sub = ble_with_retry("opening the notification stream", || treadmill.notifications()).await?;
+    let notification_app = app.clone();
+    let first_frame = Arc::new(Notify::new());
+    let data_uuid = char.uuid;
+    let stall_timeout = state.settings.lock().unwrap().stall_timeout();
+    let consumer = {
+        let first_frame = first_frame.clone();
+        async move {
+            // Data frames have to keep coming within the timeout
+            let mut watchdog = StallWatchdog::new(stall_timeout);
+            loop {
+                let notification = match watchdog.next(&mut sub).await {
+                    Watched::Next(notification) => notification,
+                    Watched::Ended => break,
+                    Watched::Stalled => {
+                        eprintln!("No machine data for {:?}.", stall_timeout);
+                        if let Err(e) = notification_app.emit_all("data-stream-stalled", stall_timeout.as_secs()) {
+                            eprintln!("Error emitting data stream stalled: {:?}", e);
+                        }
+                        continue;
+                    }
+                };
+                if notification.uuid == data_uuid {
+                    first_frame.notify_one();
+                    if watchdog.frame() {
+                        println!("Machine data is arriving again.");
+                        if let Err(e) = notification_app.emit_all("data-stream-resumed", ()) {
+                            eprintln!("Error emitting data stream resumed: {:?}", e);
+                        }
+                    }
+                    if let Err(e) = notification_app.emit_all("data-heartbeat", ()) {
+                        eprintln!("Error emitting data heartbeat: {:?}", e);
+                    }
+                }
+                handle_notification(&notification_app, &responses, notification.uuid, &notification.value);
+            }
+        }
+    };
+    if let Some(previous) = state.notifications.lock().unwrap().take() {
+        previous.abort();
+    }
+
+    let subscriptions = async {
+        // Switching types leaves the other data characteristic notifying otherwise
+        if let Some((_, previous)) = data_chars.iter().find(|(t, _)| Some(*t) == previous_type && *t != machine_type) {
+            if let Err(e) = treadmill.unsubscribe(previous).await {
+                eprintln!("Error unsubscribing from {:?} data: {:?}", previous_type, e);
+            }
+        }
+        subscribe(treadmill, char, "Machine Data").await?;
+        subscribe(treadmill, control_char, "Fitness Machine Control Point").await?;
+        // Machine status only tells us about clamped targets, so it's optional too
+        if let Some(status_char) = characteristics.iter().find(|c| c.uuid == MACHINE_STATUS_CHARACTERISTIC_UUID) {
+            if let Err(e) = subscribe(treadmill, status_char, "Fitness Machine Status").await {
+                eprintln!("Error subscribing to machine status: {:?}", e);
+            }
+        }
+        // Battery level is optional, a device without it or without notifications is still usable
+        if let Some(battery_char) = characteristics.iter().find(|c| c.uuid == BATTERY_LEVEL_CHARACTERISTIC_UUID) {
+            if let Err(e) = subscribe(treadmill, battery_char, "Battery Level").await {
+                eprintln!("Error subscribing to battery level: {:?}", e);
+            }
+        }
+        Ok(())
+    };
+    let notifications = consume_before_subscribing(consumer, subscriptions).await?;
+    *state.notifications.lock().unwrap() = Some(notifications);
+
+    // Some machines stay quiet while the belt is stopped, so silence is only reported
+    if time::timeout(FIRST_FRAME_TIMEOUT, first_frame.notified()).await.is_err() {
+        eprintln!("No machine data within {:?} of subscribing.", FIRST_FRAME_TIMEOUT);
+        if let Err(e) = app.emit_all("notifications-silent", FIRST_FRAME_TIMEOUT.as_secs()) {
+            eprintln!("Error emitting notifications silent: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn refresh_services(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let treadmill = state.connection()?.peripheral()?.clone();
+    setup_services(&app, &state, &treadmill).await?;
+    println!("Services refreshed.");
+    if let Err(e) = app.emit_all("services-refreshed", ()) {
+        eprintln!("Error emitting services refreshed: {:?}", e);
+    }
+    Ok(())
+}
+
+// btleplug doesn't report the adapter's power state, but scanning fails while it's off.
+async fn adapter_powered_on(central: &Adapter) -> bool {
+    match central.start_scan(ScanFilter::default()).await {
+        Ok(()) => {
+            if let Err(e) = central.stop_scan().await {
+                eprintln!("Error stopping adapter probe scan: {:?}", e);
+            }
+            true
+        }
+        Err(e) => !matches!(CommandError::from(e), CommandError::AdapterPoweredOff),
+    }
+}
+
+// Drops the connection and everything running on it. The belt can't be stopped without Bluetooth,
+// so the frontend is told to ask the user to stop it by hand.
+fn handle_adapter_powered_off(app: &AppHandle) {
+    interrupt_workout(app);
+    let state = app.state::<AppState>();
+    *state.treadmill.lock().unwrap() = None;
+    for task in [&state.workout_runner, &state.notifications, &state.signal_monitor] {
+        if let Some(task) = task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+    *state.capabilities.lock().unwrap() = None;
+    *state.control_granted.lock().unwrap() = false;
+    state.interlock.lock().unwrap().reset();
+    if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+        log.marker("adapter-off");
+    }
+    if let Err(e) = close_session_log(&state) {
+        eprintln!("Error saving session log: {:?}", e);
+    }
+
+    println!("Bluetooth adapter powered off, treadmill disconnected.");
+    if let Err(e) = app.emit_all("adapter-powered-off", ()) {
+        eprintln!("Error emitting adapter powered off: {:?}", e);
+    }
+}
+
+// Refreshes services whenever the adapter reports the treadmill connected again. A disconnect
+// with the adapter powered off ends the session instead, and once the adapter is back on the
+// frontend is told it can reconnect.
+fn spawn_reconnect_watcher(app: AppHandle, central: Adapter, treadmill: Peripheral) -> JoinHandle<()> {
+    tauri::async_runtime::spawn(async move {
+        let mut events = match central.events().await {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("Error listening for adapter events: {:?}", e);
+                return;
+            }
+        };
+
+        while let Some(event) = events.next().await {
+            let id = match event {
+                CentralEvent::DeviceDisconnected(id) if id == treadmill.id() => {
+                    if adapter_powered_on(&central).await {
+                        interrupt_workout(&app);
+                        continue;
+                    }
+                    handle_adapter_powered_off(&app);
+                    while !adapter_powered_on(&central).await {
+                        time::sleep(ADAPTER_POLL_INTERVAL).await;
+                    }
+                    println!("Bluetooth adapter powered on again.");
+                    if let Err(e) = app.emit_all("adapter-powered-on", ()) {
+                        eprintln!("Error emitting adapter powered on: {:?}", e);
+                    }
+                    return;
+                }
+                CentralEvent::DeviceConnected(id) => id,
+                _ => continue,
+            };
+            if id != treadmill.id() {
+                continue;
+            }
+
+            println!("Treadmill reconnected, refreshing services.");
+            let state = app.state::<AppState>();
+            if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+                log.marker("reconnect");
+            }
+            match setup_services(&app, &state, &treadmill).await {
+                Ok(_) => {
+                    if let Err(e) = app.emit_all("services-refreshed", ()) {
+                        eprintln!("Error emitting services refreshed: {:?}", e);
+                    }
+                    offer_workout_resume(&app).await;
+                }
+                Err(e) => eprintln!("Error refreshing services: {:?}", e),
+            }
+        }
+    })
+}
+
+// Runs `future` unless `cancel_connect` fires first.
+async fn cancellable<T>(
+    cancel: &Notify,
+    future: impl Future<Output = Result<T, CommandError>>,
+) -> Result<T, CommandError> {
+    let future = pin!(future);
+    let cancelled = pin!(cancel.notified());
+    match future::select(future, cancelled).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(CommandError::Cancelled),
+    }
+}
+
+// Undoes whatever a cancelled connect got through, so no half open connection is left behind.
+async fn abandon_connect(state: &AppState, central: &Adapter, treadmill: Option<&Peripheral>) {
+    if let Err(e) = central.stop_scan().await {
+        eprintln!("Error stopping scan: {:?}", e);
+    }
+    let Some(treadmill) = treadmill else {
+        return;
+    };
+
+    {
+        let mut current = state.treadmill.lock().unwrap();
+        if current.as_ref().is_some_and(|c| c.peripheral().is_ok_and(|p| p.id() == treadmill.id())) {
+            *current = None;
+            if let Some(notifications) = state.notifications.lock().unwrap().take() {
+                notifications.abort();
+            }
+        }
+    }
+    if let Err(e) = treadmill.disconnect().await {
+        eprintln!("Error disconnecting: {:?}", e);
+    }
+}
+
+async fn scan_for_treadmill(
+    state: &AppState,
+    central: &Adapter,
+    cancel: &Notify,
+) -> Result<Peripheral, CommandError> {
+    let filter = ScanFilter { services: vec![FITNESS_MACHINE_SERVICE_UUID] };
+    match central.start_scan(filter).await {
+        Ok(_) => println!("Scanning for devices..."),
+        Err(btleplug::Error::PermissionDenied) => {
+            eprintln!("Bluetooth permission denied while scanning.");
+            return Err(CommandError::PermissionDenied);
+        }
+        Err(e) => eprintln!("Error scanning: {:?}", e),
+    }
+
+    let found = cancellable(cancel, async {
+        time::sleep(SCAN_TIME).await;
+        Ok(find_treadmill(central).await)
+    });
+    match found.await {
+        Ok(Some(p)) => Ok(p),
+        Ok(None) => {
+            eprintln!("Treadmill not found.");
+            Err(CommandError::DeviceNotFound)
+        }
+        Err(e) => {
+            println!("Connect cancelled while scanning.");
+            abandon_connect(state, central, None).await;
+            Err(e)
+        }
+    }
+}
+
+const SCAN_TIME: Duration = Duration::from_secs(2);
+const SCAN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Scans on every adapter at once and takes the treadmill from whichever sees it first, for systems
+// with more than one radio. Returns that adapter with it.
+async fn scan_all_adapters(cancel: &Notify) -> Result<(Adapter, Peripheral), CommandError> {
+    let adapters = Manager::new().await?.adapters().await?;
+    if adapters.is_empty() {
+        return Err(CommandError::BleError("No Bluetooth adapters found.".to_string()));
+    }
+    let filter = ScanFilter { services: vec![FITNESS_MACHINE_SERVICE_UUID] };
+    for (index, adapter) in adapters.iter().enumerate() {
+        match adapter.start_scan(filter.clone()).await {
+            Ok(_) => println!("Scanning for devices on adapter {}...", index),
+            Err(btleplug::Error::PermissionDenied) => return Err(CommandError::PermissionDenied),
+            Err(e) => eprintln!("Error scanning on adapter {}: {:?}", index, e),
+        }
+    }
+
+    let found = cancellable(cancel, async {
+        let found = first_adapter_to_find(&adapters, find_treadmill, SCAN_TIME, SCAN_POLL_INTERVAL).await;
+        Ok(found.map(|(index, p)| {
+            println!("Adapter {} found the treadmill.", index);
+            (adapters[index].clone(), p)
+        }))
+    })
+    .await;
+    for adapter in &adapters {
+        if let Err(e) = adapter.stop_scan().await {
+            eprintln!("Error stopping scan: {:?}", e);
+        }
+    }
+    match found? {
+        Some(found) => Ok(found),
+        None => {
+            eprintln!("Treadmill not found on any adapter.");
+            Err(CommandError::DeviceNotFound)
+        }
+    }
+}
+
+// Asks each adapter in turn every `poll_interval` until one finds what `find` looks for or
+// `scan_time` is up. Returns the index of the adapter that found it.
+async fn first_adapter_to_find<'a, A, T, F: Future<Output = Option<T>>>(
+    adapters: &'a [A],
+    mut find: impl FnMut(&'a A) -> F,
+    scan_time: Duration,
+    poll_interval: Duration,
+) -> Option<(usize, T)> {
+    let started = Instant::now();
+    while started.elapsed() < scan_time {
+        time::sleep(poll_interval).await;
+        for (index, adapter) in adapters.iter().enumerate() {
+            if let Some(found) = find(adapter).await {
+                return Some((index, found));
+            }
+        }
+    }
+    None
+}
+
+async fn default_adapter() -> Result<Adapter, CommandError> {
+    let manager = Manager::new().await?;
+    match manager.adapters().await?.into_iter().next() {
+        Some(a) => Ok(a),
+        None => {
+            eprintln!("Unable to find adapters.");
+            Err(CommandError::BleError("No Bluetooth adapters found.".to_string()))
+        }
+    }
+}
+
+async fn connect(app: &AppHandle, state: &AppState, cancel: &Notify) -> Result<(), CommandError> {
+    let scan_all = state.settings.lock().unwrap().scan_all_adapters;
+    // Scanning every adapter makes the one that found the treadmill last time the active one
+    let active = state.central.lock().unwrap().clone().filter(|_| scan_all);
+    let mut central = match active {
+        Some(central) => central,
+        None => default_adapter().await?,
+    };
+
+    // Go straight to the treadmill we connected to before when the adapter still knows it
+    let known_id = state.last_peripheral_id.lock().unwrap().clone();
+    let mut known = None;
+    if let Some(id) = known_id {
+        match central.peripheral(&id).await {
+            Ok(p) => known = Some(p),
+            Err(btleplug::Error::DeviceNotFound) => {
+                println!("Adapter no longer knows {:?}, scanning instead.", id);
+                if let Err(e) = app.emit_all("reconnect-fallback-scan", ()) {
+                    eprintln!("Error emitting reconnect fallback: {:?}", e);
                 }
             }
-            println!("Notification: {:?}", notification);
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let treadmill = match known {
+        Some(p) => p,
+        None if scan_all => {
+            let (adapter, p) = scan_all_adapters(cancel).await?;
+            central = adapter;
+            p
+        }
+        None => scan_for_treadmill(state, &central, cancel).await?,
+    };
+
+    let connected = cancellable(cancel, async {
+        connect_with_retry(app, &treadmill).await?;
+        setup_services(app, state, &treadmill).await
+    });
+    if let Err(e) = connected.await {
+        if matches!(e, CommandError::Cancelled) {
+            println!("Connect cancelled while connecting.");
+            abandon_connect(state, &central, Some(&treadmill)).await;
+        }
+        return Err(e);
+    }
+
+    *state.central.lock().unwrap() = Some(central.clone());
+    *state.last_peripheral_id.lock().unwrap() = Some(treadmill.id());
+    restart_signal_monitor(
+        app,
+        state,
+        treadmill.clone(),
+        Duration::from_millis(DEFAULT_SIGNAL_MONITOR_INTERVAL_MS),
+    );
+    let watcher = spawn_reconnect_watcher(app.clone(), central.clone(), treadmill.clone());
+    if let Some(previous) = state.reconnect_watcher.lock().unwrap().replace(watcher) {
+        previous.abort();
+    }
+    Ok(())
+}
+
+// Connects the first heart rate strap found, replacing any connected before. The treadmill
+// connection is left alone.
+#[tauri::command]
+async fn connect_heart_rate_strap(app: AppHandle, state: State<'_, AppState>) -> Result<(), CommandError> {
+    let central = state.central.lock().unwrap().clone();
+    let central = match central {
+        Some(central) => central,
+        None => default_adapter().await?,
+    };
+    let strap = heart_rate_strap::connect(&app, &central).await?;
+    let previous = state.heart_rate_strap.lock().unwrap().replace(strap);
+    if let Some(previous) = previous {
+        previous.disconnect().await;
+    }
+    println!("Heart rate strap connected.");
+    if let Err(e) = app.emit_all("heart-rate-strap-connected", ()) {
+        eprintln!("Error emitting heart rate strap connected: {:?}", e);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn disconnect_heart_rate_strap(state: State<'_, AppState>) -> Result<(), CommandError> {
+    let strap = state.heart_rate_strap.lock().unwrap().take();
+    *state.strap_heart_rate.lock().unwrap() = None;
+    if let Some(strap) = strap {
+        strap.disconnect().await;
+    }
+    Ok(())
+}
+
+// Disconnects the treadmill and stops everything running on it, leaving a heart rate strap
+// connected.
+#[tauri::command]
+async fn disconnect_treadmill(state: State<'_, AppState>) -> Result<(), CommandError> {
+    for task in [&state.workout_runner, &state.notifications, &state.signal_monitor, &state.reconnect_watcher] {
+        if let Some(task) = task.lock().unwrap().take() {
+            task.abort();
         }
-    });
+    }
+    let connection = state.treadmill.lock().unwrap().take();
+    *state.capabilities.lock().unwrap() = None;
+    *state.control_granted.lock().unwrap() = false;
+    state.interlock.lock().unwrap().reset();
+    match connection.map(|c| c.transport) {
+        Some(Transport::Ble { peripheral, .. }) => peripheral.disconnect().await?,
+        Some(Transport::Simulator(simulator)) => simulator.stop(),
+        #[cfg(test)]
+        Some(Transport::Mock(_)) => {}
+        None => return Err(CommandError::NotConnected),
+    }
+    println!("Treadmill disconnected.");
+    Ok(())
+}
+
+// Aborts a `connect_to_treadmill` in progress, which then returns `Cancelled`.
+#[tauri::command]
+fn cancel_connect(state: State<'_, AppState>) -> Result<(), CommandError> {
+    if let Some(cancel) = state.connect_cancel.lock().unwrap().as_ref() {
+        println!("Cancelling connect.");
+        cancel.notify_one();
+    }
+    Ok(())
+}
 
-    time::sleep(Duration::from_secs(5)).await;
+// Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
+#[tauri::command]
+async fn connect_to_treadmill(app: AppHandle, state: State<'_, AppState>, name: String) -> Result<String, CommandError> {
+    let cancel = Arc::new(Notify::new());
+    if let Some(previous) = state.connect_cancel.lock().unwrap().replace(cancel.clone()) {
+        previous.notify_one();
+    }
+    let result = connect(&app, &state, &cancel).await;
+    {
+        let mut current = state.connect_cancel.lock().unwrap();
+        if current.as_ref().is_some_and(|c| Arc::ptr_eq(c, &cancel)) {
+            *current = None;
+        }
+    }
+    result?;
 
-    let control_char = characteristics.iter().find(|c| c.uuid == TREADMILL_CONTROL_CHARACTERISTIC_UUID).unwrap();
-    treadmill.write(control_char, &treadmill_command_to_message(TreadmillCommands::RequestControl), WriteType::WithoutResponse).await.unwrap();
-    time::sleep(Duration::from_secs(5)).await;
-    treadmill.write(control_char, &treadmill_command_to_message(TreadmillCommands::StartOrResume), WriteType::WithoutResponse).await.unwrap();
-    treadmill.write(control_char, &treadmill_command_to_message(TreadmillCommands::SetTargetSpeed(200)), WriteType::WithoutResponse).await.unwrap();
+    // Only take control, the belt doesn't move until `start_treadmill` or a workout asks it to
+    acquire_control(&app).await?;
+    offer_workout_resume(&app).await;
 
     Ok(format!("Hello, {}! You've been greeted from Rust!", name))
 }
 
+// Pauses the belt when the window loses focus or is minimized and, if asked to, resumes it once the
+// window is focused again. Both are off unless turned on in the settings.
+fn handle_window_event(event: GlobalWindowEvent) {
+    let window = event.window();
+    let pause = match event.event() {
+        WindowEvent::Focused(focused) => !focused,
+        WindowEvent::Resized(_) if window.is_minimized().unwrap_or(false) => true,
+        _ => return,
+    };
+
+    let app = window.app_handle();
+    let state = app.state::<AppState>();
+    let settings = state.settings.lock().unwrap().clone();
+    let (command, event) = {
+        let mut blur_paused = state.blur_paused.lock().unwrap();
+        if pause {
+            let moving = state.latest_data.lock().unwrap().as_ref().is_some_and(|data| data.speed > 0);
+            if !settings.pause_on_blur || *blur_paused || !moving {
+                return;
+            }
+            *blur_paused = true;
+            (TreadmillCommands::StopOrPause, "blur-paused")
+        } else {
+            if !*blur_paused {
+                return;
+            }
+            *blur_paused = false;
+            if !settings.resume_on_focus {
+                return;
+            }
+            (TreadmillCommands::StartOrResume, "focus-resumed")
+        }
+    };
+
+    let Ok(connection) = state.connection() else {
+        return;
+    };
+    println!("Window focus changed, sending {:?}.", command);
+    if let Err(e) = app.emit_all(event, ()) {
+        eprintln!("Error emitting {}: {:?}", event, e);
+    }
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = connection.send_commands(vec![command]).await {
+            eprintln!("Error sending {:?} on focus change: {:?}", command, e);
+        }
+    });
+}
+
 fn main() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![connect_to_treadmill, read_workouts])
+        .manage(AppState::default())
+        .setup(|app| {
+            let settings = settings::load(&app.handle());
+            println!("Loaded settings: {:?}", settings);
+            let state = app.state::<AppState>();
+            state.interlock.lock().unwrap().set_window(settings.arm_window());
+            *state.restorable.lock().unwrap() = snapshot::load(&app.handle()).filter(Snapshot::worth_restoring);
+            *state.settings.lock().unwrap() = settings;
+            session_log::close_interrupted(&app.handle());
+            Ok(())
+        })
+        .on_window_event(handle_window_event)
+        .invoke_handler(tauri::generate_handler![
+            connect_to_treadmill,
+            read_workouts,
+            convert_pace,
+            start_signal_monitor,
+            set_targets,
+            get_machine_capabilities,
+            set_machine_type,
+            start_simulator,
+            stop_simulator,
+            import_workout_from_text,
+            set_heart_rate_limit,
+            set_heart_rate_zone,
+            refresh_services,
+            workout_profile,
+            describe_data_fields,
+            reset_treadmill,
+            start_workout,
+            set_speed_ramp,
+            get_battery_level,
+            validate_workout_against_machine,
+            workout_json_schema,
+            reset_session_distance,
+            get_distance_calibration,
+            get_running_metrics,
+            get_lap_data,
+            start_data_server,
+            stop_data_server,
+            replay_session,
+            pause_replay,
+            resume_replay,
+            stop_replay,
+            set_max_session_duration,
+            list_characteristics,
+            set_inclination_degrees,
+            get_settings,
+            update_settings,
+            measure_control_latency,
+            start_session_log,
+            end_session_log,
+            get_session_stats,
+            expand_workout,
+            supported_commands,
+            cancel_connect,
+            set_target_speed,
+            request_control,
+            save_current_as_workout,
+            list_device_profiles,
+            set_device_profile,
+            start_treadmill,
+            self_test,
+            get_workout,
+            export_workouts,
+            import_workouts,
+            check_workouts,
+            stop_workout,
+            resume_workout,
+            workout_remaining,
+            get_device_info,
+            preview_command,
+            arm_treadmill,
+            restore_state,
+            list_workouts,
+            list_sessions,
+            load_session,
+            read_characteristic,
+            write_characteristic,
+            connect_heart_rate_strap,
+            disconnect_heart_rate_strap,
+            disconnect_treadmill
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_step(duration: &str, pace: PaceRaw) -> WorkoutStepRaw {
+        WorkoutStepRaw::Run { name: "run".to_string(), duration: duration.to_string(), pace, angle: 0 }
+    }
+
+    fn workout(steps: Vec<WorkoutStepRaw>) -> WorkoutRaw {
+        WorkoutRaw { name: "test".to_string(), description: String::new(), steps }
+    }
+
+    #[test]
+    fn warm_up_pushing_a_workout_past_the_longest_duration_is_rejected() {
+        let state = AppState::default();
+        state.settings.lock().unwrap().warm_up = Some(ExtraStep { duration: 300, pace: 600 });
+        let raw = workout(vec![run_step("1090:00", PaceRaw::Kph("10".to_string()))]);
+        assert_eq!(prepare_workout(&state, &raw, Some(false)).unwrap().duration, 65400);
+        let result = prepare_workout(&state, &raw, None);
+        assert!(matches!(result, Err(CommandError::WorkoutParse(_))), "{:?}", result.map(|w| w.duration));
+    }
+
+    #[test]
+    fn step_distance_is_in_meters() {
+        // 10 minutes at 12 km/h
+        assert_eq!(step_distance(1200, 600), 2000);
+        assert_eq!(step_distance(0, 600), 0);
+    }
+
+    #[test]
+    fn step_distance_rounds_and_does_not_overflow() {
+        // 13.9m, truncating would give 13
+        assert_eq!(step_distance(1000, 5), 14);
+        assert_eq!(step_distance(u16::MAX, u16::MAX), 11_930_101);
+    }
+
+    #[test]
+    fn repeated_step_distances_do_not_drift() {
+        let step = run_step("0:05", PaceRaw::Kph("10".to_string()));
+        let raw = workout(vec![WorkoutStepRaw::Repeat { times: 10, steps: vec![step] }]);
+        let workout = parse_workout(&raw).unwrap();
+        assert_eq!(workout.steps.len(), 10);
+        assert_eq!(workout.distance, 140);
+        assert_eq!(workout.duration, 50);
+    }
+
+    #[test]
+    fn speeds_are_scaled_and_paces_inverted() {
+        assert_eq!(parse_pace(&PaceRaw::Kph("10.5".to_string())).unwrap(), 1050);
+        assert_eq!(parse_pace(&PaceRaw::Mph("6".to_string())).unwrap(), 966);
+        assert_eq!(parse_pace(&PaceRaw::MinPerKm("6:00".to_string())).unwrap(), 1000);
+        assert_eq!(parse_pace(&PaceRaw::MinPerMi("10:00".to_string())).unwrap(), 966);
+        // The same number means opposite things as a speed and as a pace
+        assert_eq!(parse_pace(&PaceRaw::Kph("10".to_string())).unwrap(), 1000);
+        assert_eq!(parse_pace(&PaceRaw::MinPerKm("10".to_string())).unwrap(), 600);
+    }
+
+    #[test]
+    fn speed_units_deserialize_from_workout_json() {
+        let pace: PaceRaw = serde_json::from_str(r#"{ "unit": "kph", "value": "10.5" }"#).unwrap();
+        assert!(matches!(pace, PaceRaw::Kph(ref value) if value == "10.5"));
+        let pace: PaceRaw = serde_json::from_str(r#"{ "unit": "mph", "value": "6" }"#).unwrap();
+        assert!(matches!(pace, PaceRaw::Mph(_)));
+        assert!(parse_pace(&PaceRaw::Kph("fast".to_string())).is_err());
+        assert!(parse_pace(&PaceRaw::Kph("-1".to_string())).is_err());
+    }
+
+    #[test]
+    fn profile_has_a_point_per_step_boundary() {
+        let fast = WorkoutStepRaw::Run {
+            name: "fast".to_string(),
+            duration: "1:00".to_string(),
+            pace: PaceRaw::Kph("10".to_string()),
+            angle: 20,
+        };
+        let easy = run_step("0:30", PaceRaw::Kph("6".to_string()));
+        let raw = workout(vec![WorkoutStepRaw::Repeat { times: 2, steps: vec![fast, easy] }]);
+        let profile = parse_workout(&raw).unwrap().profile();
+
+        assert_eq!(profile.speed, vec![(0, 1000), (60, 600), (90, 1000), (150, 600), (180, 600)]);
+        assert_eq!(profile.inclination, vec![(0, 20), (60, 0), (90, 20), (150, 0), (180, 0)]);
+    }
+
+    #[test]
+    fn force_on_belt_and_power_output_decode_when_only_they_are_flagged() {
+        let mut frame = vec![0x00, 0b00010000];
+        frame.extend(500u16.to_le_bytes());
+        frame.extend((-25i16).to_le_bytes());
+        frame.extend(180i16.to_le_bytes());
+        let data = decode_treadmill_data(&frame).unwrap();
+
+        assert_eq!(data.speed, 500);
+        assert!(data.force_on_belt.is_some_and(|force| force < 0));
+        assert_eq!(data.force_on_belt, Some(-25));
+        assert_eq!(data.power_output, Some(180));
+        assert_eq!(data.populated_fields(), vec!["speed", "force_on_belt", "power_output"]);
+
+        assert!(matches!(decode_treadmill_data(&frame[..7]), Err(DecodeError::NotEnoughData)));
+    }
+
+    #[test]
+    fn u24_round_trips() {
+        for value in [0, 0x123456, U24_MAX] {
+            assert_eq!(read_u24_le(&write_u24_le(value)).unwrap(), value);
+        }
+        assert_eq!(write_u24_le(0x123456), [0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn u24_write_clamps_overflow() {
+        assert_eq!(write_u24_le(U24_MAX + 1), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(write_u24_le(u32::MAX), [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn u24_read_rejects_short_slices() {
+        assert!(matches!(read_u24_le(&[0x01, 0x02]), Err(DecodeError::NotEnoughData)));
+        assert!(matches!(read_u24_le(&[]), Err(DecodeError::NotEnoughData)));
+        assert_eq!(read_u24_le(&[0x01, 0x00, 0x00, 0xFF]).unwrap(), 1);
+    }
+
+    #[test]
+    fn frames_shorter_than_flags_and_speed_are_rejected() {
+        for frame in [&[][..], &[0x00, 0x00], &[0xFF, 0xFF, 0x10]] {
+            assert!(matches!(decode_treadmill_data(frame), Err(DecodeError::NotEnoughData)), "{:?}", frame);
+        }
+    }
+
+    #[test]
+    fn minimum_frame_decodes_speed_only() {
+        let data = decode_treadmill_data(&[0x00, 0x00, 0xE8, 0x03]).unwrap();
+        assert_eq!(data.speed, 1000);
+        assert_eq!(data.populated_fields(), vec!["speed"]);
+        // Flags asking for more than four bytes carry are still short
+        assert!(matches!(decode_treadmill_data(&[0x00, 0x01, 0xE8, 0x03]), Err(DecodeError::NotEnoughData)));
+    }
+
+    #[test]
+    fn metabolic_equivalent_is_scaled_to_mets() {
+        // Only the metabolic equivalent flag, 0 km/h, raw 80
+        let data = decode_treadmill_data(&[0x00, 0b00000010, 0x00, 0x00, 80]).unwrap();
+        assert_eq!(data.metabolic_equivalent, Some(80));
+        assert_eq!(data.metabolic_equivalent_met, Some(8.0));
+    }
+
+    #[test]
+    fn degrees_convert_to_tenths_of_a_percent_grade() {
+        assert_eq!(degrees_to_inclination(0.0).unwrap(), 0);
+        assert_eq!(degrees_to_inclination(3.0).unwrap(), 52);
+        assert_eq!(degrees_to_inclination(-3.0).unwrap(), -52);
+        assert!(matches!(degrees_to_inclination(45.0), Err(CommandError::OutOfRange(_))));
+        assert!(matches!(degrees_to_inclination(f64::NAN), Err(CommandError::OutOfRange(_))));
+    }
+
+    #[test]
+    fn workouts_expand_nested_repeats() {
+        let warm_up = run_step("5:00", PaceRaw::Kph("8".to_string()));
+        let fast = WorkoutStepRaw::Run {
+            name: "fast".to_string(),
+            duration: "1:00".to_string(),
+            pace: PaceRaw::Kph("12".to_string()),
+            angle: 10,
+        };
+        let easy = run_step("0:30", PaceRaw::Kph("6".to_string()));
+        let set = WorkoutStepRaw::Repeat { times: 2, steps: vec![fast, easy] };
+        let raw = workout(vec![warm_up, WorkoutStepRaw::Repeat { times: 3, steps: vec![set] }]);
+
+        let steps = parse_workout(&raw).unwrap().steps;
+        assert_eq!(steps.len(), 1 + 3 * 2 * 2);
+        let names: Vec<&str> = steps.iter().take(5).map(|step| step.name.as_str()).collect();
+        assert_eq!(names, ["run", "fast", "run", "fast", "run"]);
+        let fast = &steps[1];
+        assert_eq!((fast.pace, fast.duration, fast.distance, fast.angle), (1200, 60, 200, 10));
+    }
+
+    #[test]
+    fn invalid_nested_steps_return_the_parse_error() {
+        let empty = WorkoutStepRaw::Repeat { times: 2, steps: Vec::new() };
+        let raw = workout(vec![WorkoutStepRaw::Repeat { times: 2, steps: vec![empty] }]);
+        assert!(matches!(parse_workout(&raw), Err(CommandError::WorkoutParse(_))));
+
+        let invalid = run_step("1:75", PaceRaw::Kph("8".to_string()));
+        let raw = workout(vec![WorkoutStepRaw::Repeat { times: 2, steps: vec![invalid] }]);
+        assert!(matches!(parse_workout(&raw), Err(CommandError::WorkoutParse(_))));
+    }
+
+    #[test]
+    fn supported_commands_follow_the_target_setting_features() {
+        // Total distance and elapsed time, target speed and targeted distance
+        let payload = [0x04, 0x10, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00];
+        let (_, target_settings) = capabilities::decode_fitness_machine_feature(&payload).unwrap();
+        let opcodes = capabilities::control_opcodes(Some(&target_settings));
+        assert_eq!(commands_for_opcodes(&opcodes), [
+            "request_control",
+            "reset",
+            "set_target_speed",
+            "start_or_resume",
+            "stop_or_pause",
+            "set_targeted_distance",
+        ]);
+
+        let opcodes = capabilities::control_opcodes(None);
+        assert_eq!(commands_for_opcodes(&opcodes), ["request_control", "reset", "start_or_resume", "stop_or_pause"]);
+    }
+
+    #[test]
+    fn supported_command_names_can_be_sent_back_as_commands() {
+        let names = commands_for_opcodes(&(0..=u8::MAX).collect::<Vec<_>>());
+        assert_eq!(names.len(), ALL_COMMANDS.len());
+        for (name, command) in names.iter().zip(ALL_COMMANDS) {
+            let json = match command {
+                TreadmillCommands::RequestControl
+                | TreadmillCommands::Reset
+                | TreadmillCommands::StartOrResume
+                | TreadmillCommands::StopOrPause => serde_json::json!({ "command": name }),
+                _ => serde_json::json!({ "command": name, "value": 0 }),
+            };
+            let parsed: TreadmillCommands = serde_json::from_value(json).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(treadmill_command_to_message(parsed), treadmill_command_to_message(command));
+        }
+    }
+
+    fn write_opcodes(machine: &mock_machine::MockMachine) -> Vec<u8> {
+        machine.writes().iter().map(|message| message[0]).collect()
+    }
+
+    #[test]
+    fn control_lost_mid_session_is_requested_again() {
+        let machine = mock_machine::MockMachine::default();
+        let connection = machine.connection(Duration::from_secs(1));
+        machine.answer(0x02, &[ResultCode::ControlNotPermitted, ResultCode::Success]);
+
+        let regained = tauri::async_runtime::block_on(
+            connection.send_regaining_control(TreadmillCommands::SetTargetSpeed(800)),
+        );
+        assert!(regained.unwrap());
+        assert_eq!(write_opcodes(&machine), [0x02, 0x00, 0x02]);
+        assert!(*connection.control_granted.lock().unwrap());
+    }
+
+    #[test]
+    fn a_request_the_machine_never_answers_times_out() {
+        let machine = mock_machine::MockMachine::default();
+        let connection = machine.connection(Duration::from_millis(100));
+        machine.never_answer(0x02);
+
+        let started = Instant::now();
+        let result = tauri::async_runtime::block_on(
+            connection.send_commands(vec![TreadmillCommands::RequestControl, TreadmillCommands::SetTargetSpeed(800)]),
+        );
+        assert!(matches!(result, Err(CommandError::ProcedureTimeout(0x02))), "{:?}", result);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(write_opcodes(&machine), [0x00, 0x02]);
+        // Control has to be asked for again before the next command
+        assert!(!*connection.control_granted.lock().unwrap());
+    }
+
+    #[test]
+    fn control_refused_when_requested_again_fails_the_command() {
+        let machine = mock_machine::MockMachine::default();
+        let connection = machine.connection(Duration::from_secs(1));
+        machine.answer(0x02, &[ResultCode::ControlNotPermitted]);
+        machine.answer(0x00, &[ResultCode::ControlNotPermitted]);
+
+        let result = tauri::async_runtime::block_on(
+            connection.send_regaining_control(TreadmillCommands::SetTargetSpeed(800)),
+        );
+        assert!(matches!(result, Err(CommandError::ControlNotPermitted)));
+        assert_eq!(write_opcodes(&machine), [0x02, 0x00]);
+        assert!(!*connection.control_granted.lock().unwrap());
+    }
+
+    // Sends `command` and answers it with the raw indication `indication` once it's written
+    fn answer_with_indication(command: TreadmillCommands, indication: &[u8]) -> Result<Vec<u8>, CommandError> {
+        let machine = mock_machine::MockMachine::default();
+        machine.never_answer(treadmill_command_to_message(command)[0]);
+        let connection = machine.connection(Duration::from_secs(1));
+        let response = control_point::decode_control_point_response(indication).unwrap();
+        tauri::async_runtime::block_on(async {
+            let indicate = async {
+                time::sleep(Duration::from_millis(20)).await;
+                connection.responses.send(response).unwrap();
+            };
+            future::join(connection.send_commands(vec![command]), indicate).await.0.map(|_| write_opcodes(&machine))
+        })
+    }
+
+    #[test]
+    fn request_control_refused_as_not_permitted_is_its_own_error() {
+        let decoded = control_point::decode_control_point_response(&[0x80, 0x00, 0x05]).unwrap();
+        assert_eq!((decoded.request_opcode, decoded.result), (0x00, ResultCode::ControlNotPermitted));
+
+        let result = answer_with_indication(TreadmillCommands::RequestControl, &[0x80, 0x00, 0x05]);
+        assert!(matches!(result, Err(CommandError::ControlNotPermitted)), "{:?}", result);
+        // Other refusals stay generic rejections
+        let result = answer_with_indication(TreadmillCommands::RequestControl, &[0x80, 0x00, 0x04]);
+        assert!(matches!(result, Err(CommandError::ControlRejected(_))), "{:?}", result);
+        assert_eq!(answer_with_indication(TreadmillCommands::RequestControl, &[0x80, 0x00, 0x01]).unwrap(), [0x00]);
+    }
+
+    #[test]
+    fn trailing_bytes_are_counted_without_failing_the_decode() {
+        let data = decode_treadmill_data(&[0x08, 0x00, 0xE8, 0x03, 0x32, 0x00, 0x00, 0x00, 0xAA, 0xBB]).unwrap();
+        assert_eq!(data.speed, 1000);
+        assert_eq!(data.inclination, Some(50));
+        assert_eq!(data.trailing_bytes, 2);
+
+        let data = decode_treadmill_data(&[0x08, 0x00, 0xE8, 0x03, 0x32, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!(data.trailing_bytes, 0);
+    }
+
+    #[test]
+    fn missing_workout_ids_are_not_found() {
+        let dir = std::env::temp_dir().join(format!("treadmill-get-workout-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        write_workout(&dir, "tempo.json", "Tempo", vec![run_step("5:00", PaceRaw::Kph("10".to_string()))]);
+        write_workout(&dir.join("nested"), "workout.json", "Nested", vec![run_step("5:00", PaceRaw::Kph("10".to_string()))]);
+        fs::write(dir.join(".hidden"), "{}").unwrap();
+
+        assert_eq!(get_workout_in(&dir, "tempo.json".to_string()).unwrap().name, "Tempo");
+        let missing = get_workout_in(&dir, "no-such-workout.json".to_string());
+        assert!(matches!(missing, Err(CommandError::WorkoutNotFound(id)) if id == "no-such-workout.json"));
+        for id in ["", "../settings.json", ".hidden", "nested/workout.json", "nested"] {
+            assert!(matches!(get_workout_in(&dir, id.to_string()), Err(CommandError::WorkoutNotFound(_))), "{}", id);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn profile_speed_scale_converts_tenths_of_a_km_h() {
+        let profile = DeviceProfile {
+            name: "Tenths",
+            name_prefix: Some("TENTHS"),
+            decode: decode_treadmill_data,
+            speed_scale: 10.0,
+        };
+        // 8.5 km/h and an average of 8.0 km/h, both in 0.1 km/h
+        let mut data = (profile.decode)(&[0x02, 0x00, 0x55, 0x00, 0x50, 0x00]).unwrap();
+        data.scale_speeds(profile.speed_scale);
+        assert_eq!(data.speed, 850);
+        assert_eq!(data.average_speed, Some(800));
+        assert_eq!(data.speed_kmh, 8.5);
+        assert_eq!(data.speed_scale, 10.0);
+    }
+
+    #[test]
+    fn standard_speed_scale_leaves_speeds_alone() {
+        let mut data = decode_treadmill_data(&[0x00, 0x00, 0x55, 0x03]).unwrap();
+        data.scale_speeds(device_profile::PROFILES[0].speed_scale);
+        assert_eq!(data.speed, 853);
+        assert_eq!(data.speed_scale, 1.0);
+    }
+
+    fn write_workout(dir: &std::path::Path, id: &str, name: &str, steps: Vec<WorkoutStepRaw>) {
+        let raw = WorkoutRaw { name: name.to_string(), description: format!("{} description", name), steps };
+        fs::write(dir.join(id), serde_json::to_string(&raw).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn workout_list_is_sorted_with_totals() {
+        let dir = std::env::temp_dir().join(format!("treadmill-workout-list-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let kph = |value: &str| PaceRaw::Kph(value.to_string());
+        write_workout(&dir, "b.json", "beta", vec![run_step("15:00", kph("12"))]);
+        write_workout(&dir, "a.json", "Alpha", vec![run_step("5:00", kph("6")), run_step("5:00", kph("12"))]);
+        let repeat = WorkoutStepRaw::Repeat { times: 4, steps: vec![run_step("1:00", kph("6"))] };
+        write_workout(&dir, "c.json", "gamma", vec![repeat]);
+        fs::write(dir.join("broken.json"), "{").unwrap();
+        let last_runs = BTreeMap::from([("beta".to_string(), 2000), ("gamma".to_string(), 1000)]);
+
+        let by_name = summarize_workouts(&dir, &last_runs, WorkoutSort::Name).unwrap();
+        let names: Vec<&str> = by_name.iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names, ["Alpha", "beta", "gamma"]);
+        let alpha = &by_name[0];
+        assert_eq!((alpha.id.as_str(), alpha.duration, alpha.distance, alpha.step_count), ("a.json", 600, 1500, 2));
+        assert_eq!(alpha.description, "Alpha description");
+        assert!(alpha.modified.is_some());
+        assert_eq!(alpha.last_run, None);
+        assert_eq!(by_name[2].step_count, 4);
+
+        let by_duration = summarize_workouts(&dir, &last_runs, WorkoutSort::Duration).unwrap();
+        let ids: Vec<&str> = by_duration.iter().map(|w| w.id.as_str()).collect();
+        assert_eq!(ids, ["c.json", "a.json", "b.json"]);
+        let by_last_run = summarize_workouts(&dir, &last_runs, WorkoutSort::LastRun).unwrap();
+        let ids: Vec<&str> = by_last_run.iter().map(|w| w.id.as_str()).collect();
+        assert_eq!(ids, ["b.json", "c.json", "a.json"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn average_speed_is_read_only_when_flagged() {
+        // Average speed of 8 km/h followed by a total distance of 1234 m
+        let data = decode_treadmill_data(&[0x06, 0x00, 0xE8, 0x03, 0x20, 0x03, 0xD2, 0x04, 0x00]).unwrap();
+        assert_eq!(data.average_speed, Some(800));
+        assert_eq!(data.average_speed_kmh, Some(8.0));
+        assert!(data.average_speed_mph.is_some_and(|mph| (mph - 4.971).abs() < 0.001));
+        assert_eq!(data.total_distance, Some(1234));
+
+        let data = decode_treadmill_data(&[0x04, 0x00, 0xE8, 0x03, 0xD2, 0x04, 0x00]).unwrap();
+        assert_eq!(data.average_speed, None);
+        assert_eq!(data.average_speed_kmh, None);
+        assert_eq!(data.average_speed_mph, None);
+        assert_eq!(data.total_distance, Some(1234));
+    }
+
+    #[test]
+    fn subscribe_that_fails_once_is_retried() {
+        let attempts = std::cell::Cell::new(0);
+        let result = tauri::async_runtime::block_on(ble_with_retry("subscribing to Treadmill Data", || {
+            attempts.set(attempts.get() + 1);
+            let result = if attempts.get() == 1 { Err(btleplug::Error::NotConnected) } else { Ok(()) };
+            async move { result }
+        }));
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn subscribe_gives_up_with_a_descriptive_error() {
+        let attempts = std::cell::Cell::new(0);
+        let result: Result<(), _> = tauri::async_runtime::block_on(ble_with_retry("subscribing to Treadmill Data", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(btleplug::Error::NotConnected) }
+        }));
+        assert!(matches!(result, Err(CommandError::BleError(e)) if e.starts_with("subscribing to Treadmill Data failed")));
+        assert_eq!(attempts.get(), SUBSCRIBE_ATTEMPTS);
+    }
+
+    #[test]
+    fn frames_carry_the_current_pace() {
+        let data = decode_treadmill_data(&[0x00, 0x00, 0xB0, 0x04]).unwrap();
+        assert_eq!((data.pace_per_km.as_str(), data.pace_per_mile.as_str()), ("5:00", "8:03"));
+        let stopped = decode_treadmill_data(&[0x00, 0x00, 0x00, 0x00]).unwrap();
+        assert_eq!((stopped.pace_per_km.as_str(), stopped.pace_per_mile.as_str()), ("--", "--"));
+    }
+
+    #[test]
+    fn frames_carry_converted_units_next_to_the_raw_fields() {
+        // 10 km/h, 2.5% grade, -1.4 degrees and 4.5 METs
+        let mut data = decode_treadmill_data(&[0x08, 0x02, 0xE8, 0x03, 0x19, 0x00, 0xF2, 0xFF, 0x2D]).unwrap();
+        assert_eq!((data.speed, data.inclination, data.ramp_angle, data.metabolic_equivalent), (1000, Some(25), Some(-14), Some(45)));
+        assert_eq!((data.speed_kmh, data.speed_mph), (10.0, (10.0 / KM_PER_MILE) as f32));
+        assert_eq!((data.inclination_percent, data.ramp_angle_degrees), (Some(2.5), Some(-1.4)));
+        assert_eq!(data.metabolic_equivalent_met, Some(4.5));
+
+        data.scale_speeds(1.2);
+        assert_eq!((data.speed, data.speed_kmh, data.pace_per_km.as_str()), (1200, 12.0, "5:00"));
+    }
+
+    // Like a BLE notification stream, only sees frames sent after it was opened
+    fn notification_stream(sender: &broadcast::Sender<u8>) -> impl futures::Stream<Item = u8> + Send + Unpin {
+        Box::pin(futures::stream::unfold(sender.subscribe(), |mut receiver| async move {
+            Some((receiver.recv().await.ok()?, receiver))
+        }))
+    }
+
+    #[test]
+    fn frames_sent_straight_after_subscribing_are_received() {
+        tauri::async_runtime::block_on(async {
+            let (machine, _) = broadcast::channel(16);
+            let mut stream = notification_stream(&machine);
+            let (received, mut frames) = tokio::sync::mpsc::unbounded_channel();
+            let consumer = async move {
+                while let Some(frame) = stream.next().await {
+                    received.send(frame).unwrap();
+                }
+            };
+            let subscriptions = async {
+                machine.send(0x2A).unwrap();
+                Ok(())
+            };
+
+            let task = consume_before_subscribing(consumer, subscriptions).await.unwrap();
+            let frame = time::timeout(Duration::from_secs(1), frames.recv()).await.unwrap();
+            assert_eq!(frame, Some(0x2A));
+            task.abort();
+        });
+    }
+
+    #[test]
+    fn consumer_stops_when_subscribing_fails() {
+        tauri::async_runtime::block_on(async {
+            let (machine, _) = broadcast::channel::<u8>(16);
+            let mut stream = notification_stream(&machine);
+            let consumer = async move { while stream.next().await.is_some() {} };
+            let subscriptions = async { Err(CommandError::NotSupported("Machine Data notifications".to_string())) };
+
+            let result = consume_before_subscribing(consumer, subscriptions).await;
+            assert!(matches!(result, Err(CommandError::NotSupported(_))));
+            // The aborted consumer dropped its stream
+            time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(machine.receiver_count(), 0);
+        });
+    }
+
+    #[test]
+    fn bad_workout_files_are_reported_next_to_the_good_ones() {
+        let dir = std::env::temp_dir().join(format!("treadmill-read-workouts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        write_workout(&dir, "good.json", "Good", vec![run_step("5:00", PaceRaw::Kph("10".to_string()))]);
+        fs::write(dir.join("bad.json"), "{ \"name\": \"Bad\", \"steps\": [").unwrap();
+
+        let read = read_workouts_in(&dir).unwrap();
+        let names: Vec<&str> = read.workouts.iter().map(|file| file.workout.name.as_str()).collect();
+        assert_eq!(names, ["Good"]);
+        assert_eq!(read.workouts[0].id, "good.json");
+        assert_eq!(read.errors.len(), 1);
+        assert_eq!(read.errors[0].filename, "bad.json");
+        assert!(!read.errors[0].error.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn convert(input: fn() -> PaceRaw) -> [String; 4] {
+        ["kph", "mph", "min/km", "min/mi"].map(|unit| convert_pace(input(), unit.to_string()).unwrap())
+    }
+
+    #[test]
+    fn paces_convert_between_every_unit() {
+        let five_per_km = ["12.0", "7.5", "5:00", "8:03"];
+        assert_eq!(convert(|| PaceRaw::MinPerKm("5:00".to_string())), five_per_km);
+        assert_eq!(convert(|| PaceRaw::Kph("12".to_string())), five_per_km);
+        let eight_per_mile = ["12.1", "7.5", "4:58", "8:00"];
+        assert_eq!(convert(|| PaceRaw::MinPerMi("8:00".to_string())), eight_per_mile);
+        assert_eq!(convert(|| PaceRaw::Mph("7.5".to_string())), eight_per_mile);
+    }
+
+    #[test]
+    fn conversions_round_through_the_belt_precision() {
+        // 8:03 per mile is 11.995 km/h, which the belt runs as 12.00
+        assert_eq!(convert_pace(PaceRaw::MinPerMi("8:03".to_string()), "min/km".to_string()).unwrap(), "5:00");
+        assert_eq!(convert_pace(PaceRaw::Kph("9.99".to_string()), "kph".to_string()).unwrap(), "10.0");
+    }
+
+    #[test]
+    fn invalid_conversions_are_rejected() {
+        let convert_to = |input: PaceRaw, unit: &str| convert_pace(input, unit.to_string());
+        assert!(matches!(convert_to(PaceRaw::Kph("12".to_string()), "knots"), Err(CommandError::WorkoutParse(_))));
+        assert!(matches!(convert_to(PaceRaw::Kph("0".to_string()), "min/km"), Err(CommandError::WorkoutParse(_))));
+        assert!(matches!(convert_to(PaceRaw::MinPerKm("five".to_string()), "kph"), Err(CommandError::WorkoutParse(_))));
+        assert_eq!(convert_to(PaceRaw::Kph("0".to_string()), "mph").unwrap(), "0.0");
+    }
+
+    #[test]
+    fn workouts_sharing_a_name_are_reported_by_file_name() {
+        let dir = std::env::temp_dir().join(format!("treadmill-duplicate-workouts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let steps = || vec![run_step("5:00", PaceRaw::Kph("10".to_string()))];
+        write_workout(&dir, "tempo-b.json", "Tempo", steps());
+        write_workout(&dir, "tempo-a.json", "Tempo", steps());
+        write_workout(&dir, "easy.json", "Easy", steps());
+
+        let read = read_workouts_in(&dir).unwrap();
+        assert_eq!(read.workouts.len(), 3);
+        assert_eq!(read.duplicate_names.len(), 1);
+        assert_eq!(read.duplicate_names[0].name, "Tempo");
+        assert_eq!(read.duplicate_names[0].ids, ["tempo-a.json", "tempo-b.json"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stopping_mid_workout_returns_the_stats_so_far() {
+        tauri::async_runtime::block_on(async {
+            let state = AppState::default();
+            assert!(matches!(end_workout_early(&state), Err(CommandError::WorkoutNotRunning)));
+
+            // Dropped along with the runner once it's aborted
+            let (running, stopped) = tokio::sync::oneshot::channel::<()>();
+            let runner = tauri::async_runtime::spawn(async move {
+                let _running = running;
+                std::future::pending::<()>().await
+            });
+            *state.workout_runner.lock().unwrap() = Some(runner);
+            for speed in [800, 1000] {
+                state.session_stats.lock().unwrap().add(&TreadmillData { speed, elapsed_time: Some(60), ..Default::default() });
+            }
+
+            let stats = end_workout_early(&state).unwrap();
+            assert_eq!(stats.frames, 2);
+            assert_eq!(stats.speed.map(|speed| speed.average), Some(900.0));
+            assert_eq!(stats.elapsed_time, Some(60));
+            assert!(time::timeout(Duration::from_secs(1), stopped).await.unwrap().is_err());
+            assert!(state.workout_runner.lock().unwrap().is_none());
+            assert!(matches!(end_workout_early(&state), Err(CommandError::WorkoutNotRunning)));
+        });
+    }
+
+    // A workout running on `machine` with its runner two steps in
+    fn workout_in_progress(machine: &mock_machine::MockMachine) -> AppState {
+        let state = AppState::default();
+        *state.treadmill.lock().unwrap() = Some(machine.connection(Duration::from_secs(1)));
+        let kph = |value: &str| PaceRaw::Kph(value.to_string());
+        let workout = parse_workout(&workout(vec![run_step("5:00", kph("8")), run_step("5:00", kph("12"))])).unwrap();
+        *state.workout_position.lock().unwrap() =
+            Some(WorkoutPosition { workout, step_index: 1, step_elapsed: 45, step_travelled: Some(150) });
+        *state.workout_runner.lock().unwrap() = Some(tauri::async_runtime::spawn(std::future::pending()));
+        state
+    }
+
+    #[test]
+    fn workout_interrupted_by_a_disconnect_resumes_where_it_was() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            let state = workout_in_progress(&machine);
+
+            let held = hold_interrupted_workout(&state).unwrap();
+            assert_eq!((held.step_index, held.step_elapsed), (1, 45));
+            assert!(state.workout_runner.lock().unwrap().is_none());
+            assert!(state.workout_position.lock().unwrap().is_none());
+            assert!(hold_interrupted_workout(&state).is_none());
+
+            // Reconnecting leaves the console in control
+            *state.control_granted.lock().unwrap() = false;
+            let position = reclaim_interrupted_workout(&state).await.unwrap();
+            assert_eq!((position.step_index, position.step_elapsed, position.step_travelled), (1, 45, Some(150)));
+            assert_eq!(write_opcodes(&machine), [0x00]);
+            assert!(state.interrupted_workout.lock().unwrap().is_none());
+            assert!(matches!(reclaim_interrupted_workout(&state).await, Err(CommandError::WorkoutNotRunning)));
+        });
+    }
+
+    #[test]
+    fn interrupted_workout_waits_for_the_interlock_and_control() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            let state = workout_in_progress(&machine);
+            hold_interrupted_workout(&state).unwrap();
+
+            state.interlock.lock().unwrap().set_window(Some(Duration::from_secs(10)));
+            assert!(matches!(reclaim_interrupted_workout(&state).await, Err(CommandError::NotArmed)));
+            assert!(machine.writes().is_empty());
+
+            state.interlock.lock().unwrap().arm();
+            machine.answer(0x00, &[ResultCode::ControlNotPermitted]);
+            assert!(matches!(reclaim_interrupted_workout(&state).await, Err(CommandError::ControlNotPermitted)));
+            assert!(state.interrupted_workout.lock().unwrap().is_some(), "still there to resume later");
+        });
+    }
+
+    async fn self_test_on(machine: &mock_machine::MockMachine, state: &AppState, run_belt: bool) -> SelfTestResult {
+        *state.treadmill.lock().unwrap() = Some(machine.connection(Duration::from_secs(1)));
+        let connection = state.connection().unwrap();
+        let control = connection.request_control().await;
+        run_self_test(state, connection, control, run_belt).await
+    }
+
+    fn self_test_steps(result: &SelfTestResult) -> Vec<(&str, bool)> {
+        result.steps.iter().map(|step| (step.name, step.error.is_none())).collect()
+    }
+
+    #[test]
+    fn self_test_runs_the_belt_at_its_lowest_speed_and_stops_it() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            let result = self_test_on(&machine, &AppState::default(), true).await;
+            assert!(result.passed);
+            assert_eq!(
+                self_test_steps(&result),
+                [("request-control", true), ("read-capabilities", true), ("run-belt", true), ("stop-belt", true)]
+            );
+            assert_eq!(write_opcodes(&machine), [0x00, 0x07, 0x02, 0x08]);
+            let speed = &machine.writes()[2];
+            assert_eq!(u16::from_le_bytes([speed[1], speed[2]]), SELF_TEST_FALLBACK_SPEED);
+        });
+    }
+
+    #[test]
+    fn self_test_reports_each_failure_and_never_moves_without_control() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            machine.answer(0x00, &[ResultCode::ControlNotPermitted]);
+            let result = self_test_on(&machine, &AppState::default(), true).await;
+            assert!(!result.passed);
+            assert_eq!(self_test_steps(&result), [("request-control", false), ("read-capabilities", true)]);
+            assert_eq!(write_opcodes(&machine), [0x00]);
+
+            // Armed interlocks hold the belt back, but it's still stopped to be safe
+            let machine = mock_machine::MockMachine::default();
+            let state = AppState::default();
+            state.interlock.lock().unwrap().set_window(Some(Duration::from_secs(10)));
+            let result = self_test_on(&machine, &state, true).await;
+            assert_eq!(
+                self_test_steps(&result),
+                [("request-control", true), ("read-capabilities", true), ("run-belt", false), ("stop-belt", true)]
+            );
+            assert_eq!(write_opcodes(&machine), [0x00, 0x08]);
+        });
+    }
+
+    #[test]
+    fn remaining_time_decodes_without_elapsed_time() {
+        // 10 km/h with 25:00 remaining
+        let data = decode_treadmill_data(&[0x00, 0x08, 0xE8, 0x03, 0xDC, 0x05]).unwrap();
+        assert_eq!(data.speed, 1000);
+        assert_eq!(data.remaining_time, Some(1500));
+        assert_eq!(data.elapsed_time, None);
+        assert_eq!(data.trailing_bytes, 0);
+        assert_eq!(data.populated_fields(), ["speed", "remaining_time"]);
+
+        // The fields after it are read from where remaining time ended
+        let data = decode_treadmill_data(&[0x00, 0x18, 0xE8, 0x03, 0xDC, 0x05, 0x0A, 0x00, 0x96, 0x00]).unwrap();
+        assert_eq!(data.remaining_time, Some(1500));
+        assert_eq!((data.force_on_belt, data.power_output), (Some(10), Some(150)));
+        assert_eq!(data.populated_fields(), ["speed", "remaining_time", "force_on_belt", "power_output"]);
+    }
+
+    #[test]
+    fn exported_workouts_import_into_another_library() {
+        let root = std::env::temp_dir().join(format!("treadmill-workout-bundle-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (library, exports, other) = (root.join("library"), root.join("exports"), root.join("other"));
+        fs::create_dir_all(&library).unwrap();
+        fs::create_dir_all(&other).unwrap();
+        let kph = |value: &str| PaceRaw::Kph(value.to_string());
+        write_workout(&library, "easy.json", "Easy", vec![run_step("20:00", kph("8"))]);
+        write_workout(&library, "tempo.json", "Tempo", vec![run_step("10:00", kph("12"))]);
+        fs::write(library.join("broken.json"), "{").unwrap();
+
+        let bundle = export_workouts_to(&library, &exports).unwrap();
+        let path = bundle.to_str().unwrap();
+        let import = import_workouts_into(&other, path).unwrap();
+        assert_eq!(import.imported, ["easy.json", "tempo.json"]);
+        assert!(import.skipped.is_empty() && import.errors.is_empty());
+        let read = read_workouts_in(&other).unwrap();
+        let mut names: Vec<&str> = read.workouts.iter().map(|file| file.workout.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["Easy", "Tempo"]);
+
+        // Importing again skips what's already there and keeps a changed workout under a new id
+        write_workout(&other, "tempo.json", "Tempo", vec![run_step("15:00", kph("12"))]);
+        let import = import_workouts_into(&other, path).unwrap();
+        assert_eq!(import.skipped, ["easy.json"]);
+        assert_eq!(import.imported, ["tempo-2.json"]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn throttle_coalesces_rapid_frames_while_stats_see_them_all() {
+        let interval = Duration::from_millis(250);
+        let start = Instant::now();
+        let mut throttle = EmitThrottle::default();
+        let mut stats = SessionStats::default();
+        let mut emitted = Vec::new();
+        let mut offered = Vec::new();
+        // Ten frames 50 ms apart
+        for i in 0..10u16 {
+            let now = start + Duration::from_millis(i as u64 * 50);
+            let data = TreadmillData { speed: 800 + i, ..Default::default() };
+            stats.add(&data);
+            match throttle.offer(data, interval, now) {
+                Throttled::Emit(data) => emitted.push(data.speed),
+                Throttled::EmitLater(wait) => offered.push(wait),
+                Throttled::Replaced => {}
+            }
+            // The scheduled emit goes out when its wait is up, with the latest frame by then
+            if i == 4 {
+                emitted.extend(throttle.take_pending(now).map(|data| data.speed));
+            }
+        }
+        emitted.extend(throttle.take_pending(start + Duration::from_millis(500)).map(|data| data.speed));
+
+        assert_eq!(stats.frames, 10);
+        assert_eq!(emitted, [800, 804, 809]);
+        assert_eq!(offered, [Duration::from_millis(200), Duration::from_millis(200)]);
+    }
+
+    #[test]
+    fn zero_interval_emits_every_frame() {
+        let mut throttle = EmitThrottle::default();
+        let now = Instant::now();
+        for speed in [800, 900] {
+            let offered = throttle.offer(TreadmillData { speed, ..Default::default() }, Duration::ZERO, now);
+            assert!(matches!(offered, Throttled::Emit(data) if data.speed == speed));
+        }
+    }
+
+    #[test]
+    fn outdated_workout_files_are_migrated_with_a_backup() {
+        let root = std::env::temp_dir().join(format!("treadmill-check-workouts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("hills.json");
+        let original = r#"{"steps": [{"type": "run", "name": "climb", "duration": 330, "pace": {"unit": "kph", "value": "8"}}]}"#;
+        fs::write(&path, original).unwrap();
+
+        let (kind, message, migrated) = check_workout_file(&path, "hills.json").unwrap();
+        assert_eq!(kind, WorkoutIssueKind::Outdated);
+        assert!(message.contains("added name \"hills\""), "{}", message);
+        assert!(message.contains("from 330 to \"5:30\""), "{}", message);
+
+        let backups = root.join("backups");
+        migrate_workout_file(&backups, &path, &migrated.unwrap()).unwrap();
+        assert!(check_workout_file(&path, "hills.json").is_none());
+        let workout = parse_workout(&read_workout_file(&path).unwrap()).unwrap();
+        assert_eq!((workout.name.as_str(), workout.duration), ("hills", 330));
+        let backed_up: Vec<_> = fs::read_dir(&backups).unwrap().flatten().map(|entry| entry.path()).collect();
+        assert_eq!(backed_up.len(), 1);
+        assert_eq!(fs::read_to_string(&backed_up[0]).unwrap(), original);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unrepairable_workout_files_are_reported_without_a_migration() {
+        let root = std::env::temp_dir().join(format!("treadmill-check-broken-workouts-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        for (filename, content, expected) in [
+            ("truncated.json", r#"{"name": "Cut off", "steps": ["#, WorkoutIssueKind::Malformed),
+            ("steps.json", r#"{"name": "Odd", "steps": "run for a bit"}"#, WorkoutIssueKind::Malformed),
+            (
+                "pace.json",
+                r#"{"name": "Fast", "description": "", "steps": [{"type": "run", "name": "sprint", "duration": "1:00", "pace": {"unit": "kph", "value": "fast"}, "angle": 0}]}"#,
+                WorkoutIssueKind::Invalid,
+            ),
+        ] {
+            let path = root.join(filename);
+            fs::write(&path, content).unwrap();
+            let (kind, _, migrated) = check_workout_file(&path, filename).unwrap();
+            assert_eq!(kind, expected, "{}", filename);
+            assert!(migrated.is_none(), "{}", filename);
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn corrupted_frames_with_absurd_speeds_are_flagged() {
+        let corrupted = [0x00, 0x00, 0xFF, 0xFF];
+        let data = decode_treadmill_data(&corrupted).unwrap();
+        let frame = implausible_frame(&data, Some(4000), &corrupted).unwrap();
+        assert_eq!((frame.speed, frame.limit), (u16::MAX, 4000));
+        assert_eq!(frame.raw, corrupted);
+        // Off by default, so nothing is dropped
+        assert!(implausible_frame(&data, None, &corrupted).is_none());
+
+        let fine = [0x00, 0x00, 0xA0, 0x0F];
+        assert!(implausible_frame(&decode_treadmill_data(&fine).unwrap(), Some(4000), &fine).is_none());
+    }
+
+    // Advertises 1..=20 km/h and 0..=20% grade
+    fn advertised_ranges() -> MachineCapabilities {
+        MachineCapabilities {
+            features: None,
+            target_settings: None,
+            speed_range: Some(capabilities::SpeedRange { minimum: 100, maximum: 2000, minimum_increment: 10 }),
+            inclination_range: Some(capabilities::InclinationRange { minimum: 0, maximum: 200, minimum_increment: 5 }),
+            power_range: None,
+            control_opcodes: Vec::new(),
+        }
+    }
+
+    // `send_targets` with the machine's status notifications handled as they arrive
+    async fn send_targets_to(
+        machine: &mock_machine::MockMachine,
+        state: &AppState,
+        requested: TargetsUpdated,
+    ) -> Result<TargetsUpdated, CommandError> {
+        let mut statuses = machine.statuses();
+        let connection = machine.connection(Duration::from_secs(1));
+        let forward = async {
+            while let Ok(status) = statuses.recv().await {
+                handle_machine_status(state, &status);
+            }
+        };
+        let capabilities = advertised_ranges();
+        let sent = pin!(send_targets(state, &connection, &capabilities, requested));
+        let applied = match futures::future::select(sent, pin!(forward)).await {
+            futures::future::Either::Left((applied, _)) => applied,
+            futures::future::Either::Right(_) => unreachable!("the machine keeps its status channel open"),
+        };
+        applied
+    }
+
+    #[test]
+    fn accepted_targets_the_machine_clamps_are_returned_as_applied() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            // Narrower than it advertises, like a console with a user speed limit
+            machine.clamp_targets((100, 1600), (0, 150));
+            let state = AppState::default();
+
+            let requested = TargetsUpdated { speed: Some(1800), incline: Some(180) };
+            let applied = send_targets_to(&machine, &state, requested).await.unwrap();
+            assert_eq!((applied.speed, applied.incline), (Some(1600), Some(150)));
+            assert_eq!(machine.writes(), [vec![0x02, 0x08, 0x07], vec![0x03, 0xB4, 0x00]]);
+
+            let requested = TargetsUpdated { speed: Some(1000), incline: None };
+            let applied = send_targets_to(&machine, &state, requested).await.unwrap();
+            assert_eq!((applied.speed, applied.incline), (Some(1000), None));
+        });
+    }
+
+    #[test]
+    fn targets_are_taken_as_requested_when_the_machine_reports_nothing() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            let requested = TargetsUpdated { speed: Some(1800), incline: Some(20) };
+            let applied = send_targets_to(&machine, &AppState::default(), requested).await.unwrap();
+            assert_eq!((applied.speed, applied.incline), (Some(1800), Some(20)));
+        });
+    }
+
+    #[test]
+    fn a_ramped_speed_sends_the_incline_with_its_first_step() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            machine.clamp_targets((100, 2000), (0, 200));
+            let state = AppState::default();
+            state.settings.lock().unwrap().max_speed_change_per_command = Some(200);
+            *state.latest_data.lock().unwrap() = Some(TreadmillData { speed: 800, ..Default::default() });
+
+            let requested = TargetsUpdated { speed: Some(1400), incline: Some(50) };
+            let applied = send_targets_to(&machine, &state, requested).await.unwrap();
+            let writes: Vec<_> = machine.writes().iter().map(|write| (write[0], u16::from_le_bytes([write[1], write[2]]))).collect();
+            assert_eq!(writes, [(0x02, 1000), (0x03, 50), (0x02, 1200), (0x02, 1400)]);
+            // Read back from the final speed rather than the ramp's first
+            assert_eq!((applied.speed, applied.incline), (Some(1400), Some(50)));
+        });
+    }
+
+    #[test]
+    fn targets_outside_the_advertised_range_are_refused_before_writing() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            let state = AppState::default();
+            for requested in [
+                TargetsUpdated { speed: Some(2500), incline: Some(20) },
+                TargetsUpdated { speed: Some(1000), incline: Some(250) },
+                TargetsUpdated { speed: None, incline: Some(-10) },
+            ] {
+                let result = send_targets_to(&machine, &state, requested).await;
+                assert!(matches!(result, Err(CommandError::OutOfRange(_))), "{:?}", requested);
+            }
+            assert!(machine.writes().is_empty());
+        });
+    }
+
+    #[test]
+    fn targets_above_the_speed_cap_are_still_refused() {
+        tauri::async_runtime::block_on(async {
+            let machine = mock_machine::MockMachine::default();
+            let state = AppState::default();
+            state.settings.lock().unwrap().max_speed = Some(1200);
+            let requested = TargetsUpdated { speed: Some(1500), incline: Some(20) };
+            let result = send_targets_to(&machine, &state, requested).await;
+            assert!(matches!(result, Err(CommandError::OutOfRange(_))));
+            assert!(machine.writes().is_empty());
+        });
+    }
+
+    #[test]
+    fn a_connect_that_never_comes_up_is_not_verified() {
+        let checks = std::sync::atomic::AtomicUsize::new(0);
+        let verified = tauri::async_runtime::block_on(wait_until_connected(
+            || {
+                checks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { Ok(false) }
+            },
+            Duration::from_millis(50),
+            Duration::from_millis(10),
+        ));
+        assert!(!verified);
+        assert!(checks.load(std::sync::atomic::Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn a_connect_that_comes_up_late_is_verified() {
+        let checks = std::sync::atomic::AtomicUsize::new(0);
+        let verified = tauri::async_runtime::block_on(wait_until_connected(
+            || {
+                let check = checks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async move {
+                    match check {
+                        0 => Err(btleplug::Error::DeviceNotFound),
+                        1 => Ok(false),
+                        _ => Ok(true),
+                    }
+                }
+            },
+            Duration::from_secs(1),
+            Duration::from_millis(10),
+        ));
+        assert!(verified);
+        assert_eq!(checks.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn a_machine_with_two_data_characteristics_defaults_to_treadmill_data() {
+        let uuids = [
+            INDOOR_BIKE_DATA_CHARACTERISTIC_UUID,
+            TREADMILL_CONTROL_CHARACTERISTIC_UUID,
+            TREADMILL_DATA_CHARACTERISTIC_UUID,
+        ];
+        let available: Vec<_> = uuids.into_iter().filter_map(MachineType::from_data_characteristic).collect();
+        assert_eq!(available, vec![MachineType::IndoorBike, MachineType::Treadmill]);
+        assert_eq!(select_machine_type(&available, None), Some(MachineType::Treadmill));
+        assert_eq!(select_machine_type(&available, Some(MachineType::IndoorBike)), Some(MachineType::IndoorBike));
+        // A choice the machine can't send falls back to treadmill data
+        assert_eq!(select_machine_type(&[MachineType::Treadmill], Some(MachineType::IndoorBike)), Some(MachineType::Treadmill));
+        assert_eq!(select_machine_type(&[MachineType::IndoorBike], None), Some(MachineType::IndoorBike));
+        assert_eq!(select_machine_type(&[], None), None);
+    }
+
+    #[test]
+    fn a_stall_is_reported_once_after_the_timeout_with_no_frames() {
+        tauri::async_runtime::block_on(async {
+            let (sender, _) = broadcast::channel(4);
+            let mut frames = notification_stream(&sender);
+            let mut watchdog = StallWatchdog::new(Duration::from_millis(50));
+            let started = Instant::now();
+            assert!(matches!(watchdog.next(&mut frames).await, Watched::Stalled));
+            assert!(started.elapsed() >= Duration::from_millis(50));
+            // Stays quiet rather than reporting the same stall again
+            assert!(time::timeout(Duration::from_millis(100), watchdog.next(&mut frames)).await.is_err());
+
+            sender.send(1).unwrap();
+            assert!(matches!(watchdog.next(&mut frames).await, Watched::Next(1)));
+            assert!(watchdog.frame());
+            sender.send(2).unwrap();
+            assert!(matches!(watchdog.next(&mut frames).await, Watched::Next(2)));
+            assert!(!watchdog.frame());
+        });
+    }
+
+    #[test]
+    fn frames_within_the_timeout_keep_the_stream_from_stalling() {
+        tauri::async_runtime::block_on(async {
+            let (sender, _) = broadcast::channel(4);
+            let mut frames = notification_stream(&sender);
+            let mut watchdog = StallWatchdog::new(Duration::from_millis(100));
+            for frame in 0..5 {
+                time::sleep(Duration::from_millis(40)).await;
+                sender.send(frame).unwrap();
+                assert!(matches!(watchdog.next(&mut frames).await, Watched::Next(_)));
+                assert!(!watchdog.frame());
+            }
+            drop(sender);
+            assert!(matches!(watchdog.next(&mut frames).await, Watched::Ended));
+        });
+    }
+
+    #[test]
+    fn previews_match_what_is_written() {
+        let commands = [
+            r#"{ "command": "request_control" }"#,
+            r#"{ "command": "set_target_speed", "value": 1234 }"#,
+            r#"{ "command": "set_target_inclination", "value": -35 }"#,
+            r#"{ "command": "set_targeted_distance", "value": 70000 }"#,
+            r#"{ "command": "set_targeted_distance", "value": 20000000 }"#,
+            r#"{ "command": "set_targeted_training_time", "value": 1800 }"#,
+            r#"{ "command": "stop_or_pause" }"#,
+        ];
+        let commands: Vec<TreadmillCommands> = commands.iter().map(|json| serde_json::from_str(json).unwrap()).collect();
+        let previews: Vec<_> = commands.iter().map(|command| preview_command(*command).unwrap()).collect();
+        assert_eq!(previews[1], [0x02, 0xD2, 0x04]);
+        // Signed incline
+        assert_eq!(previews[2], [0x03, 0xDD, 0xFF]);
+        // Distance as a u24, clamped above its maximum
+        assert_eq!(previews[3], [0x0C, 0x70, 0x11, 0x01]);
+        assert_eq!(previews[4], [0x0C, 0xFF, 0xFF, 0xFF]);
+
+        let machine = mock_machine::MockMachine::default();
+        let connection = machine.connection(Duration::from_secs(1));
+        tauri::async_runtime::block_on(connection.send_commands(commands)).unwrap();
+        assert_eq!(machine.writes(), previews);
+    }
+
+    // An adapter that starts seeing `device` after it's been asked `after` times
+    struct MockAdapter {
+        device: Option<&'static str>,
+        after: usize,
+        asked: std::sync::atomic::AtomicUsize,
+    }
+
+    impl MockAdapter {
+        fn new(device: Option<&'static str>, after: usize) -> Self {
+            MockAdapter { device, after, asked: std::sync::atomic::AtomicUsize::new(0) }
+        }
+
+        async fn find(&self) -> Option<&'static str> {
+            let asked = self.asked.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            self.device.filter(|_| asked > self.after)
+        }
+
+        fn asked(&self) -> usize {
+            self.asked.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    fn scan_mock_adapters(adapters: &[MockAdapter]) -> Option<(usize, &'static str)> {
+        tauri::async_runtime::block_on(first_adapter_to_find(
+            adapters,
+            MockAdapter::find,
+            Duration::from_millis(200),
+            Duration::from_millis(10),
+        ))
+    }
+
+    #[test]
+    fn a_treadmill_only_on_the_second_adapter_is_found_there() {
+        let adapters = [MockAdapter::new(None, 0), MockAdapter::new(Some("treadmill"), 2)];
+        assert_eq!(scan_mock_adapters(&adapters), Some((1, "treadmill")));
+        // Stops asking as soon as it's found
+        assert_eq!((adapters[0].asked(), adapters[1].asked()), (3, 3));
+    }
+
+    #[test]
+    fn scanning_adapters_gives_up_when_none_find_the_treadmill() {
+        let adapters = [MockAdapter::new(None, 0), MockAdapter::new(None, 0)];
+        let started = Instant::now();
+        assert_eq!(scan_mock_adapters(&adapters), None);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(adapters[1].asked() > 1);
+        assert_eq!(scan_mock_adapters(&[]), None);
+    }
+}
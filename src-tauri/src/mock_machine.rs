@@ -0,0 +1,128 @@
+// A scripted treadmill for tests. Records every control point write and either fails it or
+// answers with the result code set for its opcode, so command handling can be tested without
+// hardware or an app handle.
+
+use crate::{
+    control_point::{ControlPointResponse, ResultCode},
+    error::CommandError,
+    interlock::Interlock,
+    Transport, TreadmillConnection,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::broadcast;
+
+#[derive(Default)]
+struct Script {
+    writes: Vec<Vec<u8>>,
+    // Writes still to fail, and how
+    failing_writes: u32,
+    failure: Option<fn() -> CommandError>,
+    // Answers still to give by opcode, the last one repeats
+    results: HashMap<u8, Vec<ResultCode>>,
+    // Opcodes that are written without ever being answered
+    unanswered: Vec<u8>,
+    // Ranges targets are clamped to, each reported as Fitness Machine Status once applied
+    speed_range: Option<(u16, u16)>,
+    inclination_range: Option<(i16, i16)>,
+}
+
+#[derive(Clone)]
+pub struct MockMachine {
+    script: Arc<Mutex<Script>>,
+    responses: broadcast::Sender<ControlPointResponse>,
+    statuses: broadcast::Sender<Vec<u8>>,
+}
+
+impl Default for MockMachine {
+    fn default() -> MockMachine {
+        let (responses, _) = broadcast::channel(16);
+        let (statuses, _) = broadcast::channel(16);
+        MockMachine { script: Arc::default(), responses, statuses }
+    }
+}
+
+impl MockMachine {
+    // A connection to this machine with the interlock off.
+    pub fn connection(&self, procedure_timeout: Duration) -> TreadmillConnection {
+        TreadmillConnection {
+            transport: Transport::Mock(self.clone()),
+            responses: self.responses.clone(),
+            interlock: Arc::new(Mutex::new(Interlock::default())),
+            control_granted: Arc::new(Mutex::new(true)),
+            procedure_timeout,
+        }
+    }
+
+    pub fn fail_writes(&self, count: u32, failure: fn() -> CommandError) {
+        let mut script = self.script.lock().unwrap();
+        script.failing_writes = count;
+        script.failure = Some(failure);
+    }
+
+    // Answers requests with `opcode` with `results` in turn, sticking with the last. Opcodes without
+    // results set succeed.
+    pub fn answer(&self, opcode: u8, results: &[ResultCode]) {
+        self.script.lock().unwrap().results.insert(opcode, results.to_vec());
+    }
+
+    // Takes requests with `opcode` without ever answering them, like a machine that's hung.
+    pub fn never_answer(&self, opcode: u8) {
+        self.script.lock().unwrap().unanswered.push(opcode);
+    }
+
+    // Clamps target speeds and inclines to these ranges like a real machine, and reports the target
+    // it applied on `statuses`.
+    pub fn clamp_targets(&self, speed: (u16, u16), inclination: (i16, i16)) {
+        let mut script = self.script.lock().unwrap();
+        script.speed_range = Some(speed);
+        script.inclination_range = Some(inclination);
+    }
+
+    // Fitness Machine Status notifications, as raw values.
+    pub fn statuses(&self) -> broadcast::Receiver<Vec<u8>> {
+        self.statuses.subscribe()
+    }
+
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.script.lock().unwrap().writes.clone()
+    }
+
+    pub fn write(&self, message: &[u8]) -> Result<(), CommandError> {
+        let mut script = self.script.lock().unwrap();
+        script.writes.push(message.to_vec());
+        if script.failing_writes > 0 {
+            script.failing_writes -= 1;
+            return Err(script.failure.map_or(CommandError::NotConnected, |failure| failure()));
+        }
+        let request_opcode = message.first().copied().unwrap_or_default();
+        if script.unanswered.contains(&request_opcode) {
+            return Ok(());
+        }
+        let result = match script.results.get_mut(&request_opcode) {
+            Some(results) if results.len() > 1 => results.remove(0),
+            Some(results) => results.first().copied().unwrap_or(ResultCode::Success),
+            None => ResultCode::Success,
+        };
+        // Nobody waiting on a response is fine, the request just isn't followed up
+        let _ = self.responses.send(ControlPointResponse { request_opcode, result, parameters: Vec::new() });
+
+        let parameter = message.get(1..3).map(|bytes| [bytes[0], bytes[1]]);
+        let status = match (request_opcode, parameter, script.speed_range, script.inclination_range) {
+            (0x02, Some(bytes), Some((minimum, maximum)), _) => {
+                Some([vec![0x05], u16::from_le_bytes(bytes).clamp(minimum, maximum).to_le_bytes().to_vec()].concat())
+            }
+            (0x03, Some(bytes), _, Some((minimum, maximum))) => {
+                Some([vec![0x06], i16::from_le_bytes(bytes).clamp(minimum, maximum).to_le_bytes().to_vec()].concat())
+            }
+            _ => None,
+        };
+        if let Some(status) = status.filter(|_| result == ResultCode::Success) {
+            let _ = self.statuses.send(status);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+// Plays a recorded session log back as `treadmill-data` events at the pace it was recorded, for
+// building the UI without a treadmill. Frames only carry the columns the log keeps, and nothing
+// else that live data feeds, such as session stats or the log itself, sees them.
+
+use crate::{emit_treadmill_data, session_log::Trackpoint, AppState, TreadmillData};
+use std::time::Duration;
+use tauri::{async_runtime::JoinHandle, AppHandle, Manager as _};
+use tokio::{sync::watch, time};
+
+pub const SPEED_MULTIPLIER_RANGE: std::ops::RangeInclusive<f64> = 0.1..=100.0;
+// Longer gaps in the recording, e.g. while the connection was down, are shortened to this
+const MAX_GAP: Duration = Duration::from_secs(5);
+
+pub struct Replay {
+    task: JoinHandle<()>,
+    paused: watch::Sender<bool>,
+}
+
+// Data rows only, markers and summaries have nothing to show.
+fn frame(point: &Trackpoint) -> Option<TreadmillData> {
+    if point.event != "data" && point.event != "split" {
+        return None;
+    }
+    let mut data = TreadmillData {
+        speed: point.speed?,
+        total_distance: point.total_distance,
+        inclination: point.inclination,
+        heart_rate: point.heart_rate,
+        elapsed_time: point.elapsed_time,
+        speed_scale: 1.0,
+        ..Default::default()
+    };
+    data.convert_units();
+    Some(data)
+}
+
+// The frames to replay with the timestamps they were recorded at.
+fn frames(trackpoints: &[Trackpoint]) -> Vec<(u64, TreadmillData)> {
+    trackpoints.iter().filter_map(|point| Some((point.timestamp, frame(point)?))).collect()
+}
+
+// Hands each frame to `emit` after the gap it was recorded with, holding them back while `paused`
+// is set. Returns false if the replay was dropped part way.
+async fn play(
+    frames: Vec<(u64, TreadmillData)>,
+    speed_multiplier: f64,
+    mut paused: watch::Receiver<bool>,
+    mut emit: impl FnMut(TreadmillData),
+) -> bool {
+    let mut previous = None;
+    for (timestamp, data) in frames {
+        if let Some(previous) = previous {
+            let gap = Duration::from_millis(timestamp.saturating_sub(previous)).min(MAX_GAP);
+            time::sleep(gap.div_f64(speed_multiplier)).await;
+        }
+        previous = Some(timestamp);
+        // Only errors once the replay has been dropped
+        if paused.wait_for(|paused| !*paused).await.is_err() {
+            return false;
+        }
+        emit(data);
+    }
+    true
+}
+
+impl Replay {
+    // Returns the replay and how many frames it will emit.
+    pub fn start(app: AppHandle, id: String, trackpoints: &[Trackpoint], speed_multiplier: f64) -> (Replay, usize) {
+        let frames = frames(trackpoints);
+        let count = frames.len();
+        let (paused, resumed) = watch::channel(false);
+
+        let task = tauri::async_runtime::spawn(async move {
+            if !play(frames, speed_multiplier, resumed, |data| emit_treadmill_data(&app, data)).await {
+                return;
+            }
+
+            println!("Replay of session {} complete.", id);
+            app.state::<AppState>().replay.lock().unwrap().take();
+            if let Err(e) = app.emit_all("replay-complete", id) {
+                eprintln!("Error emitting replay complete: {:?}", e);
+            }
+        });
+        (Replay { task, paused }, count)
+    }
+
+    // Holds the next frame back until resumed, the frame that's waiting out its gap still goes.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
+    pub fn stop(self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_log;
+    use std::{fs, time::Instant};
+
+    // A short recording with a reconnect in the middle, closed cleanly
+    const SESSION: &str = "timestamp,event,speed,total_distance,inclination,heart_rate,elapsed_time
+1000,data,800,0,10,120,0
+1200,data,850,1,10,121,0
+1400,reconnect,,,,,
+1600,data,900,2,15,,1
+1800,split,900,3,15,122,1
+2000,summary,863,3,13,121,1
+2000,end,,,,,
+";
+
+    fn recorded_frames(name: &str) -> Vec<(u64, TreadmillData)> {
+        let dir = std::env::temp_dir().join(format!("treadmill-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("1000.csv"), SESSION).unwrap();
+        let trackpoints = session_log::load_from(&dir, "1000").unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        frames(&trackpoints)
+    }
+
+    #[test]
+    fn a_recorded_session_replays_its_data_rows_only() {
+        let frames = recorded_frames("replay-rows");
+        let timestamps: Vec<u64> = frames.iter().map(|(timestamp, _)| *timestamp).collect();
+        assert_eq!(timestamps, [1000, 1200, 1600, 1800]);
+        let (_, first) = &frames[0];
+        assert_eq!((first.speed, first.total_distance, first.inclination, first.heart_rate), (800, Some(0), Some(10), Some(120)));
+        assert_eq!((first.speed_kmh, first.pace_per_km.as_str()), (8.0, "7:30"));
+        assert_eq!(frames[2].1.heart_rate, None);
+    }
+
+    #[test]
+    fn replaying_emits_every_frame_at_the_scaled_cadence() {
+        let (_paused, resumed) = watch::channel(false);
+        let mut speeds = Vec::new();
+        let started = Instant::now();
+        // 800ms recorded at 4x
+        let finished = tauri::async_runtime::block_on(play(recorded_frames("replay-cadence"), 4.0, resumed, |data| speeds.push(data.speed)));
+        assert!(finished);
+        assert_eq!(speeds, [800, 850, 900, 900]);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(started.elapsed() < Duration::from_millis(800));
+    }
+
+    #[test]
+    fn a_paused_replay_holds_its_frames_back() {
+        let (paused, resumed) = watch::channel(true);
+        let mut emitted = 0;
+        let frames = recorded_frames("replay-paused");
+        let result = tauri::async_runtime::block_on(async {
+            time::timeout(Duration::from_millis(100), play(frames, 100.0, resumed, |_| emitted += 1)).await
+        });
+        assert!(result.is_err());
+        assert_eq!(emitted, 0);
+
+        let (paused_too, resumed) = watch::channel(true);
+        drop(paused_too);
+        let finished = tauri::async_runtime::block_on(play(recorded_frames("replay-dropped"), 100.0, resumed, |_| emitted += 1));
+        assert!(!finished, "a dropped replay stops");
+        assert_eq!(emitted, 0);
+        drop(paused);
+    }
+}
@@ -0,0 +1,829 @@
+// Runs a parsed workout against the connected treadmill: sets each step's target speed and incline,
+// waits for the step to finish and moves on to the next.
+
+use crate::{
+    capabilities::MachineCapabilities, close_session_log, control_lost, control_refused, error::CommandError,
+    interrupt_workout, last_run, machine_capabilities, open_session_log, persist_state, ramp_target_speed, record_targets, session::{RunningStat, SessionStats},
+    session_log, AppState, TreadmillCommands, TreadmillConnection, TreadmillData, Workout, WorkoutStep,
+};
+use serde::Serialize;
+use std::{future::Future, time::Duration};
+use tauri::{AppHandle, Manager as _};
+use tokio::time::{self, Instant};
+
+const TICK: Duration = Duration::from_secs(1);
+// Share of the progress taken from distance when the machine reports it, the rest is time
+const DISTANCE_WEIGHT: f64 = 0.8;
+const WRITE_ATTEMPTS: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Meters short of the target that still count as done, the machine reports whole meters and
+// frames only arrive every so often
+const DISTANCE_TOLERANCE: u32 = 5;
+// How far the step clock may drift from the machine's elapsed time before it's corrected, the
+// machine only reports whole seconds
+const CLOCK_SYNC_THRESHOLD: f64 = 2.0;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompletionMethod {
+    Distance,
+    Time,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct StepEvent {
+    index: usize,
+    name: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct ControlWriteFailed {
+    command: String,
+    error: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct StepComplete {
+    index: usize,
+    name: String,
+    method: CompletionMethod,
+}
+
+// Where a running workout has got to, kept up to date so it can carry on after a dropped
+// connection.
+#[derive(Debug, Serialize, Clone)]
+pub struct WorkoutPosition {
+    pub workout: Workout,
+    pub step_index: usize,
+    // Seconds into the step
+    pub step_elapsed: u64,
+    // Meters into the step, when the machine reports distance
+    pub step_travelled: Option<u32>,
+}
+
+// What actually happened during one step, which can differ from the plan when the belt lags behind
+// the target or the step started part way through after a resume.
+#[derive(Debug, Serialize, Clone)]
+pub struct Lap {
+    pub index: usize,
+    pub name: String,
+    pub method: CompletionMethod,
+    // Seconds
+    pub duration: u64,
+    // Meters, when the machine reports distance
+    pub distance: Option<u32>,
+    // Km/h at 0.01 precision, sampled every tick so the average is over time rather than frames
+    pub speed: Option<RunningStat>,
+    pub heart_rate: Option<RunningStat>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct ClockSynced {
+    index: usize,
+    // Seconds, positive when the machine was ahead of the app
+    correction: f64,
+}
+
+// Time spent in the current step. Follows the machine's elapsed time when it reports one, so step
+// boundaries line up with what the console shows, and falls back to the app's clock otherwise.
+pub struct StepClock {
+    started: Instant,
+    // Machine elapsed time that lines up with `started`, in seconds
+    machine_started: Option<i64>,
+    last_machine: Option<u16>,
+}
+
+impl StepClock {
+    // Starts at `elapsed` rather than zero for a step picked up part way through.
+    pub fn start(elapsed: Duration) -> StepClock {
+        let now = Instant::now();
+        StepClock { started: now.checked_sub(elapsed).unwrap_or(now), machine_started: None, last_machine: None }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    // Feeds the machine's latest elapsed time and returns the correction in seconds when the step
+    // clock had drifted far enough to be moved. A machine clock that goes backwards has been reset,
+    // e.g. for a new session, so it's lined up again instead of treated as drift.
+    pub fn sync(&mut self, machine_elapsed: Option<u16>) -> Option<f64> {
+        let machine = machine_elapsed?;
+        let local = self.started.elapsed();
+        let reset = self.last_machine.is_some_and(|last| machine < last);
+        self.last_machine = Some(machine);
+
+        let machine_started = match self.machine_started {
+            Some(machine_started) if !reset => machine_started,
+            _ => {
+                self.machine_started = Some(machine as i64 - local.as_secs() as i64);
+                return None;
+            }
+        };
+        let machine_step = (machine as i64 - machine_started).max(0) as u64;
+        let correction = machine_step as f64 - local.as_secs_f64();
+        if correction.abs() < CLOCK_SYNC_THRESHOLD {
+            return None;
+        }
+        let now = Instant::now();
+        self.started = now.checked_sub(Duration::from_secs(machine_step)).unwrap_or(now);
+        Some(correction)
+    }
+}
+
+// Ends the step on whichever comes first, the distance the machine reports reaching the step's or
+// the step's duration running out, so a belt that lags behind the target still moves on. Steps
+// with no distance, like a rest at 0 km/h, and machines that don't report distance only go by time.
+pub fn step_completion(step: &WorkoutStep, elapsed: Duration, travelled: Option<u32>) -> Option<CompletionMethod> {
+    let distance_reached =
+        step.distance > 0 && travelled.is_some_and(|travelled| travelled + DISTANCE_TOLERANCE >= step.distance);
+    if distance_reached {
+        return Some(CompletionMethod::Distance);
+    }
+    (elapsed.as_secs() >= step.duration as u64).then_some(CompletionMethod::Time)
+}
+
+// Percent of the workout done, 0 to 100. Distance gets most of the weight when the machine reports
+// it, since time drifts when the belt lags behind the target speed. Workouts that run long stop at
+// 100.
+pub fn workout_progress(workout: &Workout, elapsed: Duration, travelled: Option<u32>) -> f64 {
+    let time = if workout.duration == 0 { 1.0 } else { elapsed.as_secs_f64() / workout.duration as f64 };
+    let fraction = match travelled {
+        Some(travelled) if workout.distance > 0 => {
+            DISTANCE_WEIGHT * (travelled as f64 / workout.distance as f64) + (1.0 - DISTANCE_WEIGHT) * time
+        }
+        _ => time,
+    };
+    (fraction * 100.0).clamp(0.0, 100.0)
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct WorkoutRemaining {
+    pub time_secs: u64,
+    pub distance_m: u32,
+}
+
+// What's left of the whole workout from `position`. Counts done steps at their planned totals and
+// caps the current step at its plan, the same way `workout_progress` does, so the two agree.
+pub fn workout_remaining(position: &WorkoutPosition) -> WorkoutRemaining {
+    let workout = &position.workout;
+    let done = &workout.steps[..position.step_index.min(workout.steps.len())];
+    let mut elapsed: u64 = done.iter().map(|step| step.duration as u64).sum();
+    let mut travelled: u32 = done.iter().map(|step| step.distance).sum();
+    if let Some(step) = workout.steps.get(position.step_index) {
+        elapsed += position.step_elapsed.min(step.duration as u64);
+        travelled += position.step_travelled.unwrap_or(0).min(step.distance);
+    }
+    WorkoutRemaining {
+        time_secs: (workout.duration as u64).saturating_sub(elapsed),
+        distance_m: workout.distance.saturating_sub(travelled),
+    }
+}
+
+fn emit<S: Serialize + Clone>(app: &AppHandle, event: &str, payload: S) {
+    if let Err(e) = app.emit_all(event, payload) {
+        eprintln!("Error emitting {}: {:?}", event, e);
+    }
+}
+
+// Requests control again and resends when the machine says it took control back.
+async fn try_send(app: &AppHandle, command: TreadmillCommands) -> Result<(), CommandError> {
+    let connection = app.state::<AppState>().connection()?;
+    match connection.send_regaining_control(command).await {
+        Ok(true) => emit(app, "control-granted", ()),
+        Ok(false) => {}
+        Err(e) => {
+            if control_lost(&e) {
+                control_refused(app);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// A dropped write or an unanswered request can go through on a retry. Anything else, like an
+// unarmed belt or a target the machine won't take, fails the same way every time.
+fn transient(e: &CommandError) -> bool {
+    matches!(
+        e,
+        CommandError::BleError(_)
+            | CommandError::NotConnected
+            | CommandError::ProcedureTimeout(_)
+            | CommandError::AdapterPoweredOff
+    )
+}
+
+// Tries `write` up to `WRITE_ATTEMPTS` times, `delay` apart, giving up straight away on errors a
+// retry won't fix.
+async fn with_retries<T, F, Fut>(command: TreadmillCommands, delay: Duration, mut write: F) -> Result<T, CommandError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, CommandError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match write().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt == WRITE_ATTEMPTS || !transient(&e) => return Err(e),
+            Err(e) => eprintln!("Error sending workout command {:?} (attempt {}): {:?}", command, attempt, e),
+        }
+        attempt += 1;
+        time::sleep(delay).await;
+    }
+}
+
+async fn send(app: &AppHandle, command: TreadmillCommands) -> Result<(), CommandError> {
+    let result = with_retries(command, WRITE_RETRY_DELAY, || try_send(app, command)).await;
+    if let Err(e) = &result {
+        write_failed(app, command, e);
+    }
+    result
+}
+
+fn write_failed(app: &AppHandle, command: TreadmillCommands, e: &CommandError) {
+    eprintln!("Error sending workout command {:?}: {:?}", command, e);
+    emit(app, "control-write-failed", ControlWriteFailed { command: format!("{:?}", command), error: e.to_string() });
+}
+
+// Pauses the belt and holds the workout where it is, so `resume_workout` can pick it up once the
+// treadmill answers again. Ends the runner.
+async fn hold_workout(app: &AppHandle) {
+    if let Err(e) = try_send(app, TreadmillCommands::StopOrPause).await {
+        eprintln!("Error pausing the belt after a failed workout command: {:?}", e);
+    }
+    interrupt_workout(app);
+}
+
+// The step's incline clamped to the machine's range, `None` when the machine can't set one.
+fn step_incline(capabilities: Option<&MachineCapabilities>, incline: i16) -> Option<i16> {
+    let supported = capabilities.is_some_and(|c| {
+        c.target_settings.map_or(c.inclination_range.is_some(), |target| target.inclination)
+    });
+    if !supported {
+        return None;
+    }
+    let range = capabilities.and_then(|c| c.inclination_range);
+    Some(range.map_or(incline, |range| incline.max(range.minimum).min(range.maximum)))
+}
+
+// What setting a step's targets did, for the runner to record and report.
+#[derive(Debug, Default)]
+struct StepTargets {
+    speed: Option<u16>,
+    incline: Option<i16>,
+    // The step asked for an incline the machine can't set, and it hadn't been said before
+    incline_unsupported: bool,
+    regained_control: bool,
+    // The command that didn't go through, the targets after it weren't sent
+    failed: Option<(TreadmillCommands, CommandError)>,
+}
+
+impl StepTargets {
+    async fn send(&mut self, connection: &TreadmillConnection, command: TreadmillCommands) -> bool {
+        match with_retries(command, WRITE_RETRY_DELAY, || connection.send_regaining_control(command)).await {
+            Ok(regained) => {
+                self.regained_control |= regained;
+                true
+            }
+            Err(e) => {
+                self.failed = Some((command, e));
+                false
+            }
+        }
+    }
+}
+
+// Sets the step's speed, capped at the configured maximum rather than refusing the whole workout,
+// then its incline. Machines that can't set an incline run the workout flat, `notified` says
+// whether that was already reported.
+async fn set_step_targets(
+    state: &AppState,
+    connection: &TreadmillConnection,
+    capabilities: Option<&MachineCapabilities>,
+    step: &WorkoutStep,
+    notified: &mut bool,
+) -> StepTargets {
+    let mut targets = StepTargets::default();
+    let speed = match state.settings.lock().unwrap().max_speed {
+        Some(max_speed) => step.pace.min(max_speed),
+        None => step.pace,
+    };
+    if let Err(e) = ramp_target_speed(state, connection, speed).await {
+        eprintln!("Error ramping workout speed: {:?}", e);
+    }
+    if !targets.send(connection, TreadmillCommands::SetTargetSpeed(speed)).await {
+        return targets;
+    }
+    targets.speed = Some(speed);
+    match step_incline(capabilities, step.angle) {
+        Some(incline) => {
+            let sent = targets.send(connection, TreadmillCommands::SetTargetInclination(incline)).await;
+            targets.incline = sent.then_some(incline);
+        }
+        None if step.angle != 0 && !*notified => {
+            targets.incline_unsupported = true;
+            *notified = true;
+        }
+        None => {}
+    }
+    targets
+}
+
+// Sets the step's targets and reports what happened. Fails when the workout has to be held.
+async fn apply_step_targets(
+    app: &AppHandle,
+    capabilities: Option<&MachineCapabilities>,
+    step: &WorkoutStep,
+    notified: &mut bool,
+) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let connection = match state.connection() {
+        Ok(connection) => connection,
+        Err(e) => {
+            write_failed(app, TreadmillCommands::SetTargetSpeed(step.pace), &e);
+            return Err(e);
+        }
+    };
+    let targets = set_step_targets(&state, &connection, capabilities, step, notified).await;
+    if targets.regained_control {
+        emit(app, "control-granted", ());
+    }
+    record_targets(app, targets.speed, targets.incline);
+    if targets.incline_unsupported {
+        println!("Treadmill can't set an incline, ignoring workout inclines.");
+        emit(app, "incline-unsupported", step.angle);
+    }
+    match targets.failed {
+        Some((command, e)) => {
+            if control_lost(&e) {
+                control_refused(app);
+            }
+            write_failed(app, command, &e);
+            Err(e)
+        }
+        None => Ok(()),
+    }
+}
+
+fn total_distance(app: &AppHandle) -> Option<u32> {
+    let state = app.state::<AppState>();
+    let data = state.latest_data.lock().unwrap();
+    data.as_ref().and_then(|d| d.total_distance)
+}
+
+// Speed and heart rate over a step, for its lap.
+#[derive(Debug, Default)]
+struct LapStats {
+    speed: Option<RunningStat>,
+    heart_rate: Option<RunningStat>,
+}
+
+impl LapStats {
+    fn sample(&mut self, data: &TreadmillData) {
+        RunningStat::add(&mut self.speed, data.speed as f64);
+        if let Some(value) = data.heart_rate {
+            RunningStat::add(&mut self.heart_rate, value as f64);
+        }
+    }
+
+    fn lap(self, index: usize, step: &WorkoutStep, method: CompletionMethod, elapsed: Duration, travelled: Option<u32>) -> Lap {
+        Lap {
+            index,
+            name: step.name.clone(),
+            method,
+            duration: elapsed.as_secs(),
+            distance: travelled,
+            speed: self.speed,
+            heart_rate: self.heart_rate,
+        }
+    }
+}
+
+// Adds the latest speed and heart rate to the step's stats.
+fn sample(app: &AppHandle, stats: &mut LapStats) {
+    let state = app.state::<AppState>();
+    let data = state.latest_data.lock().unwrap();
+    if let Some(data) = data.as_ref() {
+        stats.sample(data);
+    }
+}
+
+fn record_lap(app: &AppHandle, lap: Lap) {
+    record_lap_in(&app.state::<AppState>(), lap.clone());
+    emit(app, "lap-complete", lap);
+}
+
+fn record_lap_in(state: &AppState, lap: Lap) {
+    if let Some(log) = state.session_log.lock().unwrap().as_mut() {
+        log.lap(&lap);
+    }
+    state.laps.lock().unwrap().push(lap);
+}
+
+// Laps of the current or last workout, see `get_lap_data`.
+pub fn laps(state: &AppState) -> Vec<Lap> {
+    state.laps.lock().unwrap().clone()
+}
+
+fn machine_elapsed(app: &AppHandle) -> Option<u16> {
+    let state = app.state::<AppState>();
+    let data = state.latest_data.lock().unwrap();
+    data.as_ref().and_then(|d| d.elapsed_time)
+}
+
+// Runs `workout` from the start, or from `resume` when picking up an interrupted one, in which case
+// the session carries on rather than starting over.
+pub async fn run_workout(app: AppHandle, workout: Workout, resume: Option<WorkoutPosition>) {
+    let state = app.state::<AppState>();
+    if resume.is_none() {
+        println!("Starting workout {}.", workout.name);
+        if let Err(e) = last_run::record(&app, &workout.name) {
+            eprintln!("Error recording workout run: {:?}", e);
+        }
+        state.session_distance.lock().unwrap().reset();
+        state.distance_estimate.lock().unwrap().reset();
+        state.splits.lock().unwrap().reset();
+        state.laps.lock().unwrap().clear();
+        *state.session_stats.lock().unwrap() = SessionStats::default();
+    } else {
+        println!("Resuming workout {} at {:?}.", workout.name, resume);
+    }
+    *state.workout_position.lock().unwrap() = Some(WorkoutPosition {
+        workout: workout.clone(),
+        step_index: resume.as_ref().map_or(0, |r| r.step_index),
+        step_elapsed: 0,
+        step_travelled: None,
+    });
+    match open_session_log(&app, &state) {
+        Ok(id) => {
+            if let Err(e) = session_log::record_workout(&app, &id, &workout.name) {
+                eprintln!("Error indexing session log: {:?}", e);
+            }
+        }
+        Err(e) => eprintln!("Error starting session log: {:?}", e),
+    }
+    let capabilities = match machine_capabilities(&state).await {
+        Ok(capabilities) => Some(capabilities),
+        Err(e) => {
+            eprintln!("Error reading machine capabilities, workout inclines won't be set: {:?}", e);
+            None
+        }
+    };
+    if send(&app, TreadmillCommands::StartOrResume).await.is_err() {
+        return hold_workout(&app).await;
+    }
+
+    // Planned totals of the steps already done, so progress doesn't jump when a step ends early
+    let mut completed_duration = Duration::ZERO;
+    let mut completed_distance = 0;
+    let mut incline_notified = false;
+    let first_step = resume.as_ref().map_or(0, |r| r.step_index);
+    for step in &workout.steps[..first_step.min(workout.steps.len())] {
+        completed_duration += Duration::from_secs(step.duration as u64);
+        completed_distance += step.distance;
+    }
+    for (index, step) in workout.steps.iter().enumerate().skip(first_step) {
+        emit(&app, "step-started", StepEvent { index, name: step.name.clone() });
+        persist_state(&app, |snapshot| {
+            snapshot.workout = Some(workout.name.clone());
+            snapshot.step_index = Some(index);
+        });
+        // Only the step we stopped in is part done
+        let (done_elapsed, done_travelled) = match resume.as_ref().filter(|r| r.step_index == index) {
+            Some(r) => (Duration::from_secs(r.step_elapsed), r.step_travelled.unwrap_or(0)),
+            None => (Duration::ZERO, 0),
+        };
+        if let Some(position) = state.workout_position.lock().unwrap().as_mut() {
+            position.step_index = index;
+            position.step_elapsed = done_elapsed.as_secs();
+            position.step_travelled = resume.as_ref().filter(|r| r.step_index == index).and_then(|r| r.step_travelled);
+        }
+        // Step timing only starts once the targets are set, so a held step picks up where it was
+        if apply_step_targets(&app, capabilities.as_ref(), step, &mut incline_notified).await.is_err() {
+            return hold_workout(&app).await;
+        }
+
+        let mut clock = StepClock::start(done_elapsed);
+        let start_distance = total_distance(&app);
+        let mut lap_stats = LapStats::default();
+        let (method, travelled) = loop {
+            time::sleep(TICK).await;
+            sample(&app, &mut lap_stats);
+            if let Some(correction) = clock.sync(machine_elapsed(&app)) {
+                println!("Step {} clock off from the machine by {:.1}s, corrected.", index, correction);
+                emit(&app, "clock-synced", ClockSynced { index, correction });
+            }
+            let travelled = match (start_distance, total_distance(&app)) {
+                (Some(start), Some(current)) => Some(current.saturating_sub(start) + done_travelled),
+                _ => None,
+            };
+            if let Some(position) = state.workout_position.lock().unwrap().as_mut() {
+                position.step_index = index;
+                position.step_elapsed = clock.elapsed().as_secs();
+                position.step_travelled = travelled;
+            }
+            let progress = workout_progress(
+                &workout,
+                completed_duration + clock.elapsed().min(Duration::from_secs(step.duration as u64)),
+                travelled.map(|travelled| completed_distance + travelled.min(step.distance)),
+            );
+            emit(&app, "workout-progress", progress);
+            if let Some(method) = step_completion(step, clock.elapsed(), travelled) {
+                break (method, travelled);
+            }
+        };
+        completed_duration += Duration::from_secs(step.duration as u64);
+        completed_distance += step.distance;
+
+        println!("Step {} ({}) complete by {:?}.", index, step.name, method);
+        emit(&app, "step-complete", StepComplete { index, name: step.name.clone(), method });
+        record_lap(&app, lap_stats.lap(index, step, method, clock.elapsed(), travelled));
+    }
+
+    // Already reported, and there's nothing left of the workout to hold
+    let _ = send(&app, TreadmillCommands::StopOrPause).await;
+    state.interlock.lock().unwrap().reset();
+    persist_state(&app, |snapshot| {
+        snapshot.workout = None;
+        snapshot.step_index = None;
+    });
+    println!("Workout {} complete.", workout.name);
+    emit(&app, "workout-progress", 100.0);
+    if let Err(e) = close_session_log(&state) {
+        eprintln!("Error saving session log: {:?}", e);
+    }
+    emit(&app, "workout-complete", workout.name);
+    // Done, so `stop_workout` has nothing to stop
+    state.workout_runner.lock().unwrap().take();
+    *state.workout_position.lock().unwrap() = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{capabilities::InclinationRange, control_point::ResultCode, mock_machine::MockMachine};
+
+    fn send_with_retries(machine: &MockMachine, command: TreadmillCommands) -> Result<(), CommandError> {
+        let connection = machine.connection(Duration::from_secs(1));
+        let connection = &connection;
+        tauri::async_runtime::block_on(with_retries(command, Duration::ZERO, || async move {
+            connection.send_commands(vec![command]).await.map(drop)
+        }))
+    }
+
+    fn step(duration: u16, distance: u32) -> WorkoutStep {
+        WorkoutStep { name: "step".to_string(), duration, distance, pace: 0, angle: 0 }
+    }
+
+    #[test]
+    fn step_completes_on_distance_within_tolerance() {
+        let step = step(120, 400);
+        assert_eq!(step_completion(&step, Duration::from_secs(60), Some(390)), None);
+        assert_eq!(step_completion(&step, Duration::from_secs(60), Some(395)), Some(CompletionMethod::Distance));
+    }
+
+    #[test]
+    fn step_times_out_when_the_belt_lags_behind() {
+        let step = step(120, 400);
+        assert_eq!(step_completion(&step, Duration::from_secs(119), Some(300)), None);
+        assert_eq!(step_completion(&step, Duration::from_secs(120), Some(300)), Some(CompletionMethod::Time));
+    }
+
+    #[test]
+    fn rest_step_runs_for_its_duration() {
+        let rest = step(90, 0);
+        assert_eq!(step_completion(&rest, Duration::from_secs(1), Some(0)), None);
+        assert_eq!(step_completion(&rest, Duration::from_secs(90), Some(0)), Some(CompletionMethod::Time));
+    }
+
+    #[test]
+    fn step_falls_back_to_time_without_distance() {
+        let step = step(120, 400);
+        assert_eq!(step_completion(&step, Duration::from_secs(60), None), None);
+        assert_eq!(step_completion(&step, Duration::from_secs(120), None), Some(CompletionMethod::Time));
+    }
+
+    #[test]
+    fn failed_writes_are_retried_until_one_goes_through() {
+        let machine = MockMachine::default();
+        machine.fail_writes(WRITE_ATTEMPTS - 1, || CommandError::BleError("write failed".to_string()));
+        assert!(send_with_retries(&machine, TreadmillCommands::SetTargetSpeed(800)).is_ok());
+        assert_eq!(machine.writes().len(), WRITE_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn retries_give_up_after_the_last_attempt() {
+        let machine = MockMachine::default();
+        machine.fail_writes(u32::MAX, || CommandError::BleError("write failed".to_string()));
+        let result = send_with_retries(&machine, TreadmillCommands::SetTargetSpeed(800));
+        assert!(matches!(result, Err(CommandError::BleError(_))));
+        assert_eq!(machine.writes().len(), WRITE_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn errors_a_retry_wont_fix_fail_straight_away() {
+        let machine = MockMachine::default();
+        machine.fail_writes(u32::MAX, || CommandError::NotSupported("target speed".to_string()));
+        let result = send_with_retries(&machine, TreadmillCommands::SetTargetSpeed(800));
+        assert!(matches!(result, Err(CommandError::NotSupported(_))));
+        assert_eq!(machine.writes().len(), 1);
+
+        let machine = MockMachine::default();
+        machine.answer(0x02, &[ResultCode::InvalidParameter]);
+        let result = send_with_retries(&machine, TreadmillCommands::SetTargetSpeed(800));
+        assert!(matches!(result, Err(CommandError::ControlRejected(_))));
+        assert_eq!(machine.writes().len(), 1);
+    }
+
+    fn two_step_workout() -> Workout {
+        Workout {
+            name: "test".to_string(),
+            description: String::new(),
+            duration: 120,
+            distance: 400,
+            steps: vec![step(60, 200), step(60, 200)],
+        }
+    }
+
+    #[test]
+    fn progress_is_half_way_at_the_midpoint() {
+        let workout = two_step_workout();
+        assert_eq!(workout_progress(&workout, Duration::from_secs(60), Some(200)), 50.0);
+        assert_eq!(workout_progress(&workout, Duration::from_secs(60), None), 50.0);
+    }
+
+    #[test]
+    fn progress_leans_on_distance_and_stops_at_100() {
+        let workout = two_step_workout();
+        let progress = workout_progress(&workout, Duration::from_secs(60), Some(300));
+        assert!((progress - 70.0).abs() < 1e-9);
+        assert_eq!(workout_progress(&workout, Duration::from_secs(300), Some(500)), 100.0);
+        assert_eq!(workout_progress(&workout, Duration::ZERO, Some(0)), 0.0);
+    }
+
+    fn remaining_at(step_index: usize, step_elapsed: u64, step_travelled: Option<u32>) -> (u64, u32) {
+        let position = WorkoutPosition { workout: two_step_workout(), step_index, step_elapsed, step_travelled };
+        let remaining = workout_remaining(&position);
+        (remaining.time_secs, remaining.distance_m)
+    }
+
+    #[test]
+    fn remaining_counts_down_from_the_whole_workout() {
+        assert_eq!(remaining_at(0, 0, Some(0)), (120, 400));
+        assert_eq!(remaining_at(1, 0, Some(0)), (60, 200));
+        assert_eq!(remaining_at(1, 60, Some(200)), (0, 0));
+        assert_eq!(remaining_at(2, 0, None), (0, 0));
+    }
+
+    #[test]
+    fn remaining_counts_skipped_and_overrun_steps_at_their_plan() {
+        // Skipped a few seconds into the first step
+        assert_eq!(remaining_at(1, 10, Some(30)), (50, 170));
+        // Running past the plan doesn't eat into the next step
+        assert_eq!(remaining_at(0, 90, Some(300)), (60, 200));
+        // No distance from the machine leaves the step's distance outstanding
+        assert_eq!(remaining_at(0, 30, None), (90, 400));
+    }
+
+    fn near(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() < 0.5
+    }
+
+    #[test]
+    fn step_clock_follows_a_machine_running_ahead() {
+        let mut clock = StepClock::start(Duration::from_secs(10));
+        // The first reading only lines the clocks up
+        assert_eq!(clock.sync(Some(100)), None);
+        let correction = clock.sync(Some(105)).unwrap();
+        assert!(near(correction, 5.0), "{}", correction);
+        assert!(near(clock.elapsed().as_secs_f64(), 15.0));
+        // Under the threshold, left alone
+        assert_eq!(clock.sync(Some(106)), None);
+        assert_eq!(clock.sync(None), None);
+    }
+
+    #[test]
+    fn step_clock_lines_up_again_after_the_machine_resets() {
+        let mut clock = StepClock::start(Duration::from_secs(30));
+        assert_eq!(clock.sync(Some(500)), None);
+        assert_eq!(clock.sync(Some(2)), None);
+        assert!(near(clock.elapsed().as_secs_f64(), 30.0));
+        let correction = clock.sync(Some(10)).unwrap();
+        assert!(near(correction, 8.0), "{}", correction);
+    }
+
+    fn capabilities(inclination_range: Option<InclinationRange>) -> MachineCapabilities {
+        MachineCapabilities {
+            features: None,
+            target_settings: None,
+            speed_range: None,
+            inclination_range,
+            power_range: None,
+            control_opcodes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn step_inclines_are_clamped_to_the_machine_range() {
+        let capabilities = capabilities(Some(InclinationRange { minimum: 0, maximum: 150, minimum_increment: 5 }));
+        assert_eq!(step_incline(Some(&capabilities), 30), Some(30));
+        assert_eq!(step_incline(Some(&capabilities), 200), Some(150));
+        assert_eq!(step_incline(Some(&capabilities), -20), Some(0));
+    }
+
+    #[test]
+    fn machines_without_incline_get_no_incline_write() {
+        assert_eq!(step_incline(Some(&capabilities(None)), 30), None);
+        assert_eq!(step_incline(None, 30), None);
+    }
+
+    #[test]
+    fn step_with_an_angle_writes_speed_and_incline() {
+        let step = WorkoutStep { name: "hill".to_string(), duration: 60, distance: 167, pace: 1000, angle: 40 };
+        let capabilities = capabilities(Some(InclinationRange { minimum: 0, maximum: 150, minimum_increment: 5 }));
+        let machine = MockMachine::default();
+        let connection = machine.connection(Duration::from_secs(1));
+        let state = AppState::default();
+
+        let mut notified = false;
+        let targets = tauri::async_runtime::block_on(set_step_targets(
+            &state,
+            &connection,
+            Some(&capabilities),
+            &step,
+            &mut notified,
+        ));
+        assert!(targets.failed.is_none());
+        assert_eq!((targets.speed, targets.incline), (Some(1000), Some(40)));
+        assert!(!targets.incline_unsupported);
+        assert_eq!(machine.writes(), [vec![0x02, 0xE8, 0x03], vec![0x03, 0x28, 0x00]]);
+    }
+
+    #[test]
+    fn machines_without_incline_run_hilly_steps_flat_with_one_notice() {
+        let capabilities = capabilities(None);
+        let machine = MockMachine::default();
+        let connection = machine.connection(Duration::from_secs(1));
+        let state = AppState::default();
+        let steps = [
+            WorkoutStep { name: "flat".to_string(), duration: 60, distance: 167, pace: 1000, angle: 0 },
+            WorkoutStep { name: "hill".to_string(), duration: 60, distance: 167, pace: 1000, angle: 40 },
+            WorkoutStep { name: "hill".to_string(), duration: 60, distance: 167, pace: 1000, angle: 60 },
+        ];
+
+        let mut notified = false;
+        let notices: Vec<bool> = steps
+            .iter()
+            .map(|step| {
+                let targets = tauri::async_runtime::block_on(set_step_targets(
+                    &state,
+                    &connection,
+                    Some(&capabilities),
+                    step,
+                    &mut notified,
+                ));
+                assert!(targets.failed.is_none());
+                assert_eq!(targets.incline, None);
+                targets.incline_unsupported
+            })
+            .collect();
+        assert_eq!(notices, [false, true, false]);
+        assert_eq!(machine.writes(), vec![vec![0x02, 0xE8, 0x03]; 3]);
+    }
+
+    #[test]
+    fn each_finished_step_leaves_a_lap_in_the_log() {
+        let dir = std::env::temp_dir().join(format!("treadmill-laps-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let state = AppState::default();
+        *state.session_log.lock().unwrap() = Some(session_log::SessionLog::start_in(&dir).unwrap());
+        let workout = two_step_workout();
+
+        let mut fast = LapStats::default();
+        for (speed, heart_rate) in [(1000, Some(140)), (1200, Some(150)), (1100, None)] {
+            fast.sample(&TreadmillData { speed, heart_rate, ..Default::default() });
+        }
+        record_lap_in(&state, fast.lap(0, &workout.steps[0], CompletionMethod::Distance, Duration::from_secs(58), Some(197)));
+        let mut recover = LapStats::default();
+        recover.sample(&TreadmillData { speed: 500, ..Default::default() });
+        record_lap_in(&state, recover.lap(1, &workout.steps[1], CompletionMethod::Time, Duration::from_secs(60), None));
+
+        let laps = laps(&state);
+        let boundaries: Vec<_> = laps.iter().map(|lap| (lap.index, lap.method, lap.duration, lap.distance)).collect();
+        assert_eq!(boundaries, [(0, CompletionMethod::Distance, 58, Some(197)), (1, CompletionMethod::Time, 60, None)]);
+        let fast = &laps[0];
+        assert_eq!(fast.speed.map(|s| (s.average, s.min, s.max)), Some((1100.0, 1000.0, 1200.0)));
+        assert_eq!(fast.heart_rate.map(|s| (s.average, s.min, s.max)), Some((145.0, 140.0, 150.0)));
+        assert_eq!(laps[1].speed.map(|s| s.average), Some(500.0));
+        assert!(laps[1].heart_rate.is_none());
+
+        let log = state.session_log.lock().unwrap().take().unwrap();
+        let path = log.finish(&SessionStats::default()).unwrap();
+        let id = path.file_stem().unwrap().to_str().unwrap();
+        let rows: Vec<_> = session_log::load_from(&dir, id).unwrap().into_iter().filter(|t| t.event == "lap").collect();
+        let rows: Vec<_> = rows.iter().map(|t| (t.speed, t.total_distance, t.heart_rate, t.elapsed_time)).collect();
+        assert_eq!(rows, [(Some(1100), Some(197), Some(145), Some(58)), (Some(500), None, None, Some(60))]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
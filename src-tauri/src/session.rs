@@ -0,0 +1,459 @@
+// Per session bookkeeping on top of the machine's cumulative counters.
+
+use crate::{units, TreadmillData};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// Total distance is a uint24 of meters
+const TOTAL_DISTANCE_MAX: u32 = 0xFF_FFFF;
+// Meters either side of the uint24 limit within which going backwards counts as the counter
+// wrapping, and from zero within which it counts as the machine zeroing it
+const WRAP_MARGIN: u32 = 1000;
+
+// Why the machine's total distance went backwards.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceDrop {
+    // The uint24 counter overflowed
+    Wrap,
+    // The machine zeroed it
+    Reset,
+    // Went back to somewhere other than zero, e.g. after a glitched frame
+    Jump,
+}
+
+// Meters covered since the session started. The machine's total distance keeps counting across
+// sessions, some machines zero it on their own and odometers eventually overflow the uint24, so
+// when it goes backwards the distance covered so far is carried over instead of going negative.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SessionDistance {
+    start: Option<u32>,
+    carried: u32,
+    last: u32,
+}
+
+impl SessionDistance {
+    // Feeds the latest total distance and returns the session distance, along with why the total
+    // went backwards when it did.
+    pub fn update(&mut self, total_distance: u32) -> (u32, Option<DistanceDrop>) {
+        let mut drop = None;
+        match self.start {
+            None => self.start = Some(total_distance),
+            Some(start) if total_distance < self.last => {
+                self.carried += self.last.saturating_sub(start);
+                let kind = if total_distance >= WRAP_MARGIN {
+                    DistanceDrop::Jump
+                } else if self.last > TOTAL_DISTANCE_MAX - WRAP_MARGIN {
+                    DistanceDrop::Wrap
+                } else {
+                    DistanceDrop::Reset
+                };
+                if kind == DistanceDrop::Wrap {
+                    // The meters between the last frame and the overflow
+                    self.carried += TOTAL_DISTANCE_MAX + 1 - self.last;
+                }
+                // Counting on from zero would add the meters the counter already shows again
+                self.start = Some(if kind == DistanceDrop::Jump { total_distance } else { 0 });
+                drop = Some(kind);
+            }
+            Some(_) => {}
+        }
+        self.last = total_distance;
+        let distance = self.carried + total_distance.saturating_sub(self.start.unwrap_or(total_distance));
+        (distance, drop)
+    }
+
+    // The next reported distance becomes the new zero.
+    pub fn reset(&mut self) {
+        *self = SessionDistance::default();
+    }
+}
+
+// Meters the machine has to report past the first reading before they're used to calibrate
+const MIN_CALIBRATION_DISTANCE: u32 = 100;
+// The factor is kept within this, anything further out is more likely a bad reading than drift
+const CALIBRATION_RANGE: std::ops::RangeInclusive<f64> = 0.5..=2.0;
+// Longer gaps between frames are treated as the belt having stopped rather than integrated
+const MAX_FRAME_GAP: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct DistanceCalibration {
+    // Reported meters per integrated meter, 1 until the machine has reported enough distance
+    pub factor: f64,
+    pub calibrated: bool,
+}
+
+// Session distance for frames without one, integrated from speed. Whenever the machine does
+// report distance the estimate picks up from there, and the reported meters against the
+// integrated ones give a factor that corrects the estimate for the rest of the session.
+#[derive(Debug, Clone, Copy)]
+pub struct DistanceEstimate {
+    last_frame: Option<Instant>,
+    // Meters from speed alone since the session started
+    integrated: f64,
+    // Integrated and reported meters at the first and the latest reported distance
+    first_report: Option<(f64, u32)>,
+    last_report: Option<(f64, u32)>,
+    calibration: DistanceCalibration,
+}
+
+impl Default for DistanceEstimate {
+    fn default() -> Self {
+        DistanceEstimate {
+            last_frame: None,
+            integrated: 0.0,
+            first_report: None,
+            last_report: None,
+            calibration: DistanceCalibration { factor: 1.0, calibrated: false },
+        }
+    }
+}
+
+impl DistanceEstimate {
+    // Feeds a frame's speed, in km/h at 0.01 precision, and session distance when it has one.
+    // Returns the estimated session distance in meters.
+    pub fn update(&mut self, speed: u16, reported: Option<u32>) -> u32 {
+        self.update_at(speed, reported, Instant::now())
+    }
+
+    fn update_at(&mut self, speed: u16, reported: Option<u32>, now: Instant) -> u32 {
+        if let Some(gap) = self.last_frame.map(|last| now - last).filter(|gap| *gap <= MAX_FRAME_GAP) {
+            self.integrated += units::kmh(speed) / 3.6 * gap.as_secs_f64();
+        }
+        self.last_frame = Some(now);
+
+        if let Some(reported) = reported {
+            let (first_integrated, first_reported) = *self.first_report.get_or_insert((self.integrated, reported));
+            let integrated = self.integrated - first_integrated;
+            if reported.saturating_sub(first_reported) >= MIN_CALIBRATION_DISTANCE && integrated > 0.0 {
+                let factor = (reported - first_reported) as f64 / integrated;
+                self.calibration = DistanceCalibration {
+                    factor: factor.clamp(*CALIBRATION_RANGE.start(), *CALIBRATION_RANGE.end()),
+                    calibrated: true,
+                };
+            }
+            self.last_report = Some((self.integrated, reported));
+            return reported;
+        }
+
+        let (base_integrated, base_reported) = self.last_report.unwrap_or((0.0, 0));
+        base_reported + ((self.integrated - base_integrated) * self.calibration.factor).round() as u32
+    }
+
+    pub fn calibration(&self) -> DistanceCalibration {
+        self.calibration
+    }
+
+    pub fn reset(&mut self) {
+        *self = DistanceEstimate::default();
+    }
+}
+
+// Tracks how long the belt has been running for the optional session time limit. Uses the
+// machine's elapsed time when it reports one, since that's what the user sees on the console.
+#[derive(Debug, Default)]
+pub struct SessionTimer {
+    started: Option<Instant>,
+    // Machine elapsed time when the belt started, it isn't always zeroed between sessions
+    machine_started: Option<u16>,
+    timed_out: bool,
+}
+
+impl SessionTimer {
+    // True once per session, on the first frame past `limit`. Stopping the belt starts a new
+    // session.
+    pub fn check(&mut self, limit: Duration, running: bool, machine_elapsed: Option<u16>) -> bool {
+        if !running {
+            *self = SessionTimer::default();
+            return false;
+        }
+
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let elapsed = match machine_elapsed {
+            Some(seconds) => {
+                let machine_started = *self.machine_started.get_or_insert(seconds);
+                Duration::from_secs(seconds.saturating_sub(machine_started) as u64)
+            }
+            None => started.elapsed(),
+        };
+        if self.timed_out || elapsed < limit {
+            return false;
+        }
+        self.timed_out = true;
+        true
+    }
+}
+
+// Min, max and mean of a value over the session, in the units of the field it came from.
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct RunningStat {
+    pub min: f64,
+    pub max: f64,
+    pub average: f64,
+    #[serde(skip)]
+    count: u32,
+}
+
+impl RunningStat {
+    pub fn add(stat: &mut Option<RunningStat>, value: f64) {
+        let Some(stat) = stat else {
+            *stat = Some(RunningStat { min: value, max: value, average: value, count: 1 });
+            return;
+        };
+        stat.min = stat.min.min(value);
+        stat.max = stat.max.max(value);
+        stat.count += 1;
+        stat.average += (value - stat.average) / stat.count as f64;
+    }
+}
+
+// Summary of the Treadmill Data frames seen this session. Stats are `None` until a frame carries
+// the field.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SessionStats {
+    pub frames: u32,
+    // Km/h at 0.01 precision
+    pub speed: Option<RunningStat>,
+    // Percent grade at 0.1 precision
+    pub inclination: Option<RunningStat>,
+    pub heart_rate: Option<RunningStat>,
+    // Meters, the session distance when there is one
+    pub distance: Option<u32>,
+    // Seconds, as reported by the machine
+    pub elapsed_time: Option<u16>,
+}
+
+impl SessionStats {
+    pub fn add(&mut self, data: &TreadmillData) {
+        self.frames += 1;
+        RunningStat::add(&mut self.speed, data.speed as f64);
+        if let Some(inclination) = data.inclination {
+            RunningStat::add(&mut self.inclination, inclination as f64);
+        }
+        if let Some(heart_rate) = data.heart_rate {
+            RunningStat::add(&mut self.heart_rate, heart_rate as f64);
+        }
+        if let Some(distance) = data.session_distance.or(data.total_distance) {
+            self.distance = Some(distance);
+        }
+        if let Some(elapsed_time) = data.elapsed_time {
+            self.elapsed_time = Some(elapsed_time);
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitUnit {
+    #[default]
+    Kilometer,
+    Mile,
+}
+
+impl SplitUnit {
+    fn meters(self) -> u32 {
+        match self {
+            SplitUnit::Kilometer => 1000,
+            SplitUnit::Mile => (units::KM_PER_MILE * 1000.0).round() as u32,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Split {
+    // Starting at 1
+    pub number: u32,
+    pub unit: SplitUnit,
+    // Seconds
+    pub duration: u32,
+    // "m:ss" per unit
+    pub pace: String,
+}
+
+// Times each kilometer or mile of the session distance. Uses the machine's elapsed time when it
+// reports one, like `SessionTimer`, and the app's clock otherwise. A frame that skips past more than
+// one boundary only completes one split, the next frame catches up.
+#[derive(Debug, Default)]
+pub struct Splits {
+    completed: u32,
+    // When the current split started, in seconds since the first frame
+    split_started: u32,
+    started: Option<Instant>,
+    machine_started: Option<u16>,
+}
+
+impl Splits {
+    pub fn update(&mut self, unit: SplitUnit, distance: u32, machine_elapsed: Option<u16>) -> Option<Split> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let elapsed = match machine_elapsed {
+            Some(seconds) => seconds.saturating_sub(*self.machine_started.get_or_insert(seconds)) as u32,
+            None => started.elapsed().as_secs() as u32,
+        };
+        if distance < (self.completed + 1) * unit.meters() {
+            return None;
+        }
+
+        self.completed += 1;
+        let duration = elapsed.saturating_sub(self.split_started);
+        self.split_started = elapsed;
+        Some(Split {
+            number: self.completed,
+            unit,
+            duration,
+            pace: format!("{}:{:02}", duration / 60, duration % 60),
+        })
+    }
+
+    pub fn reset(&mut self) {
+        *self = Splits::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(distance: &mut SessionDistance, totals: &[u32]) -> Vec<(u32, Option<DistanceDrop>)> {
+        totals.iter().map(|total| distance.update(*total)).collect()
+    }
+
+    #[test]
+    fn session_distance_carries_on_across_a_wrap() {
+        let mut distance = SessionDistance::default();
+        let readings = feed(&mut distance, &[TOTAL_DISTANCE_MAX - 20, TOTAL_DISTANCE_MAX - 5, 10, 40]);
+        assert_eq!(readings, [(0, None), (15, None), (31, Some(DistanceDrop::Wrap)), (61, None)]);
+    }
+
+    #[test]
+    fn session_distance_carries_on_across_a_reset_to_zero() {
+        let mut distance = SessionDistance::default();
+        let readings = feed(&mut distance, &[5000, 5400, 0, 30]);
+        assert_eq!(readings, [(0, None), (400, None), (400, Some(DistanceDrop::Reset)), (430, None)]);
+    }
+
+    #[test]
+    fn drops_away_from_zero_rebase_instead_of_recounting() {
+        let mut distance = SessionDistance::default();
+        let readings = feed(&mut distance, &[5000, 5400, 3000, 3050]);
+        assert_eq!(readings, [(0, None), (400, None), (400, Some(DistanceDrop::Jump)), (450, None)]);
+    }
+
+    fn frame(speed: u16, inclination: Option<i16>, heart_rate: Option<u8>, total_distance: u32) -> TreadmillData {
+        TreadmillData { speed, inclination, heart_rate, total_distance: Some(total_distance), ..Default::default() }
+    }
+
+    #[test]
+    fn stats_fold_frames_into_min_max_and_average() {
+        let mut stats = SessionStats::default();
+        for data in [
+            frame(600, Some(0), None, 10),
+            frame(1000, Some(20), Some(120), 40),
+            frame(800, None, Some(140), 70),
+            TreadmillData { elapsed_time: Some(30), ..frame(1200, Some(10), Some(160), 100) },
+        ] {
+            stats.add(&data);
+        }
+
+        assert_eq!(stats.frames, 4);
+        let speed = stats.speed.unwrap();
+        assert_eq!((speed.min, speed.max, speed.average), (600.0, 1200.0, 900.0));
+        let inclination = stats.inclination.unwrap();
+        assert_eq!((inclination.min, inclination.max, inclination.average), (0.0, 20.0, 10.0));
+        let heart_rate = stats.heart_rate.unwrap();
+        assert_eq!((heart_rate.min, heart_rate.max, heart_rate.average), (120.0, 160.0, 140.0));
+        assert_eq!(stats.distance, Some(100));
+        assert_eq!(stats.elapsed_time, Some(30));
+    }
+
+    #[test]
+    fn stats_stay_empty_for_fields_no_frame_carried() {
+        let mut stats = SessionStats::default();
+        stats.add(&TreadmillData { speed: 500, ..Default::default() });
+        assert!(stats.inclination.is_none());
+        assert!(stats.heart_rate.is_none());
+        assert_eq!(stats.distance, None);
+        assert_eq!(stats.speed.unwrap().average, 500.0);
+    }
+
+    // 100 m every 30 s, a 5:00 per km pace, as (frame, split) for every split that fired
+    fn run_splits(unit: SplitUnit, frames: u32) -> Vec<(u32, Split)> {
+        let mut splits = Splits::default();
+        (0..=frames)
+            .filter_map(|i| Some((i, splits.update(unit, i * 100, Some(i as u16 * 30))?)))
+            .collect()
+    }
+
+    #[test]
+    fn kilometer_splits_fire_at_each_boundary() {
+        let splits = run_splits(SplitUnit::Kilometer, 25);
+        let fired: Vec<(u32, u32, u32, &str)> =
+            splits.iter().map(|(i, split)| (*i, split.number, split.duration, split.pace.as_str())).collect();
+        assert_eq!(fired, [(10, 1, 300, "5:00"), (20, 2, 300, "5:00")]);
+    }
+
+    #[test]
+    fn mile_splits_fire_past_1609_meters() {
+        let splits = run_splits(SplitUnit::Mile, 35);
+        let fired: Vec<(u32, u32, u32)> = splits.iter().map(|(i, split)| (*i, split.number, split.duration)).collect();
+        assert_eq!(fired, [(17, 1, 510), (33, 2, 480)]);
+        assert!(splits.iter().all(|(_, split)| split.unit == SplitUnit::Mile));
+    }
+
+    #[test]
+    fn skipping_past_two_boundaries_catches_up_on_the_next_frame() {
+        let mut splits = Splits::default();
+        assert!(splits.update(SplitUnit::Kilometer, 0, Some(0)).is_none());
+        assert_eq!(splits.update(SplitUnit::Kilometer, 2100, Some(600)).map(|split| split.number), Some(1));
+        assert_eq!(splits.update(SplitUnit::Kilometer, 2150, Some(615)).map(|split| split.number), Some(2));
+        assert!(splits.update(SplitUnit::Kilometer, 2200, Some(630)).is_none());
+
+        splits.reset();
+        assert!(splits.update(SplitUnit::Kilometer, 500, Some(700)).is_none());
+    }
+
+    // Feeds one 10 km/h frame a second from `from` up to and including `to`, returning the last estimate
+    fn run_estimate(estimate: &mut DistanceEstimate, start: Instant, from: u64, to: u64) -> u32 {
+        (from..=to).map(|second| estimate.update_at(1000, None, start + Duration::from_secs(second))).last().unwrap()
+    }
+
+    #[test]
+    fn distance_estimate_is_corrected_once_the_machine_reports_distance() {
+        let start = Instant::now();
+        let mut estimate = DistanceEstimate::default();
+        assert_eq!(estimate.update_at(1000, Some(0), start), 0);
+        // 10 km/h for 36 seconds is 100 meters
+        assert_eq!(run_estimate(&mut estimate, start, 1, 36), 100);
+        assert!(!estimate.calibration().calibrated);
+
+        // The machine has counted 10% more than the speed gave
+        run_estimate(&mut estimate, start, 37, 71);
+        assert_eq!(estimate.update_at(1000, Some(220), start + Duration::from_secs(72)), 220);
+        let calibration = estimate.calibration();
+        assert!(calibration.calibrated);
+        assert!((calibration.factor - 1.1).abs() < 1e-6, "{}", calibration.factor);
+
+        // Picks up from the reported distance and applies the factor from there on
+        assert_eq!(run_estimate(&mut estimate, start, 73, 108), 330);
+    }
+
+    #[test]
+    fn distance_estimate_skips_long_gaps_and_calibrates_within_range() {
+        let start = Instant::now();
+        let mut estimate = DistanceEstimate::default();
+        estimate.update_at(1000, None, start);
+        // A gap over the limit is the belt having stopped, not 10 km/h the whole time
+        assert_eq!(estimate.update_at(1000, None, start + Duration::from_secs(60)), 0);
+
+        estimate.update_at(1000, Some(0), start + Duration::from_secs(61));
+        run_estimate(&mut estimate, start, 62, 96);
+        // Too short a reported distance to calibrate from
+        estimate.update_at(1000, Some(50), start + Duration::from_secs(97));
+        assert!(!estimate.calibration().calibrated);
+        // A reading far off the speed is clamped
+        estimate.update_at(1000, Some(1000), start + Duration::from_secs(98));
+        assert_eq!(estimate.calibration(), DistanceCalibration { factor: 2.0, calibrated: true });
+
+        estimate.reset();
+        assert_eq!(estimate.calibration(), DistanceCalibration { factor: 1.0, calibrated: false });
+    }
+}
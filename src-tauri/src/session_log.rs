@@ -0,0 +1,455 @@
+// Records a session's Treadmill Data as CSV in the app data dir. A session is started by the
+// workout runner or the frontend and keeps writing to the same file across reconnects, with a
+// marker row where the connection came back, until it is explicitly ended. Which workout a session
+// ran is kept in an index next to the logs, everything else is read back out of the CSV.
+//
+// Rows are flushed every few seconds and whenever the belt is stopped, and a cleanly ended log
+// closes with a `summary` row of session totals and an `end` marker. Logs left without the `end`
+// marker by a crash are closed with a `session-interrupted` marker on the next start. Workouts add a
+// `lap` row for every step they finish, in the same columns as the summary.
+
+use crate::{
+    error::CommandError,
+    runner::Lap,
+    session::{RunningStat, SessionStats},
+    timestamp_millis, TreadmillData,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tauri::AppHandle;
+
+const SESSIONS_DIR: &str = "sessions";
+const HEADER: &str = "timestamp,event,speed,total_distance,inclination,heart_rate,elapsed_time";
+const INDEX_FILE: &str = "index.json";
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct SessionLog {
+    pub id: String,
+    pub path: PathBuf,
+    writer: BufWriter<File>,
+    last_flush: Instant,
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn average(stat: Option<RunningStat>) -> String {
+    optional(stat.map(|s| s.average.round() as i64))
+}
+
+pub fn sessions_dir(app: &AppHandle) -> Result<PathBuf, CommandError> {
+    app.path_resolver()
+        .app_data_dir()
+        .map(|dir| dir.join(SESSIONS_DIR))
+        .ok_or_else(|| CommandError::Io("No app data directory.".to_string()))
+}
+
+impl SessionLog {
+    pub fn start(app: &AppHandle) -> Result<SessionLog, CommandError> {
+        SessionLog::start_in(&sessions_dir(app)?)
+    }
+
+    pub fn start_in(dir: &Path) -> Result<SessionLog, CommandError> {
+        fs::create_dir_all(dir).map_err(|e| CommandError::Io(e.to_string()))?;
+
+        let id = timestamp_millis().to_string();
+        let path = dir.join(format!("{}.csv", id));
+        let file = File::create(&path).map_err(|e| CommandError::Io(e.to_string()))?;
+        let mut log = SessionLog { id, path, writer: BufWriter::new(file), last_flush: Instant::now() };
+        log.write_line(HEADER);
+        Ok(log)
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Err(e) = writeln!(self.writer, "{}", line) {
+            eprintln!("Error writing session log {:?}: {:?}", self.path, e);
+        }
+    }
+
+    pub fn append(&mut self, data: &TreadmillData) {
+        self.append_row("data", data);
+    }
+
+    // The frame that completed a split, with `split` as its event.
+    pub fn split(&mut self, data: &TreadmillData) {
+        self.append_row("split", data);
+        self.flush();
+    }
+
+    fn append_row(&mut self, event: &str, data: &TreadmillData) {
+        let line = format!(
+            "{},{},{},{},{},{},{}",
+            timestamp_millis(),
+            event,
+            data.speed,
+            optional(data.total_distance),
+            optional(data.inclination),
+            optional(data.heart_rate),
+            optional(data.elapsed_time),
+        );
+        self.write_line(&line);
+        if data.speed == 0 || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            eprintln!("Error flushing session log {:?}: {:?}", self.path, e);
+        }
+        self.last_flush = Instant::now();
+    }
+
+    // A row with no data, e.g. `reconnect` where the connection came back.
+    pub fn marker(&mut self, event: &str) {
+        let line = format!("{},{},,,,,", timestamp_millis(), event);
+        self.write_line(&line);
+        self.flush();
+    }
+
+    // A workout step's averages, distance and duration.
+    pub fn lap(&mut self, lap: &Lap) {
+        let line = format!(
+            "{},lap,{},{},,{},{}",
+            timestamp_millis(),
+            average(lap.speed),
+            optional(lap.distance),
+            average(lap.heart_rate),
+            lap.duration,
+        );
+        self.write_line(&line);
+        self.flush();
+    }
+
+    // Ends the log with the session's averages, distance and elapsed time.
+    pub fn finish(mut self, stats: &SessionStats) -> Result<PathBuf, CommandError> {
+        let line = format!(
+            "{},summary,{},{},{},{},{}",
+            timestamp_millis(),
+            average(stats.speed),
+            optional(stats.distance),
+            average(stats.inclination),
+            average(stats.heart_rate),
+            optional(stats.elapsed_time),
+        );
+        self.write_line(&line);
+        self.marker("end");
+        self.writer.flush().map_err(|e| CommandError::Io(e.to_string()))?;
+        Ok(self.path)
+    }
+}
+
+// One row of a recorded session. Markers only have a timestamp and event.
+#[derive(Debug, Serialize, Clone)]
+pub struct Trackpoint {
+    // Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub event: String,
+    // Km/h at 0.01 precision
+    pub speed: Option<u16>,
+    // Meters
+    pub total_distance: Option<u32>,
+    // Percent grade at 0.1 precision
+    pub inclination: Option<i16>,
+    pub heart_rate: Option<u8>,
+    // Seconds, as reported by the machine
+    pub elapsed_time: Option<u16>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SessionSummary {
+    pub id: String,
+    // Milliseconds since the unix epoch
+    pub started: u64,
+    pub workout: Option<String>,
+    // Seconds
+    pub duration: u64,
+    // Meters, `None` when the machine didn't report distance
+    pub distance: Option<u32>,
+}
+
+// Workout name by session id.
+fn load_index(dir: &Path) -> BTreeMap<String, String> {
+    let path = dir.join(INDEX_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(e) => {
+            eprintln!("Error reading session index {:?}: {:?}", path, e);
+            return BTreeMap::new();
+        }
+    };
+    serde_json::from_str(&content).unwrap_or_else(|e| {
+        eprintln!("Error parsing session index: {:?}", e);
+        BTreeMap::new()
+    })
+}
+
+pub fn record_workout(app: &AppHandle, id: &str, workout: &str) -> Result<(), CommandError> {
+    record_workout_in(&sessions_dir(app)?, id, workout)
+}
+
+fn record_workout_in(dir: &Path, id: &str, workout: &str) -> Result<(), CommandError> {
+    let mut index = load_index(dir);
+    index.insert(id.to_string(), workout.to_string());
+    let content = serde_json::to_string_pretty(&index).map_err(|e| CommandError::Io(e.to_string()))?;
+    fs::write(dir.join(INDEX_FILE), content).map_err(|e| CommandError::Io(e.to_string()))
+}
+
+fn parse_line(line: &str) -> Option<Trackpoint> {
+    let fields: Vec<&str> = line.split(',').collect();
+    let [timestamp, event, speed, total_distance, inclination, heart_rate, elapsed_time] = fields[..] else {
+        return None;
+    };
+    Some(Trackpoint {
+        timestamp: timestamp.parse().ok()?,
+        event: event.to_string(),
+        speed: speed.parse().ok(),
+        total_distance: total_distance.parse().ok(),
+        inclination: inclination.parse().ok(),
+        heart_rate: heart_rate.parse().ok(),
+        elapsed_time: elapsed_time.parse().ok(),
+    })
+}
+
+// Every row of a session, skipping any that can't be parsed, e.g. one cut short by a crash.
+pub fn load(app: &AppHandle, id: &str) -> Result<Vec<Trackpoint>, CommandError> {
+    load_from(&sessions_dir(app)?, id)
+}
+
+pub fn load_from(dir: &Path, id: &str) -> Result<Vec<Trackpoint>, CommandError> {
+    // Ids are timestamps, anything else could point outside the sessions directory
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(CommandError::SessionNotFound(id.to_string()));
+    }
+    let path = dir.join(format!("{}.csv", id));
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(CommandError::SessionNotFound(id.to_string()));
+        }
+        Err(e) => return Err(CommandError::Io(e.to_string())),
+    };
+    Ok(content.lines().skip(1).filter_map(parse_line).collect())
+}
+
+// Distance only counts what was gained between frames, so a machine zeroing its counter
+// mid-session doesn't wipe out the distance before it.
+fn summarize(id: String, workout: Option<String>, trackpoints: &[Trackpoint]) -> SessionSummary {
+    let started = trackpoints.first().map_or_else(|| id.parse().unwrap_or(0), |t| t.timestamp);
+    let data: Vec<&Trackpoint> = trackpoints.iter().filter(|t| t.event == "data").collect();
+    let machine_duration = data.iter().rev().find_map(|t| t.elapsed_time);
+    let clock_duration = trackpoints.last().map_or(0, |t| t.timestamp.saturating_sub(started) / 1000);
+
+    let distances: Vec<u32> = data.iter().filter_map(|t| t.total_distance).collect();
+    let distance = (!distances.is_empty())
+        .then(|| distances.windows(2).map(|pair| pair[1].saturating_sub(pair[0])).sum());
+
+    SessionSummary {
+        id,
+        started,
+        workout,
+        duration: machine_duration.map_or(clock_duration, |seconds| seconds as u64),
+        distance,
+    }
+}
+
+// Newest first.
+pub fn list(app: &AppHandle) -> Result<Vec<SessionSummary>, CommandError> {
+    list_in(&sessions_dir(app)?)
+}
+
+fn list_in(dir: &Path) -> Result<Vec<SessionSummary>, CommandError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(CommandError::Io(e.to_string())),
+    };
+    let index = load_index(dir);
+
+    let mut sessions = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(|e| CommandError::Io(e.to_string()))?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("csv") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+            continue;
+        };
+        let trackpoints = match load_from(dir, &id) {
+            Ok(trackpoints) => trackpoints,
+            Err(e) => {
+                eprintln!("Skipping session {}: {:?}", id, e);
+                continue;
+            }
+        };
+        let workout = index.get(&id).cloned();
+        sessions.push(summarize(id, workout, &trackpoints));
+    }
+    sessions.sort_by_key(|session| std::cmp::Reverse(session.started));
+    Ok(sessions)
+}
+
+// Closes logs a crash left open, so every log on disk ends with an `end` marker.
+pub fn close_interrupted(app: &AppHandle) {
+    if let Ok(dir) = sessions_dir(app) {
+        close_interrupted_in(&dir);
+    }
+}
+
+fn close_interrupted_in(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("csv") {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let last_event = content.lines().last().and_then(|line| line.split(',').nth(1));
+        if last_event == Some("end") {
+            continue;
+        }
+
+        println!("Closing interrupted session log {:?}.", path);
+        let now = timestamp_millis();
+        // A crash mid-write can leave the last row without its newline
+        let separator = if content.ends_with('\n') { "" } else { "\n" };
+        let footer = format!("{}{},session-interrupted,,,,,\n{},end,,,,,\n", separator, now, now);
+        let result = fs::OpenOptions::new().append(true).open(&path).and_then(|mut file| file.write_all(footer.as_bytes()));
+        if let Err(e) = result {
+            eprintln!("Error closing interrupted session log {:?}: {:?}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("treadmill-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn reconnect_keeps_writing_to_the_same_log() {
+        let dir = test_dir("session-log-reconnect");
+        let mut log = SessionLog::start_in(&dir).unwrap();
+        let path = log.path.clone();
+        log.append(&TreadmillData { speed: 800, total_distance: Some(100), ..Default::default() });
+        log.marker("reconnect");
+        log.append(&TreadmillData { speed: 800, total_distance: Some(150), ..Default::default() });
+        assert_eq!(log.finish(&SessionStats::default()).unwrap(), path);
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().flatten().collect();
+        assert_eq!(files.len(), 1);
+        let content = fs::read_to_string(&path).unwrap();
+        let events: Vec<&str> = content.lines().skip(1).filter_map(|line| line.split(',').nth(1)).collect();
+        assert_eq!(events, ["data", "reconnect", "data", "summary", "end"]);
+        assert_eq!(content.lines().filter(|line| *line == HEADER).count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn write_session(dir: &Path, id: &str, rows: &[&str]) {
+        fs::create_dir_all(dir).unwrap();
+        let content: String = std::iter::once(HEADER).chain(rows.iter().copied()).map(|row| format!("{}\n", row)).collect();
+        fs::write(dir.join(format!("{}.csv", id)), content).unwrap();
+    }
+
+    #[test]
+    fn recorded_sessions_are_listed_newest_first() {
+        let dir = test_dir("session-log-list");
+        write_session(
+            &dir,
+            "1000",
+            &["1000,data,800,0,,,0", "31000,data,800,250,,,30", "32000,summary,800,250,,,30", "32000,end,,,,,"],
+        );
+        write_session(&dir, "90000", &["90000,data,1000,5,10,120,", "95000,data,1000,20,10,125,", "96000,end,,,,,"]);
+        record_workout_in(&dir, "1000", "Intervals").unwrap();
+
+        let sessions = list_in(&dir).unwrap();
+        let ids: Vec<&str> = sessions.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["90000", "1000"]);
+        // Without elapsed time the duration comes from the timestamps
+        assert_eq!((sessions[0].started, sessions[0].duration, sessions[0].distance), (90000, 6, Some(15)));
+        assert_eq!(sessions[0].workout, None);
+        assert_eq!((sessions[1].started, sessions[1].duration, sessions[1].distance), (1000, 30, Some(250)));
+        assert_eq!(sessions[1].workout.as_deref(), Some("Intervals"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loading_a_session_returns_its_rows() {
+        let dir = test_dir("session-log-load");
+        write_session(&dir, "1000", &["1000,data,800,0,15,130,0", "2000,reconnect,,,,,", "3000,data,8"]);
+
+        let trackpoints = load_from(&dir, "1000").unwrap();
+        assert_eq!(trackpoints.len(), 2);
+        assert_eq!(trackpoints[0].speed, Some(800));
+        assert_eq!(trackpoints[0].inclination, Some(15));
+        assert_eq!(trackpoints[0].heart_rate, Some(130));
+        assert_eq!(trackpoints[1].event, "reconnect");
+        assert_eq!(trackpoints[1].speed, None);
+        assert!(matches!(load_from(&dir, "2000"), Err(CommandError::SessionNotFound(_))));
+        assert!(matches!(load_from(&dir, "../1000"), Err(CommandError::SessionNotFound(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stopping_a_session_leaves_a_complete_log_with_a_summary() {
+        let dir = test_dir("session-log-finish");
+        let mut log = SessionLog::start_in(&dir).unwrap();
+        let id = log.id.clone();
+        let mut stats = SessionStats::default();
+        for data in [
+            TreadmillData { speed: 800, total_distance: Some(0), inclination: Some(10), elapsed_time: Some(0), ..Default::default() },
+            TreadmillData { speed: 1000, total_distance: Some(150), inclination: Some(20), elapsed_time: Some(60), ..Default::default() },
+            TreadmillData { speed: 0, total_distance: Some(160), inclination: Some(30), elapsed_time: Some(61), ..Default::default() },
+        ] {
+            stats.add(&data);
+            log.append(&data);
+        }
+        log.finish(&stats).unwrap();
+
+        let content = fs::read_to_string(dir.join(format!("{}.csv", id))).unwrap();
+        let trackpoints = load_from(&dir, &id).unwrap();
+        assert_eq!(trackpoints.len(), content.lines().count() - 1, "every row parses");
+        let events: Vec<&str> = trackpoints.iter().map(|t| t.event.as_str()).collect();
+        assert_eq!(events, ["data", "data", "data", "summary", "end"]);
+        let summary = &trackpoints[3];
+        assert_eq!(summary.speed, Some(600));
+        assert_eq!(summary.total_distance, Some(160));
+        assert_eq!(summary.inclination, Some(20));
+        assert_eq!(summary.heart_rate, None);
+        assert_eq!(summary.elapsed_time, Some(61));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn logs_left_open_are_closed_as_interrupted() {
+        let dir = test_dir("session-log-interrupted");
+        write_session(&dir, "1000", &["1000,data,800,0,,,0", "2000,end,,,,,"]);
+        fs::write(dir.join("2000.csv"), format!("{}\n2000,data,800,0,,,0\n3000,data,8", HEADER)).unwrap();
+
+        close_interrupted_in(&dir);
+        close_interrupted_in(&dir);
+
+        let events = |id| load_from(&dir, id).unwrap().into_iter().map(|t| t.event).collect::<Vec<_>>();
+        assert_eq!(events("1000"), ["data", "end"]);
+        // The row cut short stays unparseable, but doesn't swallow the marker after it
+        assert_eq!(events("2000"), ["data", "session-interrupted", "end"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
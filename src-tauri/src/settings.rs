@@ -0,0 +1,249 @@
+// User settings, persisted as JSON in the app config dir so they survive restarts.
+
+use crate::{control_point, device_profile, error::CommandError, heart_rate_limit::HeartRateLimit, session::SplitUnit, MachineType};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf, time::Duration};
+use tauri::AppHandle;
+
+const SETTINGS_FILE: &str = "settings.json";
+// Km/h at 0.01 precision, faster than any treadmill we know of
+const MAX_SPEED_CAP: u16 = 3000;
+const MAX_SPEED_DEBOUNCE_MS: u32 = 5000;
+const MAX_DATA_EMIT_INTERVAL_MS: u32 = 5000;
+const DEFAULT_STALL_TIMEOUT_SECS: u16 = 10;
+const DEFAULT_ARM_WINDOW_SECS: u16 = 10;
+const MAX_PROCEDURE_TIMEOUT_SECS: u16 = 60;
+const BODY_WEIGHT_RANGE_KG: std::ops::RangeInclusive<f32> = 20.0..=300.0;
+
+// A step run before or after every workout.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct ExtraStep {
+    // Seconds
+    pub duration: u16,
+    // Km/h at 0.01 precision
+    pub pace: u16,
+}
+
+// Missing fields fall back to their defaults so older settings files keep loading.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub heart_rate_limit: Option<HeartRateLimit>,
+    // Km/h at 0.01 precision, `None` sends target speed changes in one jump
+    pub max_speed_change_per_command: Option<u16>,
+    // Seconds, `None` never stops the belt on its own
+    pub max_session_duration: Option<u32>,
+    // Km/h at 0.01 precision, target speeds above this are refused
+    pub max_speed: Option<u16>,
+    // Milliseconds `set_target_speed` waits for further changes, `None` uses the default
+    pub speed_debounce_ms: Option<u32>,
+    // Milliseconds between `treadmill-data` events, 0 emits every frame and `None` uses the default
+    pub data_emit_interval_ms: Option<u32>,
+    // Km/h at 0.01 precision, frames reporting a faster speed are taken as corrupt and dropped.
+    // Off by default so real data is never hidden.
+    pub implausible_speed: Option<u16>,
+    pub warm_up: Option<ExtraStep>,
+    pub cool_down: Option<ExtraStep>,
+    // Device profile to use regardless of the treadmill's name
+    pub device_profile: Option<String>,
+    // Seconds `arm_treadmill` allows the belt to start for. On by default, `None` turns the
+    // interlock off.
+    pub arm_window_secs: Option<u16>,
+    // Pause the belt when the window loses focus or is minimized, off since some people run with
+    // the app in the background
+    pub pause_on_blur: bool,
+    // Resume a belt paused by `pause_on_blur` once the window has focus again
+    pub resume_on_focus: bool,
+    // Used to estimate calories, `None` leaves them out
+    pub body_weight_kg: Option<f32>,
+    // Lets `write_characteristic` send arbitrary bytes, for debugging only
+    pub allow_raw_writes: bool,
+    pub split_unit: SplitUnit,
+    // Carry on with a workout the connection dropped out of once the treadmill reconnects, without
+    // asking. The interlock still asks first when it's on.
+    pub auto_resume_workout: bool,
+    // Data to use from a machine that sends several kinds, `None` prefers treadmill data
+    pub machine_type: Option<MachineType>,
+    // Seconds without machine data before `data-stream-stalled`, `None` uses the default. Machines
+    // that go quiet while the belt is stopped trip it too.
+    pub stall_timeout_secs: Option<u16>,
+    // Look for the treadmill on every Bluetooth adapter instead of only the first
+    pub scan_all_adapters: bool,
+    // Seconds to wait for the machine to answer a control point request, `None` uses the spec's 30
+    pub procedure_timeout_secs: Option<u16>,
+    // Log Treadmill Data frames with bytes past the flagged fields, which usually means a machine
+    // misreports its flags or adds a vendor field
+    pub warn_trailing_bytes: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            heart_rate_limit: None,
+            max_speed_change_per_command: None,
+            max_session_duration: None,
+            max_speed: None,
+            speed_debounce_ms: None,
+            data_emit_interval_ms: None,
+            implausible_speed: None,
+            warm_up: None,
+            cool_down: None,
+            device_profile: None,
+            arm_window_secs: Some(DEFAULT_ARM_WINDOW_SECS),
+            pause_on_blur: false,
+            resume_on_focus: false,
+            body_weight_kg: None,
+            allow_raw_writes: false,
+            split_unit: SplitUnit::default(),
+            auto_resume_workout: false,
+            machine_type: None,
+            stall_timeout_secs: None,
+            scan_all_adapters: false,
+            procedure_timeout_secs: None,
+            warn_trailing_bytes: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), CommandError> {
+        if let Some(limit) = self.heart_rate_limit {
+            if limit.resume_below.is_some_and(|floor| floor >= limit.ceiling) {
+                return Err(CommandError::OutOfRange(format!(
+                    "heart rate resume point {:?} must be below the ceiling {}",
+                    limit.resume_below, limit.ceiling
+                )));
+            }
+        }
+        if self.max_speed_change_per_command == Some(0) {
+            return Err(CommandError::OutOfRange("max speed change per command of 0".to_string()));
+        }
+        if self.arm_window_secs == Some(0) {
+            return Err(CommandError::OutOfRange("arming window of 0".to_string()));
+        }
+        if self.max_session_duration == Some(0) {
+            return Err(CommandError::OutOfRange("max session duration of 0".to_string()));
+        }
+        if let Some(max_speed) = self.max_speed {
+            if max_speed == 0 || max_speed > MAX_SPEED_CAP {
+                return Err(CommandError::OutOfRange(format!(
+                    "max speed {} is outside 1..={}",
+                    max_speed, MAX_SPEED_CAP
+                )));
+            }
+        }
+        if self.speed_debounce_ms.is_some_and(|ms| ms > MAX_SPEED_DEBOUNCE_MS) {
+            return Err(CommandError::OutOfRange(format!(
+                "speed debounce of {:?}ms is above {}ms",
+                self.speed_debounce_ms, MAX_SPEED_DEBOUNCE_MS
+            )));
+        }
+        if self.stall_timeout_secs == Some(0) {
+            return Err(CommandError::OutOfRange("stall timeout of 0".to_string()));
+        }
+        if let Some(secs) = self.procedure_timeout_secs {
+            if secs == 0 || secs > MAX_PROCEDURE_TIMEOUT_SECS {
+                return Err(CommandError::OutOfRange(format!(
+                    "procedure timeout of {}s is outside 1..={}s",
+                    secs, MAX_PROCEDURE_TIMEOUT_SECS
+                )));
+            }
+        }
+        if self.implausible_speed == Some(0) {
+            return Err(CommandError::OutOfRange("implausible speed of 0".to_string()));
+        }
+        if self.data_emit_interval_ms.is_some_and(|ms| ms > MAX_DATA_EMIT_INTERVAL_MS) {
+            return Err(CommandError::OutOfRange(format!(
+                "data emit interval of {:?}ms is above {}ms",
+                self.data_emit_interval_ms, MAX_DATA_EMIT_INTERVAL_MS
+            )));
+        }
+        for (name, step) in [("warm up", self.warm_up), ("cool down", self.cool_down)] {
+            let Some(step) = step else {
+                continue;
+            };
+            if step.duration == 0 || step.pace > MAX_SPEED_CAP {
+                return Err(CommandError::OutOfRange(format!("{} of {:?}", name, step)));
+            }
+        }
+        if self.body_weight_kg.is_some_and(|weight| !BODY_WEIGHT_RANGE_KG.contains(&weight)) {
+            return Err(CommandError::OutOfRange(format!(
+                "body weight of {:?}kg is outside {:?}",
+                self.body_weight_kg, BODY_WEIGHT_RANGE_KG
+            )));
+        }
+        if let Some(name) = &self.device_profile {
+            if device_profile::find(name).is_none() {
+                return Err(CommandError::NotSupported(format!("device profile '{}'", name)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn arm_window(&self) -> Option<Duration> {
+        self.arm_window_secs.map(|secs| Duration::from_secs(secs as u64))
+    }
+
+    pub fn stall_timeout(&self) -> Duration {
+        Duration::from_secs(self.stall_timeout_secs.unwrap_or(DEFAULT_STALL_TIMEOUT_SECS) as u64)
+    }
+
+    pub fn procedure_timeout(&self) -> Duration {
+        self.procedure_timeout_secs.map_or(control_point::RESPONSE_TIMEOUT, |secs| Duration::from_secs(secs as u64))
+    }
+}
+
+fn settings_path(app: &AppHandle) -> Option<PathBuf> {
+    app.path_resolver().app_config_dir().map(|dir| dir.join(SETTINGS_FILE))
+}
+
+// Defaults when there's no settings file yet, or it can't be read.
+pub fn load(app: &AppHandle) -> Settings {
+    let Some(path) = settings_path(app) else {
+        return Settings::default();
+    };
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Settings::default(),
+        Err(e) => {
+            eprintln!("Error reading settings from {:?}: {:?}", path, e);
+            return Settings::default();
+        }
+    };
+
+    match serde_json::from_str::<Settings>(&content) {
+        Ok(settings) if settings.validate().is_ok() => settings,
+        Ok(settings) => {
+            eprintln!("Ignoring invalid settings {:?}.", settings);
+            Settings::default()
+        }
+        Err(e) => {
+            eprintln!("Error parsing settings: {:?}", e);
+            Settings::default()
+        }
+    }
+}
+
+pub fn save(app: &AppHandle, settings: &Settings) -> Result<(), CommandError> {
+    let path = settings_path(app).ok_or_else(|| CommandError::Io("No app config directory.".to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| CommandError::Io(e.to_string()))?;
+    }
+    let content = serde_json::to_string_pretty(settings).map_err(|e| CommandError::Io(e.to_string()))?;
+    fs::write(&path, content).map_err(|e| CommandError::Io(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_interlock_is_on_unless_turned_off() {
+        assert_eq!(Settings::default().arm_window(), Some(Duration::from_secs(10)));
+        let missing: Settings = serde_json::from_str("{}").unwrap();
+        assert_eq!(missing.arm_window_secs, Some(DEFAULT_ARM_WINDOW_SECS));
+        let off: Settings = serde_json::from_str(r#"{ "arm_window_secs": null }"#).unwrap();
+        assert_eq!(off.arm_window(), None);
+        assert!(off.validate().is_ok());
+    }
+}
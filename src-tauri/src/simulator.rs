@@ -0,0 +1,211 @@
+use crate::{
+    capabilities::{InclinationRange, MachineCapabilities, SpeedRange},
+    control_point::ControlPointResponse,
+    handle_notification, units, TREADMILL_CONTROL_CHARACTERISTIC_UUID, TREADMILL_DATA_CHARACTERISTIC_UUID,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tauri::{async_runtime::JoinHandle, AppHandle};
+use tokio::{sync::broadcast, time};
+
+const TICK: Duration = Duration::from_secs(1);
+// Km/h at 0.01 precision, how much the belt speeds up or slows down per tick
+const SPEED_RAMP_PER_TICK: u16 = 50;
+const RESTING_HEART_RATE: f32 = 70.0;
+
+#[derive(Debug, Default)]
+struct SimulatedTreadmill {
+    running: bool,
+    // Km/h at 0.01 precision
+    speed: u16,
+    target_speed: u16,
+    // Percent grade at 0.1 precision
+    inclination: i16,
+    // Meters, kept as a float so slow speeds still accumulate
+    distance: f64,
+    elapsed_time: u16,
+    heart_rate: f32,
+}
+
+impl SimulatedTreadmill {
+    fn tick(&mut self) {
+        if self.running {
+            self.speed = if self.speed < self.target_speed {
+                (self.speed + SPEED_RAMP_PER_TICK).min(self.target_speed)
+            } else {
+                self.speed.saturating_sub(SPEED_RAMP_PER_TICK).max(self.target_speed)
+            };
+            self.elapsed_time = self.elapsed_time.saturating_add(1);
+        } else {
+            self.speed = self.speed.saturating_sub(SPEED_RAMP_PER_TICK);
+        }
+
+        self.distance += units::meters_per_second(self.speed) * TICK.as_secs_f64();
+
+        // Drift heart rate towards a target that grows with effort
+        let effort_heart_rate = RESTING_HEART_RATE + self.speed as f32 / 10.0 + self.inclination.max(0) as f32 / 2.0;
+        self.heart_rate += (effort_heart_rate - self.heart_rate) * 0.1;
+    }
+
+    // Encodes the current state as a Treadmill Data notification with total distance,
+    // inclination, heart rate and elapsed time present.
+    fn treadmill_data(&self) -> Vec<u8> {
+        let mut data = vec![0b00001100, 0b00000101];
+        data.extend(self.speed.to_le_bytes());
+        data.extend(crate::write_u24_le(self.distance as u32));
+        data.extend(self.inclination.to_le_bytes());
+        // Ramp angle, not simulated
+        data.extend(0i16.to_le_bytes());
+        data.push(self.heart_rate.round() as u8);
+        data.extend(self.elapsed_time.to_le_bytes());
+        data
+    }
+
+    // Applies a control point write and returns the indication a real machine would send back.
+    fn handle_control_point(&mut self, message: &[u8]) -> Vec<u8> {
+        let opcode = message.first().copied().unwrap_or(0xFF);
+        let result = match (opcode, message.get(1..).unwrap_or_default()) {
+            (0x00, _) => 0x01,
+            (0x01, _) => {
+                *self = SimulatedTreadmill { heart_rate: self.heart_rate, ..Default::default() };
+                0x01
+            }
+            (0x02, [low, high, ..]) => {
+                self.target_speed = u16::from_le_bytes([*low, *high]);
+                0x01
+            }
+            (0x03, [low, high, ..]) => {
+                self.inclination = i16::from_le_bytes([*low, *high]);
+                0x01
+            }
+            (0x07, _) => {
+                self.running = true;
+                0x01
+            }
+            (0x08, _) => {
+                self.running = false;
+                0x01
+            }
+            (0x02 | 0x03, _) => 0x03,
+            _ => 0x02,
+        };
+        vec![0x80, opcode, result]
+    }
+}
+
+// A fake treadmill for frontend development without hardware. It feeds synthetic frames through
+// the same notification handling as a real device, so events look identical to the UI.
+#[derive(Clone)]
+pub struct Simulator {
+    treadmill: Arc<Mutex<SimulatedTreadmill>>,
+    task: Arc<JoinHandle<()>>,
+    app: AppHandle,
+    responses: broadcast::Sender<ControlPointResponse>,
+}
+
+impl Simulator {
+    pub fn start(app: AppHandle, responses: broadcast::Sender<ControlPointResponse>) -> Simulator {
+        let treadmill = Arc::new(Mutex::new(SimulatedTreadmill {
+            heart_rate: RESTING_HEART_RATE,
+            ..Default::default()
+        }));
+
+        let task = {
+            let treadmill = treadmill.clone();
+            let app = app.clone();
+            let responses = responses.clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    time::sleep(TICK).await;
+                    let data = {
+                        let mut treadmill = treadmill.lock().unwrap();
+                        treadmill.tick();
+                        treadmill.treadmill_data()
+                    };
+                    handle_notification(&app, &responses, TREADMILL_DATA_CHARACTERISTIC_UUID, &data);
+                }
+            })
+        };
+
+        Simulator { treadmill, task: Arc::new(task), app, responses }
+    }
+
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+
+    pub fn write(&self, message: &[u8]) {
+        let response = self.treadmill.lock().unwrap().handle_control_point(message);
+        handle_notification(&self.app, &self.responses, TREADMILL_CONTROL_CHARACTERISTIC_UUID, &response);
+    }
+
+    pub fn capabilities(&self) -> MachineCapabilities {
+        MachineCapabilities {
+            features: None,
+            target_settings: None,
+            speed_range: Some(SpeedRange { minimum: 0, maximum: 2000, minimum_increment: 10 }),
+            inclination_range: Some(InclinationRange { minimum: 0, maximum: 150, minimum_increment: 5 }),
+            power_range: None,
+            control_opcodes: vec![0x00, 0x01, 0x02, 0x03, 0x07, 0x08],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        control_point::{decode_control_point_response, ResultCode},
+        decode_treadmill_data,
+    };
+
+    fn treadmill() -> SimulatedTreadmill {
+        SimulatedTreadmill { heart_rate: RESTING_HEART_RATE, ..Default::default() }
+    }
+
+    #[test]
+    fn frames_decode_like_a_real_treadmill() {
+        let mut treadmill = treadmill();
+        treadmill.handle_control_point(&[0x02, 0xE8, 0x03]);
+        treadmill.handle_control_point(&[0x07]);
+        for _ in 0..3 {
+            treadmill.tick();
+        }
+
+        let data = decode_treadmill_data(&treadmill.treadmill_data()).unwrap();
+        assert_eq!(data.speed, 3 * SPEED_RAMP_PER_TICK);
+        assert_eq!(data.elapsed_time, Some(3));
+        assert_eq!(data.inclination, Some(0));
+        assert!(data.total_distance.is_some());
+        assert!(data.heart_rate.is_some_and(|heart_rate| heart_rate as f32 > RESTING_HEART_RATE));
+    }
+
+    #[test]
+    fn set_target_speed_moves_the_belt_towards_it() {
+        let mut treadmill = treadmill();
+        let response = decode_control_point_response(&treadmill.handle_control_point(&[0x02, 0x64, 0x00])).unwrap();
+        assert_eq!(response.request_opcode, 0x02);
+        assert_eq!(response.result, ResultCode::Success);
+
+        treadmill.handle_control_point(&[0x07]);
+        for _ in 0..5 {
+            treadmill.tick();
+        }
+        assert_eq!(treadmill.speed, 100);
+
+        treadmill.handle_control_point(&[0x08]);
+        treadmill.tick();
+        assert_eq!(treadmill.speed, 100 - SPEED_RAMP_PER_TICK);
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes_and_short_parameters() {
+        let mut treadmill = treadmill();
+        let unknown = decode_control_point_response(&treadmill.handle_control_point(&[0x20])).unwrap();
+        assert_eq!(unknown.result, ResultCode::OpCodeNotSupported);
+        let short = decode_control_point_response(&treadmill.handle_control_point(&[0x02, 0x64])).unwrap();
+        assert_eq!(short.result, ResultCode::InvalidParameter);
+    }
+}
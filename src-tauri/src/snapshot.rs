@@ -0,0 +1,121 @@
+// Last known targets and workout position, saved to the app data dir as they change so the app can
+// offer to pick up where it left off after a crash. A clean stop leaves a snapshot with nothing
+// running, which isn't worth offering.
+
+use crate::{error::CommandError, timestamp_millis};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tauri::AppHandle;
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Snapshot {
+    // Km/h at 0.01 precision
+    pub speed: Option<u16>,
+    // Percent grade at 0.1 precision
+    pub incline: Option<i16>,
+    // Whether the belt was moving
+    pub running: bool,
+    pub workout: Option<String>,
+    pub step_index: Option<usize>,
+    // Milliseconds since the unix epoch
+    pub saved_at: u64,
+}
+
+impl Snapshot {
+    pub fn worth_restoring(&self) -> bool {
+        self.running || self.workout.is_some()
+    }
+}
+
+fn snapshot_path(app: &AppHandle) -> Option<PathBuf> {
+    app.path_resolver().app_data_dir().map(|dir| dir.join(SNAPSHOT_FILE))
+}
+
+// `None` when there's no snapshot or it can't be read.
+pub fn load(app: &AppHandle) -> Option<Snapshot> {
+    load_from(&snapshot_path(app)?)
+}
+
+fn load_from(path: &Path) -> Option<Snapshot> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            eprintln!("Error reading snapshot from {:?}: {:?}", path, e);
+            return None;
+        }
+    };
+
+    match serde_json::from_str(&content) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => {
+            eprintln!("Error parsing snapshot: {:?}", e);
+            None
+        }
+    }
+}
+
+pub fn save(app: &AppHandle, snapshot: &mut Snapshot) -> Result<(), CommandError> {
+    let path = snapshot_path(app).ok_or_else(|| CommandError::Io("No app data directory.".to_string()))?;
+    save_to(&path, snapshot)
+}
+
+fn save_to(path: &Path, snapshot: &mut Snapshot) -> Result<(), CommandError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| CommandError::Io(e.to_string()))?;
+    }
+    snapshot.saved_at = timestamp_millis();
+    let content = serde_json::to_string_pretty(snapshot).map_err(|e| CommandError::Io(e.to_string()))?;
+    fs::write(path, content).map_err(|e| CommandError::Io(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("treadmill-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(SNAPSHOT_FILE)
+    }
+
+    #[test]
+    fn snapshot_survives_a_restart() {
+        let path = test_path("snapshot-restart");
+        let mut snapshot = Snapshot {
+            speed: Some(950),
+            incline: Some(20),
+            running: true,
+            workout: Some("Intervals".to_string()),
+            step_index: Some(3),
+            saved_at: 0,
+        };
+        save_to(&path, &mut snapshot).unwrap();
+        assert!(snapshot.saved_at > 0);
+
+        // Nothing carries over in memory, the next run only has the file
+        let restored = load_from(&path).unwrap();
+        assert_eq!(restored, snapshot);
+        assert!(restored.worth_restoring());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn clean_stops_and_unreadable_snapshots_are_not_offered() {
+        let path = test_path("snapshot-clean");
+        assert_eq!(load_from(&path), None);
+
+        save_to(&path, &mut Snapshot { speed: Some(950), ..Default::default() }).unwrap();
+        assert!(!load_from(&path).unwrap().worth_restoring());
+
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(load_from(&path), None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,22 @@
+// Breaks large target speed changes into smaller ones so the belt doesn't lurch between intervals.
+
+use std::time::Duration;
+
+// How long to wait between intermediate targets
+pub const STEP_DELAY: Duration = Duration::from_millis(500);
+
+// Targets to send before `to`, each at most `max_change` (km/h at 0.01 precision) from the last.
+// `to` itself isn't included so callers can send it alongside other targets.
+pub fn intermediate_speeds(from: u16, to: u16, max_change: u16) -> Vec<u16> {
+    if max_change == 0 {
+        return Vec::new();
+    }
+
+    let mut speeds = Vec::new();
+    let mut current = from;
+    while current.abs_diff(to) > max_change {
+        current = if current < to { current + max_change } else { current - max_change };
+        speeds.push(current);
+    }
+    speeds
+}
@@ -0,0 +1,84 @@
+// Conversions from the fixed point units FTMS fields come in, for display and calculations. Raw
+// values stay on the data as decoded, these only read them.
+
+pub const KM_PER_MILE: f64 = 1.60934;
+// Shown instead of a pace while the belt is stopped
+const NO_PACE: &str = "--";
+
+pub fn kmh(speed: u16) -> f64 {
+    speed as f64 / 100.0
+}
+
+pub fn mph(speed: u16) -> f64 {
+    kmh(speed) / KM_PER_MILE
+}
+
+pub fn meters_per_second(speed: u16) -> f64 {
+    kmh(speed) / 3.6
+}
+
+// Percent grade from 0.1% precision
+pub fn percent(inclination: i16) -> f64 {
+    inclination as f64 / 10.0
+}
+
+// Degrees from 0.1 degree precision
+pub fn degrees(angle: i16) -> f64 {
+    angle as f64 / 10.0
+}
+
+// METs from 0.1 MET precision
+pub fn mets(metabolic_equivalent: u8) -> f64 {
+    metabolic_equivalent as f64 / 10.0
+}
+
+// Time per `km_per_unit` kilometers as "m:ss", e.g. 1.0 for min/km or `KM_PER_MILE` for min/mi.
+pub fn pace(speed: u16, km_per_unit: f64) -> String {
+    if speed == 0 {
+        return NO_PACE.to_string();
+    }
+    let seconds = (km_per_unit / kmh(speed) * 3600.0).round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pace_per_km_and_mile() {
+        assert_eq!(pace(1000, 1.0), "6:00");
+        assert_eq!(pace(1200, 1.0), "5:00");
+        assert_eq!(pace(850, 1.0), "7:04");
+        assert_eq!(pace(1000, KM_PER_MILE), "9:39");
+    }
+
+    #[test]
+    fn stopped_belt_has_no_pace() {
+        assert_eq!(pace(0, 1.0), NO_PACE);
+        assert_eq!(pace(0, KM_PER_MILE), NO_PACE);
+    }
+
+    fn near(actual: f64, expected: f64) -> bool {
+        (actual - expected).abs() < 1e-9
+    }
+
+    #[test]
+    fn speeds_from_hundredths_of_a_kmh() {
+        assert!(near(kmh(1250), 12.5));
+        assert!(near(mph(1609), 1609.0 / 100.0 / KM_PER_MILE));
+        assert!(near(mph(0), 0.0));
+        assert!(near(meters_per_second(360), 1.0));
+        assert!(near(meters_per_second(1800), 5.0));
+    }
+
+    #[test]
+    fn tenths_to_percent_degrees_and_mets() {
+        assert!(near(percent(25), 2.5));
+        assert!(near(percent(-30), -3.0));
+        assert!(near(degrees(14), 1.4));
+        assert!(near(degrees(-14), -1.4));
+        assert!(near(mets(45), 4.5));
+        assert!(near(mets(u8::MAX), 25.5));
+    }
+}
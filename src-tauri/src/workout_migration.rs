@@ -0,0 +1,57 @@
+// Brings workout files written for older versions of the format up to date, working on the raw
+// JSON so files that no longer deserialize into `WorkoutRaw` can still be fixed. Anything this
+// doesn't recognise is left for the user to fix by hand.
+
+use serde_json::{Map, Value};
+
+// Applies every migration that's needed and describes each change made, empty when the file
+// needed none.
+pub fn migrate(workout: &mut Value, file_stem: &str) -> Vec<String> {
+    let mut changes = Vec::new();
+    let Some(workout) = workout.as_object_mut() else {
+        return changes;
+    };
+
+    // Names used to come from the file name
+    if !workout.contains_key("name") {
+        workout.insert("name".to_string(), Value::String(file_stem.to_string()));
+        changes.push(format!("added name \"{}\"", file_stem));
+    }
+    if !workout.contains_key("description") {
+        workout.insert("description".to_string(), Value::String(String::new()));
+        changes.push("added an empty description".to_string());
+    }
+    if let Some(Value::Array(steps)) = workout.get_mut("steps") {
+        migrate_steps(steps, &mut changes);
+    }
+    changes
+}
+
+fn migrate_steps(steps: &mut [Value], changes: &mut Vec<String>) {
+    for step in steps.iter_mut().filter_map(Value::as_object_mut) {
+        match step.get("type").and_then(Value::as_str) {
+            Some("repeat") => {
+                if let Some(Value::Array(steps)) = step.get_mut("steps") {
+                    migrate_steps(steps, changes);
+                }
+            }
+            Some("run") => migrate_run(step, changes),
+            _ => {}
+        }
+    }
+}
+
+fn migrate_run(step: &mut Map<String, Value>, changes: &mut Vec<String>) {
+    let name = step.get("name").and_then(Value::as_str).unwrap_or("unnamed").to_string();
+    // Steps were flat before inclines could be set
+    if !step.contains_key("angle") {
+        step.insert("angle".to_string(), Value::from(0));
+        changes.push(format!("added a flat angle to step \"{}\"", name));
+    }
+    // Durations were plain seconds before they were "m:ss"
+    if let Some(seconds) = step.get("duration").and_then(Value::as_u64) {
+        let duration = format!("{}:{:02}", seconds / 60, seconds % 60);
+        changes.push(format!("changed the duration of step \"{}\" from {} to \"{}\"", name, seconds, duration));
+        step.insert("duration".to_string(), Value::String(duration));
+    }
+}
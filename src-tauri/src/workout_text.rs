@@ -0,0 +1,266 @@
+// Parses a compact interval notation into a `WorkoutRaw`, e.g.
+//
+//     10:00 @ 6:00/km, 3x(400m @ 5:00/km ^2, 200m @ 7:00/km), 1mi @ 9:00/mi
+//
+// Steps are separated by commas. A step is an amount, `@` and a pace, optionally followed by
+// `^angle`. Amounts are either a duration (`5:00`, `90s`, `10min`) or a distance (`400m`, `1.5km`,
+// `1mi`); distances are turned into durations using the step's pace. Paces are `m:ss/km`,
+// `m:ss/mi`, `<n>kph` or `<n>mph`. `Nx(...)` repeats a group of steps and groups can be nested.
+
+use crate::{PaceRaw, WorkoutRaw, WorkoutStepRaw};
+
+const METERS_PER_MILE: f64 = 1609.344;
+
+enum Amount {
+    Seconds(u32),
+    Meters(f64),
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    fn consume(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.pos += expected.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.consume(expected) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}' at position {}", expected, self.pos))
+        }
+    }
+
+    fn read_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let end = rest.find(|c| !predicate(c)).unwrap_or(rest.len());
+        self.pos += end;
+        &rest[..end]
+    }
+
+    fn parse_sequence(&mut self) -> Result<Vec<WorkoutStepRaw>, String> {
+        let mut steps = vec![self.parse_item()?];
+        while self.consume(',') {
+            steps.push(self.parse_item()?);
+        }
+        Ok(steps)
+    }
+
+    fn parse_item(&mut self) -> Result<WorkoutStepRaw, String> {
+        let start = self.pos;
+        let times = self.read_while(|c| c.is_ascii_digit());
+        if !times.is_empty() && (self.consume('x') || self.consume('X')) {
+            let times = times.parse::<u8>().map_err(|_| format!("Invalid repeat count '{}'", times))?;
+            let steps = if self.consume('(') {
+                let steps = self.parse_sequence()?;
+                self.expect(')')?;
+                steps
+            } else {
+                vec![self.parse_run()?]
+            };
+            return Ok(WorkoutStepRaw::Repeat { times, steps });
+        }
+
+        self.pos = start;
+        self.parse_run()
+    }
+
+    fn parse_run(&mut self) -> Result<WorkoutStepRaw, String> {
+        let amount_text = self.read_while(|c| !c.is_whitespace() && c != '@');
+        if amount_text.is_empty() {
+            return Err(format!("Expected a duration or distance at position {}", self.pos));
+        }
+        let amount = parse_amount(amount_text)?;
+
+        self.expect('@')?;
+        let pace_text = self.read_while(|c| !c.is_whitespace() && !matches!(c, ',' | ')' | '^'));
+        let pace = parse_pace_text(pace_text)?;
+
+        let mut angle = 0;
+        if self.consume('^') {
+            let angle_text = self.read_while(|c| c.is_ascii_digit() || c == '-');
+            angle = angle_text.parse::<i16>().map_err(|_| format!("Invalid angle '{}'", angle_text))?;
+        }
+
+        let seconds = match amount {
+            Amount::Seconds(seconds) => seconds,
+            Amount::Meters(meters) => {
+                let speed = meters_per_second(&pace)?;
+                (meters / speed).round() as u32
+            }
+        };
+
+        Ok(WorkoutStepRaw::Run {
+            name: amount_text.to_string(),
+            duration: format!("{}:{:02}", seconds / 60, seconds % 60),
+            pace,
+            angle,
+        })
+    }
+}
+
+fn parse_number(text: &str) -> Result<f64, String> {
+    text.parse::<f64>().map_err(|_| format!("Invalid number '{}'", text))
+}
+
+// "m:ss" to seconds
+fn parse_clock(text: &str) -> Result<u32, String> {
+    let (minutes, seconds) = text.split_once(':').ok_or_else(|| format!("Invalid time '{}'", text))?;
+    let minutes = minutes.parse::<u32>().map_err(|_| format!("Invalid time '{}'", text))?;
+    let seconds = seconds.parse::<u32>().map_err(|_| format!("Invalid time '{}'", text))?;
+    Ok(minutes * 60 + seconds)
+}
+
+fn parse_amount(text: &str) -> Result<Amount, String> {
+    if text.contains(':') {
+        return Ok(Amount::Seconds(parse_clock(text)?));
+    }
+    if let Some(minutes) = text.strip_suffix("min") {
+        return Ok(Amount::Seconds((parse_number(minutes)? * 60.0).round() as u32));
+    }
+    if let Some(km) = text.strip_suffix("km") {
+        return Ok(Amount::Meters(parse_number(km)? * 1000.0));
+    }
+    if let Some(miles) = text.strip_suffix("mi") {
+        return Ok(Amount::Meters(parse_number(miles)? * METERS_PER_MILE));
+    }
+    if let Some(meters) = text.strip_suffix('m') {
+        return Ok(Amount::Meters(parse_number(meters)?));
+    }
+    if let Some(seconds) = text.strip_suffix('s') {
+        return Ok(Amount::Seconds(parse_number(seconds)?.round() as u32));
+    }
+    Err(format!("Unknown duration or distance '{}'", text))
+}
+
+fn parse_pace_text(text: &str) -> Result<PaceRaw, String> {
+    if let Some(pace) = text.strip_suffix("/km") {
+        parse_clock(pace)?;
+        return Ok(PaceRaw::MinPerKm(pace.to_string()));
+    }
+    if let Some(pace) = text.strip_suffix("/mi") {
+        parse_clock(pace)?;
+        return Ok(PaceRaw::MinPerMi(pace.to_string()));
+    }
+    if let Some(speed) = text.strip_suffix("kph") {
+        parse_number(speed)?;
+        return Ok(PaceRaw::Kph(speed.to_string()));
+    }
+    if let Some(speed) = text.strip_suffix("mph") {
+        parse_number(speed)?;
+        return Ok(PaceRaw::Mph(speed.to_string()));
+    }
+    Err(format!("Unknown pace '{}'", text))
+}
+
+fn meters_per_second(pace: &PaceRaw) -> Result<f64, String> {
+    let speed = match pace {
+        PaceRaw::MinPerKm(pace) => 1000.0 / parse_clock(pace)? as f64,
+        PaceRaw::MinPerMi(pace) => METERS_PER_MILE / parse_clock(pace)? as f64,
+        PaceRaw::Kph(speed) => parse_number(speed)? / 3.6,
+        PaceRaw::Mph(speed) => parse_number(speed)? * METERS_PER_MILE / 3600.0,
+    };
+    if !speed.is_finite() || speed <= 0.0 {
+        return Err("A distance step needs a non-zero pace".to_string());
+    }
+    Ok(speed)
+}
+
+pub fn parse_workout_text(text: &str) -> Result<WorkoutRaw, String> {
+    let mut parser = Parser { input: text, pos: 0 };
+    let steps = parser.parse_sequence()?;
+    parser.skip_whitespace();
+    if parser.pos != text.len() {
+        return Err(format!("Unexpected '{}' at position {}", parser.rest(), parser.pos));
+    }
+
+    Ok(WorkoutRaw {
+        name: text.trim().to_string(),
+        description: String::new(),
+        steps,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (name, duration, angle) of a run step
+    fn run(step: &WorkoutStepRaw) -> (&str, &str, i16) {
+        match step {
+            WorkoutStepRaw::Run { name, duration, angle, .. } => (name, duration, *angle),
+            WorkoutStepRaw::Repeat { .. } => panic!("expected a run step, got {:?}", step),
+        }
+    }
+
+    fn repeat(step: &WorkoutStepRaw) -> (u8, &[WorkoutStepRaw]) {
+        match step {
+            WorkoutStepRaw::Repeat { times, steps } => (*times, steps),
+            WorkoutStepRaw::Run { .. } => panic!("expected a repeat step, got {:?}", step),
+        }
+    }
+
+    #[test]
+    fn parses_repeats_with_mixed_units() {
+        let workout = parse_workout_text("10:00 @ 6:00/km, 3x(400m @ 5:00/km ^2, 200m @ 12kph), 1mi @ 8:00/mi").unwrap();
+        assert_eq!(workout.steps.len(), 3);
+        assert_eq!(run(&workout.steps[0]), ("10:00", "10:00", 0));
+
+        let (times, steps) = repeat(&workout.steps[1]);
+        assert_eq!(times, 3);
+        assert_eq!(run(&steps[0]), ("400m", "2:00", 2));
+        assert!(matches!(&steps[0], WorkoutStepRaw::Run { pace: PaceRaw::MinPerKm(pace), .. } if pace == "5:00"));
+        assert_eq!(run(&steps[1]), ("200m", "1:00", 0));
+        assert!(matches!(&steps[1], WorkoutStepRaw::Run { pace: PaceRaw::Kph(speed), .. } if speed == "12"));
+
+        assert_eq!(run(&workout.steps[2]), ("1mi", "8:00", 0));
+    }
+
+    #[test]
+    fn parses_nested_repeats_and_single_step_repeats() {
+        let workout = parse_workout_text("2x(90s @ 10kph, 2x(1km @ 4:30/km)), 4x 1min @ 6mph").unwrap();
+        let (times, steps) = repeat(&workout.steps[0]);
+        assert_eq!(times, 2);
+        assert_eq!(run(&steps[0]), ("90s", "1:30", 0));
+        let (inner_times, inner) = repeat(&steps[1]);
+        assert_eq!(inner_times, 2);
+        assert_eq!(run(&inner[0]), ("1km", "4:30", 0));
+
+        let (times, steps) = repeat(&workout.steps[1]);
+        assert_eq!(times, 4);
+        assert_eq!(run(&steps[0]), ("1min", "1:00", 0));
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        assert!(parse_workout_text("").is_err());
+        assert!(parse_workout_text("400m 5:00/km").is_err());
+        assert!(parse_workout_text("400m @ fast").is_err());
+        assert!(parse_workout_text("3x(400m @ 5:00/km").is_err());
+        assert!(parse_workout_text("400m @ 0kph").is_err());
+        assert!(parse_workout_text("400m @ 5:00/km extra").is_err());
+    }
+}
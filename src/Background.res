@@ -1,10 +1,19 @@
 // All code running at the Rust level, is called the Background.
 // Ideally, all it's state is managed in the Foreground and communicated here with messages
 
+// Commands reject with a `CommandError` from src-tauri/src/error.rs. Branch on `kind`, e.g.
+// "NotConnected" or "WorkoutParse", and show `message` to the user.
+type commandError = {
+  kind: string,
+  message: string,
+}
+
+external toCommandError: Js.Exn.t => commandError = "%identity"
+
 let toResult = async (f, x) =>
   switch await f(x) {
   | value => Ok(value)
-  | exception Js.Exn.Error(e) => Error(e)
+  | exception Js.Exn.Error(e) => Error(toCommandError(e))
   }
 
 %%raw(`
@@ -19,7 +28,35 @@ function connectToTreadmill() {
 }
 `)
 
-let _readWorkouts: unit => promise<array<string>> = %raw(`
+// Mirrors `WorkoutsRead` in src-tauri/src/main.rs. Workouts are keyed by file name, `id`, since
+// names can be shared.
+type workoutFile = {
+  id: string,
+  name: string,
+  description: string,
+  // Seconds
+  duration: int,
+  // Meters
+  distance: int,
+}
+
+type workoutFileError = {
+  filename: string,
+  error: string,
+}
+
+type duplicateWorkoutName = {
+  name: string,
+  ids: array<string>,
+}
+
+type workoutsRead = {
+  workouts: array<workoutFile>,
+  errors: array<workoutFileError>,
+  @as("duplicate_names") duplicateNames: array<duplicateWorkoutName>,
+}
+
+let _readWorkouts: unit => promise<workoutsRead> = %raw(`
   function readWorkouts() {
     return invoke('read_workouts')
   }
@@ -25,9 +25,19 @@ module App = {
       <button
         onClick={_ =>
           Background.readWorkouts()
-          ->Promise.then(x => {
-            Js.log(x)
-            Promise.resolve(x)
+          ->Promise.then(result => {
+            switch result {
+            | Ok(read: Background.workoutsRead) =>
+              Js.log(read.workouts)
+              read.errors->Array.forEach((e: Background.workoutFileError) =>
+                Js.Console.warn2(e.filename, e.error)
+              )
+              read.duplicateNames->Array.forEach((duplicate: Background.duplicateWorkoutName) =>
+                Js.Console.warn3("Workout name used by several files:", duplicate.name, duplicate.ids)
+              )
+            | Error({kind, message}) => Js.Console.error2(kind, message)
+            }
+            Promise.resolve()
           })
           ->ignore}>
         {"Read Workouts"->React.string}